@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use lib_sr::event;
+use lib_sr::event::{DuplicateOfData, Event, Typed, TypedEvent};
+use lib_sr::in_process::StepContext;
+use lib_sr::Config;
+
+use crate::embedded;
+use crate::embedded::generator;
+use crate::embedded::MapContext;
+
+const DOI_PREFIXES: [&str; 5] = [
+    "https://doi.org/",
+    "http://doi.org/",
+    "https://dx.doi.org/",
+    "http://dx.doi.org/",
+    "doi:",
+];
+
+const PUBMED_PREFIXES: [&str; 2] = [
+    "https://pubmed.ncbi.nlm.nih.gov/",
+    "http://pubmed.ncbi.nlm.nih.gov/",
+];
+
+/// JSON pointers into document data where each identity field may be
+/// found. The first pointer with a value is used.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct Fields {
+    doi: Vec<String>,
+    pmid: Vec<String>,
+    title: Vec<String>,
+    year: Vec<String>,
+}
+
+impl Default for Fields {
+    fn default() -> Fields {
+        Fields {
+            doi: vec![String::from("/doi")],
+            pmid: vec![String::from("/pmid")],
+            title: vec![String::from("/title")],
+            year: vec![String::from("/year")],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum KeyKind {
+    #[serde(rename = "doi")]
+    Doi,
+    #[serde(rename = "pmid")]
+    Pmid,
+    #[serde(alias = "title_year", rename = "title-year")]
+    TitleYear,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    // Leave duplicates out of the output
+    Drop,
+    // Emit a duplicate-of event before each duplicate
+    #[default]
+    Link,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct Options {
+    fields: Fields,
+    // The minimum similarity of two titles from the same year for the
+    // documents to be duplicates. None turns off fuzzy matching.
+    #[serde(alias = "fuzzy_title", rename = "fuzzy-title")]
+    fuzzy_title: Option<f64>,
+    keys: Vec<KeyKind>,
+    mode: Mode,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            fields: Fields::default(),
+            fuzzy_title: None,
+            keys: vec![KeyKind::Doi, KeyKind::Pmid, KeyKind::TitleYear],
+            mode: Mode::default(),
+        }
+    }
+}
+
+impl Options {
+    fn from_config(config: &Config) -> Result<Options> {
+        let extra = match &config.current_step {
+            Some(step) => step.extra.to_owned(),
+            None => BTreeMap::new(),
+        };
+        let options: Options = serde_json::from_value(Value::Object(extra.into_iter().collect()))
+            .with_context(|| "Invalid options for dedupe step")?;
+        if let Some(threshold) = options.fuzzy_title {
+            if !(0.0..=1.0).contains(&threshold) {
+                return Err(Error::msg(format!(
+                    "fuzzy-title must be between 0 and 1. Found: {}",
+                    threshold
+                )));
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn find_text(data: &Value, pointers: &[String]) -> Option<String> {
+    pointers
+        .iter()
+        .find_map(|pointer| match data.pointer(pointer) {
+            Some(Value::String(s)) => Some(s.to_owned()),
+            Some(Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        })
+}
+
+fn normalize_doi(s: &str) -> Option<String> {
+    let s = s.trim().to_lowercase();
+    let doi = DOI_PREFIXES
+        .iter()
+        .find_map(|prefix| s.strip_prefix(prefix))
+        .unwrap_or(&s)
+        .trim();
+    if doi.starts_with("10.") {
+        Some(doi.to_owned())
+    } else {
+        None
+    }
+}
+
+fn normalize_pmid(s: &str) -> Option<String> {
+    let s = s.trim().trim_end_matches('/');
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+        Some(s.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Lowercase a title and reduce punctuation and runs of whitespace to
+/// single spaces
+fn normalize_title(s: &str) -> String {
+    s.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The first four-digit number in s, such as the year of a date
+fn find_year(s: &str) -> Option<String> {
+    s.split(|c: char| !c.is_ascii_digit())
+        .find(|part| part.len() == 4)
+        .map(String::from)
+}
+
+/// The identity keys of a document, and its normalized title and
+/// year if it has both
+fn identity_keys(options: &Options, event: &Event) -> (Vec<String>, Option<(String, String)>) {
+    let null = Value::Null;
+    let data = event.data.as_ref().unwrap_or(&null);
+    let uri = event.uri.as_deref().unwrap_or_default();
+    let mut keys = Vec::new();
+    let mut title_year = None;
+    for kind in &options.keys {
+        match kind {
+            KeyKind::Doi => {
+                let doi = find_text(data, &options.fields.doi)
+                    .and_then(|s| normalize_doi(&s))
+                    .or_else(|| normalize_doi(uri));
+                if let Some(doi) = doi {
+                    keys.push(format!("doi:{}", doi));
+                }
+            }
+            KeyKind::Pmid => {
+                let pmid = find_text(data, &options.fields.pmid)
+                    .and_then(|s| normalize_pmid(&s))
+                    .or_else(|| {
+                        PUBMED_PREFIXES
+                            .iter()
+                            .find_map(|prefix| uri.strip_prefix(prefix))
+                            .and_then(normalize_pmid)
+                    });
+                if let Some(pmid) = pmid {
+                    keys.push(format!("pmid:{}", pmid));
+                }
+            }
+            KeyKind::TitleYear => {
+                let title = find_text(data, &options.fields.title).map(|s| normalize_title(&s));
+                let year = find_text(data, &options.fields.year).and_then(|s| find_year(&s));
+                if let (Some(title), Some(year)) = (title, year) {
+                    if !title.is_empty() {
+                        keys.push(format!("title-year:{}|{}", title, year));
+                        title_year = Some((title, year));
+                    }
+                }
+            }
+        }
+    }
+    (keys, title_year)
+}
+
+/// Finds documents that have the same identity as a document seen
+/// earlier. The first document with an identity is kept.
+struct Deduper {
+    // The hashes of kept documents by identity key
+    index: HashMap<String, String>,
+    options: Options,
+    // The result of checking each document, so that a document seen
+    // again gets the same result
+    results: HashMap<String, Option<(String, Vec<String>)>>,
+    // The normalized titles and hashes of kept documents by year
+    titles: HashMap<String, Vec<(String, String)>>,
+}
+
+impl Deduper {
+    fn new(options: Options) -> Deduper {
+        Deduper {
+            index: HashMap::new(),
+            options,
+            results: HashMap::new(),
+            titles: HashMap::new(),
+        }
+    }
+
+    /// Check a document against the documents seen so far. Returns the
+    /// hash of the kept document and the keys that matched if the
+    /// document is a duplicate.
+    fn check(&mut self, event: &Event) -> Option<(String, Vec<String>)> {
+        let hash = event.hash.as_deref().expect("hash");
+        if let Some(result) = self.results.get(hash) {
+            return result.to_owned();
+        }
+        let (keys, title_year) = identity_keys(&self.options, event);
+        let kept = keys
+            .iter()
+            .find_map(|key| self.index.get(key))
+            .filter(|kept| kept.as_str() != hash)
+            .cloned();
+        let duplicate = match kept {
+            Some(kept) => {
+                let matched = keys
+                    .iter()
+                    .filter(|key| self.index.get(*key) == Some(&kept))
+                    .cloned()
+                    .collect();
+                Some((kept, matched))
+            }
+            None => self.fuzzy_match(hash, &title_year),
+        };
+        match &duplicate {
+            Some((kept, _)) => {
+                // Later documents that match the duplicate's other
+                // keys are duplicates of the kept document too
+                for key in keys {
+                    self.index.entry(key).or_insert_with(|| kept.to_owned());
+                }
+            }
+            None => {
+                for key in keys {
+                    self.index.entry(key).or_insert_with(|| hash.to_owned());
+                }
+                if let Some((title, year)) = title_year {
+                    self.titles
+                        .entry(year)
+                        .or_default()
+                        .push((title, hash.to_owned()));
+                }
+            }
+        }
+        self.results.insert(hash.to_owned(), duplicate.clone());
+        duplicate
+    }
+
+    /// Find the kept document from the same year with the most similar
+    /// title, if it meets the fuzzy-title threshold
+    fn fuzzy_match(
+        &self,
+        hash: &str,
+        title_year: &Option<(String, String)>,
+    ) -> Option<(String, Vec<String>)> {
+        let threshold = self.options.fuzzy_title?;
+        let (title, year) = title_year.as_ref()?;
+        self.titles
+            .get(year)?
+            .iter()
+            .filter(|(_, kept)| kept != hash)
+            .map(|(kept_title, kept)| (strsim::sorensen_dice(title, kept_title), kept))
+            .filter(|(similarity, _)| *similarity >= threshold)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, kept)| (kept.to_owned(), vec![String::from("fuzzy-title")]))
+    }
+}
+
+fn duplicate_of_event(
+    config: &Config,
+    event: &Event,
+    kept: String,
+    keys: Vec<String>,
+) -> Result<Event> {
+    let mut duplicate_of: Event = TypedEvent::DuplicateOf(Typed {
+        data: DuplicateOfData {
+            duplicate_of: kept,
+            event: event.hash.clone().expect("hash"),
+            extra: BTreeMap::new(),
+            keys,
+        },
+        extra: BTreeMap::new(),
+        hash: None,
+        uri: None,
+    })
+    .into();
+    event::ensure_hash_with(&mut duplicate_of, config.hash_algorithm.unwrap_or_default())?;
+    Ok(duplicate_of)
+}
+
+/// Pass through events, and drop or link documents that have the
+/// same identity as a document in the sink or earlier in the stream
+pub fn run_with<F>(
+    config: &Config,
+    in_events: impl Iterator<Item = Result<Event>>,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    let options = Options::from_config(config)?;
+    let mode = options.mode;
+    let mut deduper = Deduper::new(options);
+
+    // Documents that are already in a local sink are kept over
+    // documents in the stream
+    if !embedded::is_remote_target(&config.db) && Path::new(&config.db).exists() {
+        generator::run_f(&config.db, config, false, &mut |event: Event| {
+            if event.r#type == "document" {
+                deduper.check(&event);
+            }
+            Ok(())
+        })
+        .with_context(|| format!("Failed to read documents from {}", config.db))?;
+    }
+
+    for result in in_events {
+        let event = result?;
+        if event.r#type == "document" {
+            if let Some((kept, keys)) = deduper.check(&event) {
+                match mode {
+                    Mode::Drop => continue,
+                    // The link comes first so that later steps know
+                    // that the document is a duplicate when they see it
+                    Mode::Link => f(duplicate_of_event(config, &event, kept, keys)?)?,
+                }
+            }
+        }
+        f(event)?;
+    }
+
+    Ok(())
+}
+
+pub fn run() -> Result<()> {
+    let MapContext {
+        config,
+        in_events,
+        timestamp_override: _,
+        mut writer,
+    } = embedded::get_map_context()?;
+    let mut f = |event| embedded::write_event(&mut writer, &event);
+    run_with(&config, in_events, &mut f)
+}
+
+pub fn run_in_process(context: StepContext) -> Result<()> {
+    let output = context
+        .output
+        .ok_or(Error::msg("Missing output for dedupe step"))?;
+    let mut f = |event| embedded::send_event(&output, event);
+    let in_events = embedded::channel_events(context.input);
+    run_with(&context.config, in_events, &mut f)
+}
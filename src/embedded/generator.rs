@@ -2,25 +2,32 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 
 use anyhow::{Context, Error, Result};
-use log::trace;
+use log::{trace, warn};
 use reqwest::blocking::Client;
 use rusqlite::Connection;
-use serde_json::{json, Value};
 use url::Url;
 
+use lib_sr::answers;
 use lib_sr::common;
 use lib_sr::event;
-use lib_sr::event::Event;
+use lib_sr::event::{Event, Typed, TypedEvent};
+use lib_sr::in_process::StepContext;
 use lib_sr::sqlite;
 use lib_sr::{Config, Label};
 
 use crate::embedded;
+use crate::embedded::revisions;
+use crate::embedded::revisions::Plan;
 use crate::embedded::GeneratorContext;
 
 const SELECT_DOCUMENTS: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type = 'document' ORDER BY uri NULLS LAST, hash";
+const SELECT_DOCUMENT_VERSIONS: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type = 'document' AND uri IN (SELECT uri FROM srvc_event WHERE type = 'document' GROUP BY uri HAVING count(*) > 1) ORDER BY uri, rowid";
 const SELECT_LABELS: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type = 'label' ORDER BY data->>'$.id', hash";
 const SELECT_LABEL_ANSWERS_FOR_EVENT: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type = 'label-answer' AND data->>'$.event' = ? ORDER BY data->>'$.timestamp', hash";
-const SELECT_OTHER_EVENTS: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type <> 'document' AND type <> 'label' AND type <> 'label-answer' ORDER BY uri NULLS LAST, hash";
+const SELECT_LABEL_ANSWER_RETRACTIONS_FOR_EVENT: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type = 'label-answer-retraction' AND data->>'$.event' = ? ORDER BY data->>'$.timestamp', hash";
+const SELECT_REDACTIONS: &str =
+    "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type = 'redaction'";
+const SELECT_OTHER_EVENTS: &str = "SELECT data, extra, hash, type, uri FROM srvc_event WHERE type <> 'document' AND type <> 'label' AND (type <> 'label-answer' AND type <> 'label-answer-retraction' OR extra->>'$.redacted' IS NOT NULL) ORDER BY uri NULLS LAST, hash";
 
 fn get_label_events(config: &Config) -> Result<Vec<Event>> {
     let mut labels: Vec<&Label> = config.labels.values().collect();
@@ -29,39 +36,54 @@ fn get_label_events(config: &Config) -> Result<Vec<Event>> {
     let mut events = Vec::new();
 
     for label in labels {
-        let data: Value = serde_json::from_str(
-            &serde_json::to_string(&label).with_context(|| "Serialization failure")?,
-        )
-        .with_context(|| "Deserialization failure")?;
-        let mut data_m = data.as_object().unwrap().clone();
-        let hash = data_m
-            .get("hash")
-            .expect("hash")
-            .as_str()
-            .map(|s| String::from(s));
-        data_m.remove("hash");
-        let event = Event {
-            data: Some(json!(data_m)),
+        let mut data = label.to_owned();
+        let hash = data.hash.take();
+        let event = TypedEvent::Label(Typed {
+            data,
             extra: BTreeMap::new(),
             hash,
-            r#type: String::from("label"),
             uri: None,
-        };
-        events.push(event);
+        });
+        events.push(event.into());
     }
 
     Ok(events)
 }
 
-fn run_jsonl<F>(file_or_url: &str, config: &Config, f: &mut F) -> Result<()>
+fn read_event(config: &Config, line_no: usize, result: Result<Event>) -> Result<TypedEvent> {
+    let mut event = match result {
+        Ok(evt) => {
+            trace! {"Parsed event: {}", evt.hash.to_owned().unwrap_or(String::from("No hash"))};
+            evt
+        }
+        Err(e) => {
+            trace! {"run_jsonl event parse error"};
+            Err(e).with_context(|| format!("Cannot parse line {} as JSON", line_no))?
+        }
+    };
+    event::ensure_hash_with(&mut event, config.hash_algorithm.unwrap_or_default())
+        .with_context(|| format!("Invalid event on line {}", line_no))?;
+    Ok(TypedEvent::from_event_or_other(event))
+}
+
+fn run_jsonl<F>(
+    file_or_url: &str,
+    config: &Config,
+    options: &revisions::Options,
+    skip_invalid: bool,
+    f: &mut F,
+) -> Result<()>
 where
     F: FnMut(Event) -> Result<()>,
 {
     trace! {"run_jsonl"};
+    // Hashes of redaction events by the hash of the event they redact
+    let mut redactions: HashMap<String, String> = HashMap::new();
+    let mut redaction_events: HashMap<String, Event> = HashMap::new();
     let (reader, _, _) = common::get_file_or_url(&Client::default(), file_or_url)?;
-    let in_events = event::events(reader);
+    let in_events = event::EventReader::new(reader);
 
-    let mut answers: HashMap<String, Vec<Event>> = HashMap::new();
+    let mut answers: HashMap<String, Vec<TypedEvent>> = HashMap::new();
     let mut labels: HashMap<String, Event> = HashMap::new();
     let mut events: Vec<Event> = Vec::new();
 
@@ -69,63 +91,59 @@ where
         labels.insert(event.hash.clone().expect("hash"), event);
     }
 
-    for (i, result) in in_events.enumerate() {
-        let mut event = match result {
-            Ok(evt) => {
-                trace! {"Parsed event: {}", evt.hash.to_owned().unwrap_or(String::from("No hash"))};
-                evt
-            }
-            Err(e) => {
-                trace! {"run_jsonl event parse error"};
-                Err(e).with_context(|| format!("Cannot parse line {} as JSON", i + 1))?
+    for (line_no, result) in in_events {
+        let typed = match read_event(config, line_no, result) {
+            Ok(typed) => typed,
+            Err(e) if skip_invalid => {
+                warn!("Skipped invalid event: {:#}", e);
+                continue;
             }
+            Err(e) => return Err(e),
         };
-        let expected_hash = lib_sr::event::event_hash(event.clone())?;
-        let hash = event.hash.clone().unwrap_or("".to_string());
-        if hash == "" {
-            event.hash = Some(expected_hash);
-        } else if expected_hash != hash {
-            return Err(Error::msg(format!(
-                "Incorrect event hash. Expected: \"{}\". Found: \"{}\".",
-                expected_hash, hash
-            )));
-        }
+        let hash = typed.hash().expect("hash").to_owned();
 
-        if event.r#type == "label" {
-            labels.insert(event.hash.clone().expect("hash"), event);
-        } else if event.r#type == "label-answer" {
-            match &event.data {
-                Some(data) => match data.get("event") {
-                    Some(event_hash) => {
-                        let hash = event_hash.as_str().expect("str").to_owned();
-                        match answers.get_mut(&hash) {
-                            Some(v) => v.push(event.clone()),
-                            None => {
-                                let mut v = Vec::new();
-                                v.push(event.clone());
-                                answers.insert(hash, v);
-                            }
-                        }
-                    }
-                    None => {
-                        return Err(Error::msg(format!(
-                            "label-answer is missing the \"data.event\" property. Event hash: {}",
-                            event.hash.unwrap()
-                        )))
-                    }
-                },
-                None => {
-                    return Err(Error::msg(format!(
-                        "label-answer is missing the \"data\" property. Event hash: {}",
-                        event.hash.unwrap()
-                    )))
-                }
+        match typed {
+            TypedEvent::Label(label) => {
+                labels.insert(hash, TypedEvent::Label(label).into());
+            }
+            TypedEvent::LabelAnswer(answer) => {
+                answers
+                    .entry(answer.data.event.clone())
+                    .or_default()
+                    .push(TypedEvent::LabelAnswer(answer));
             }
-        } else {
-            events.push(event);
+            TypedEvent::LabelAnswerRetraction(retraction) => {
+                answers
+                    .entry(retraction.data.event.clone())
+                    .or_default()
+                    .push(TypedEvent::LabelAnswerRetraction(retraction));
+            }
+            TypedEvent::Redaction(redaction) => {
+                redactions.insert(redaction.data.event.clone(), hash.clone());
+                let event: Event = TypedEvent::Redaction(redaction).into();
+                redaction_events.insert(hash, event.clone());
+                events.push(event);
+            }
+            typed => events.push(typed.into()),
         }
     }
 
+    // Redacted documents have no content to compare, so they are
+    // left out of chains of versions
+    let redacted = redactions.keys().cloned().collect();
+    let chains = revisions::chains(events.iter(), &redacted);
+    let plan = Plan::new(options, config, chains, &mut |hash| {
+        Ok(related_answers_jsonl(hash, &answers))
+    })?;
+
+    // Emit tombstones in place of redacted events, wherever the
+    // redaction appears in the file
+    let mut f = redactions_first(&redaction_events, f);
+    let f = &mut |event: Event| match event.hash.as_ref().and_then(|h| redactions.get(h)) {
+        Some(redaction_hash) => f(event::tombstone(&event, redaction_hash)),
+        None => f(event),
+    };
+
     // Emit all labels before any label-answers
     // Because a label-answer can depend on two different labels, it is not
     // always possible to group every label with all of its answers.
@@ -143,32 +161,92 @@ where
     }
 
     for event in events {
-        let event_hash = event.hash.clone().expect("hash");
-        f(event)?;
-        write_event_answers_jsonl(&event_hash, &answers, f)?;
+        plan.emit(event, f, &mut |hash, f| {
+            write_event_answers_jsonl(hash, &answers, f)
+        })?;
     }
 
     Ok(())
 }
 
+/// Emit the redaction that a tombstone names just before the
+/// tombstone, so that sinks can check the tombstone against it. Each
+/// redaction is emitted once.
+fn redactions_first<'a, F>(
+    redactions: &'a HashMap<String, Event>,
+    f: &'a mut F,
+) -> impl FnMut(Event) -> Result<()> + 'a
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    let mut emitted = HashSet::new();
+    move |event: Event| {
+        if event::is_tombstone(&event) {
+            let redaction_hash = event.extra[event::REDACTED_PROPERTY]
+                .as_str()
+                .expect("redaction hash");
+            if let Some(redaction) = redactions.get(redaction_hash) {
+                if emitted.insert(redaction_hash.to_owned()) {
+                    f(redaction.clone())?;
+                }
+            }
+        } else if event.r#type == "redaction" && !emitted.insert(event.hash.clone().expect("hash"))
+        {
+            return Ok(());
+        }
+        f(event)
+    }
+}
+
+/// Order the events that refer to an event so that each label-answer
+/// comes after the answer it supersedes and the answers in effect come
+/// last. related holds these events and the retractions of their
+/// label-answers.
+fn sort_answers(hash: &str, events: &[TypedEvent], related: &[TypedEvent]) -> Vec<TypedEvent> {
+    let positions: HashMap<&str, usize> = answers::answer_order(hash, related)
+        .into_iter()
+        .enumerate()
+        .map(|(i, h)| (h, i))
+        .collect();
+    let mut sorted = events.to_vec();
+    sorted.sort_by_key(|event| event.hash().and_then(|h| positions.get(h).copied()));
+    sorted
+}
+
+/// The events that refer to an event, and the retractions of its
+/// label-answers
+fn related_answers_jsonl(
+    hash: &str,
+    answers: &HashMap<String, Vec<TypedEvent>>,
+) -> Vec<TypedEvent> {
+    let mut related = Vec::new();
+    if let Some(event_answers) = answers.get(hash) {
+        related.extend(event_answers.iter().cloned());
+        for answer in event_answers {
+            if let Some(v) = answer.hash().and_then(|h| answers.get(h)) {
+                related.extend(v.iter().cloned());
+            }
+        }
+    }
+    related
+}
+
 fn write_event_answers_jsonl<F>(
     hash: &str,
-    answers: &HashMap<String, Vec<Event>>,
+    answers: &HashMap<String, Vec<TypedEvent>>,
     f: &mut F,
 ) -> Result<()>
 where
     F: FnMut(Event) -> Result<()>,
 {
-    match answers.get(hash) {
-        Some(event_answers) => {
-            for answer in event_answers {
-                let answer_hash = answer.hash.clone().expect("hash");
-                f(answer.to_owned())?;
-                write_event_answers_jsonl(&answer_hash, &answers, f)?;
-            }
+    if let Some(event_answers) = answers.get(hash) {
+        let related = related_answers_jsonl(hash, answers);
+        for answer in sort_answers(hash, event_answers, &related) {
+            let answer_hash = answer.hash().expect("hash").to_owned();
+            f(answer.into())?;
+            write_event_answers_jsonl(&answer_hash, answers, f)?;
         }
-        None => {}
-    };
+    }
     Ok(())
 }
 
@@ -209,22 +287,52 @@ where
     Ok(())
 }
 
+fn query_events_for_event(conn: &Connection, sql: &str, hash: &str) -> Result<Vec<TypedEvent>> {
+    let mut stmt = sqlite::prepare_cached(conn, sql)?;
+    let mut rows = stmt
+        .query([hash])
+        .with_context(|| format!("Failed to execute prepared statement: {}", sql))?;
+    let mut events = Vec::new();
+    while let Some(row) = rows.next().with_context(|| "Failed to get next row")? {
+        events.push(TypedEvent::from_event_or_other(sqlite::parse_event(row)?));
+    }
+    Ok(events)
+}
+
+/// The label-answers and retractions that refer to an event, and the
+/// events and the retractions of its label-answers
+fn query_related_answers(
+    conn: &Connection,
+    hash: &str,
+) -> Result<(Vec<TypedEvent>, Vec<TypedEvent>)> {
+    let mut events = query_events_for_event(conn, SELECT_LABEL_ANSWERS_FOR_EVENT, hash)?;
+    events.extend(query_events_for_event(
+        conn,
+        SELECT_LABEL_ANSWER_RETRACTIONS_FOR_EVENT,
+        hash,
+    )?);
+    let mut related = events.clone();
+    for event in &events {
+        if let TypedEvent::LabelAnswer(answer) = event {
+            related.extend(query_events_for_event(
+                conn,
+                SELECT_LABEL_ANSWER_RETRACTIONS_FOR_EVENT,
+                answer.hash.as_deref().expect("hash"),
+            )?);
+        }
+    }
+    Ok((events, related))
+}
+
 fn write_event_answers_sqlite<F>(conn: &Connection, f: &mut F, doc_hash: &str) -> Result<()>
 where
     F: FnMut(Event) -> Result<()>,
 {
-    let mut stmt = sqlite::prepare_cached(&conn, SELECT_LABEL_ANSWERS_FOR_EVENT)?;
-    let mut rows = stmt.query([doc_hash]).with_context(|| {
-        format!(
-            "Failed to execute prepared statement: {}",
-            SELECT_LABEL_ANSWERS_FOR_EVENT
-        )
-    })?;
-    while let Some(row) = rows.next().with_context(|| "Failed to get next row")? {
-        let event = sqlite::parse_event(row)?;
-        let hash = event.hash.clone();
-        f(event)?;
-        write_event_answers_sqlite(conn, f, &hash.expect("hash"))?;
+    let (events, related) = query_related_answers(conn, doc_hash)?;
+    for event in sort_answers(doc_hash, &events, &related) {
+        let hash = event.hash().expect("hash").to_owned();
+        f(event.into())?;
+        write_event_answers_sqlite(conn, f, &hash)?;
     }
     Ok(())
 }
@@ -248,7 +356,36 @@ where
     Ok(())
 }
 
-pub fn write_documents_sqlite<F>(conn: &Connection, f: &mut F) -> Result<()>
+fn query_events(conn: &Connection, sql: &str) -> Result<Vec<Event>> {
+    let mut stmt = sqlite::prepare_cached(conn, sql)?;
+    let mut rows = stmt
+        .query([])
+        .with_context(|| format!("Failed to execute prepared statement: {}", sql))?;
+    let mut events = Vec::new();
+    while let Some(row) = rows.next().with_context(|| "Failed to get next row")? {
+        events.push(sqlite::parse_event(row)?);
+    }
+    Ok(events)
+}
+
+/// Plan the output for versions of the same document. Versions are
+/// ordered by when they were added to the database.
+fn plan_revisions_sqlite(
+    conn: &Connection,
+    config: &Config,
+    options: &revisions::Options,
+) -> Result<Plan> {
+    if options.revisions.is_none() {
+        return Ok(Plan::default());
+    }
+    let versions = query_events(conn, SELECT_DOCUMENT_VERSIONS)?;
+    let chains = revisions::chains(versions.iter(), &HashSet::new());
+    Plan::new(options, config, chains, &mut |hash| {
+        Ok(query_related_answers(conn, hash)?.1)
+    })
+}
+
+pub fn write_documents_sqlite<F>(conn: &Connection, plan: &Plan, f: &mut F) -> Result<()>
 where
     F: FnMut(Event) -> Result<()>,
 {
@@ -258,70 +395,112 @@ where
         .with_context(|| format!("Failed to execute prepared statement: {}", SELECT_DOCUMENTS))?;
     while let Some(row) = rows.next().with_context(|| "Failed to get next row")? {
         let event = sqlite::parse_event(row)?;
-        f(event.clone())?;
-        write_event_answers_sqlite(&conn, f, &event.hash.expect("hash"))?;
+        plan.emit(event, f, &mut |hash, f| {
+            write_event_answers_sqlite(conn, f, hash)
+        })?;
     }
     Ok(())
 }
 
-pub fn run_sqlite<F>(file: &str, config: &Config, f: &mut F) -> Result<()>
+pub fn run_sqlite<F>(
+    file: &str,
+    config: &Config,
+    options: &revisions::Options,
+    f: &mut F,
+) -> Result<()>
 where
     F: FnMut(Event) -> Result<()>,
 {
     let conn = sqlite::open_ro(&PathBuf::from(file))?;
+    let plan = plan_revisions_sqlite(&conn, config, options)?;
+    let redactions = query_events(&conn, SELECT_REDACTIONS)?
+        .into_iter()
+        .map(|event| (event.hash.clone().expect("hash"), event))
+        .collect();
+    let f = &mut redactions_first(&redactions, f);
 
     write_labels_sqlite(&conn, config, f)?;
     write_other_events_sqlite(&conn, f)?;
-    write_documents_sqlite(&conn, f)?;
+    write_documents_sqlite(&conn, &plan, f)?;
 
     sqlite::close(conn)?;
     Ok(())
 }
 
-pub fn run_f<F>(file_or_url: &str, config: &Config, f: &mut F) -> Result<()>
+pub fn run_f<F>(file_or_url: &str, config: &Config, skip_invalid: bool, f: &mut F) -> Result<()>
 where
     F: FnMut(Event) -> Result<()>,
 {
+    let options = revisions::Options::default();
     match Url::parse(file_or_url) {
-        Ok(_) => run_jsonl(file_or_url, config, f),
+        Ok(_) => run_jsonl(file_or_url, config, &options, skip_invalid, f),
         Err(_) => {
             if common::has_sqlite_ext(file_or_url) {
-                run_sqlite(file_or_url, config, f)
+                run_sqlite(file_or_url, config, &options, f)
             } else {
-                run_jsonl(file_or_url, config, f)
+                run_jsonl(file_or_url, config, &options, skip_invalid, f)
             }
         }
     }
 }
 
-pub fn run(file_or_url: &str) -> Result<()> {
-    let GeneratorContext {
-        config,
-        in_events,
-        mut writer,
-    } = embedded::get_generator_context()?;
-
+/// Pass the input events through, and then emit the events from a
+/// file or URL
+pub fn run_with<F>(
+    file_or_url: &str,
+    config: &Config,
+    skip_invalid: bool,
+    in_events: impl Iterator<Item = Result<Event>>,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    let options = revisions::Options::from_config(config)?;
     let mut hashes = HashSet::new();
 
     for event in in_events {
-        embedded::write_event_dedupe(&mut writer, &event?, &mut hashes)?;
+        embedded::emit_dedupe(f, event?, &mut hashes)?;
     }
 
     match Url::parse(file_or_url) {
         Ok(_) => {
-            let mut f_dedupe =
-                |event| embedded::write_event_dedupe(&mut writer, &event, &mut hashes);
-            run_jsonl(file_or_url, &config, &mut f_dedupe)
+            let mut f_dedupe = |event| embedded::emit_dedupe(f, event, &mut hashes);
+            run_jsonl(file_or_url, config, &options, skip_invalid, &mut f_dedupe)
         }
         Err(_) => {
             if common::has_sqlite_ext(file_or_url) {
-                let mut f = |event| embedded::write_event(&mut writer, &event);
-                run_sqlite(file_or_url, &config, &mut f)
+                run_sqlite(file_or_url, config, &options, f)
             } else {
-                let mut f_dedupe =
-                    |event| embedded::write_event_dedupe(&mut writer, &event, &mut hashes);
-                run_jsonl(file_or_url, &config, &mut f_dedupe)
+                let mut f_dedupe = |event| embedded::emit_dedupe(f, event, &mut hashes);
+                run_jsonl(file_or_url, config, &options, skip_invalid, &mut f_dedupe)
             }
         }
     }
 }
+
+pub fn run(file_or_url: &str, skip_invalid: bool) -> Result<()> {
+    let GeneratorContext {
+        config,
+        in_events,
+        mut writer,
+    } = embedded::get_generator_context()?;
+
+    let mut f = |event| embedded::write_event(&mut writer, &event);
+    run_with(file_or_url, &config, skip_invalid, in_events, &mut f)
+}
+
+pub fn run_in_process(file_or_url: &str, skip_invalid: bool, context: StepContext) -> Result<()> {
+    let output = context
+        .output
+        .ok_or(Error::msg("Missing output for generator step"))?;
+    let mut f = |event| embedded::send_event(&output, event);
+    let in_events = embedded::channel_events(context.input);
+    run_with(
+        file_or_url,
+        &context.config,
+        skip_invalid,
+        in_events,
+        &mut f,
+    )
+}
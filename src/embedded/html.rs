@@ -21,7 +21,9 @@ use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use url::Url;
 
+use lib_sr::blob;
 use lib_sr::event::Event;
+use lib_sr::signature::{self, SigningKey};
 use lib_sr::{common, event, Config};
 
 use crate::embedded;
@@ -30,9 +32,14 @@ use crate::embedded::MapContext;
 struct AppContext {
     config: Config,
     current_doc_events: Option<Vec<Event>>,
+    // When the current document was first requested, in milliseconds
+    displayed_ms: Option<u64>,
     doc_events: DocEventsIterator,
     html: String,
     html_file_path: Option<PathBuf>,
+    session_ended: bool,
+    signing_key: Option<SigningKey>,
+    step_name: &'static str,
     timestamp_override: Option<u64>,
     writer: Box<dyn Write + Send + Sync>,
 }
@@ -101,13 +108,46 @@ async fn get_config(app_ctx_mutex: Data<Mutex<AppContext>>) -> HttpResponse {
 #[routes]
 #[get("/current-doc-events")] // Deprecated in v0.14.0
 #[get("/srvc/current-doc-events")]
-async fn get_current_doc_events(app_ctx_mutex: Data<Mutex<AppContext>>) -> HttpResponse {
-    let current_doc_events = &app_ctx_mutex.lock().unwrap().current_doc_events;
-    let events = match current_doc_events {
+async fn get_current_doc_events(
+    app_ctx_mutex: Data<Mutex<AppContext>>,
+) -> std::io::Result<HttpResponse> {
+    let app_ctx = &mut app_ctx_mutex.lock().unwrap();
+    let events = match &app_ctx.current_doc_events {
         Some(events) => events.to_owned(),
         None => Vec::new(),
     };
-    HttpResponse::Ok().json(json!(events))
+    if !events.is_empty() && app_ctx.displayed_ms.is_none() {
+        app_ctx.displayed_ms = Some(
+            embedded::epoch_ms(app_ctx.timestamp_override).map_err(|e| err(&format!("{:#}", e)))?,
+        );
+    }
+    Ok(HttpResponse::Ok().json(json!(events)))
+}
+
+/// Serve a blob that the current document's events reference
+#[get("/srvc/blob/{hash}")]
+async fn get_blob(app_ctx_mutex: Data<Mutex<AppContext>>, hash: web::Path<String>) -> HttpResponse {
+    let (store, media_type) = {
+        let app_ctx = app_ctx_mutex.lock().unwrap();
+        let events = app_ctx.current_doc_events.as_deref().unwrap_or_default();
+        if !events
+            .iter()
+            .any(|event| blob::blob_hashes(event).contains(&hash.as_str()))
+        {
+            return HttpResponse::NotFound().finish();
+        }
+        let media_type = events
+            .iter()
+            .find_map(|event| blob::media_type(event, &hash))
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+        (embedded::blob_store(&app_ctx.config), media_type)
+    };
+    match store.get(&hash) {
+        Ok(Some(bytes)) => HttpResponse::Ok().content_type(media_type).body(bytes),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::BadRequest().body(format!("{:#}", e)),
+    }
 }
 
 #[get("/")]
@@ -136,6 +176,7 @@ async fn post_submit_label_answers(
     request: Json<SubmitLabelAnswersRequest>,
 ) -> std::io::Result<HttpResponse> {
     let app_ctx = &mut app_ctx_mutex.lock().unwrap();
+    let hash_algorithm = app_ctx.config.hash_algorithm.unwrap_or_default();
     let mut hashes = HashSet::new();
     match app_ctx.current_doc_events.to_owned() {
         Some(events) => {
@@ -153,9 +194,26 @@ async fn post_submit_label_answers(
     match request.answers.to_owned() {
         Some(events) => {
             for mut event in events {
-                event::ensure_hash(&mut event)
+                // Answers that the page already submitted keep their hash
+                if event.r#type == "label-answer" && event.hash.is_none() {
+                    if let Some(displayed_ms) = app_ctx.displayed_ms {
+                        embedded::insert_time_on_task(
+                            &mut event,
+                            displayed_ms,
+                            app_ctx.timestamp_override,
+                        )
+                        .map_err(|e| err(&format!("{:#}", e)))?;
+                    }
+                }
+                event::ensure_hash_with(&mut event, hash_algorithm)
                     .with_context(|| "")
                     .expect("Hash mismatch");
+                if let Some(key) = &app_ctx.signing_key {
+                    if event.r#type == "label-answer" && !signature::is_signed(&event) {
+                        signature::sign_event(&mut event, key, hash_algorithm)
+                            .map_err(|e| err(&format!("{:#}", e)))?;
+                    }
+                }
                 let hash = event.hash.clone().expect("Hash not set");
                 if !hashes.contains(&hash) {
                     serde_json::to_writer(&mut app_ctx.writer, &event)?;
@@ -166,8 +224,12 @@ async fn post_submit_label_answers(
         }
         None => {}
     };
-    app_ctx.writer.flush()?;
     app_ctx.current_doc_events = app_ctx.doc_events.next().transpose()?;
+    app_ctx.displayed_ms = None;
+    if app_ctx.current_doc_events.is_none() {
+        write_session_end(app_ctx)?;
+    }
+    app_ctx.writer.flush()?;
     Ok(HttpResponse::Ok().json(hashmap! {"success" => true}))
 }
 
@@ -245,6 +307,28 @@ fn write_leading_non_docs(app_ctx: &mut AppContext) -> std::io::Result<()> {
     Ok(())
 }
 
+fn write_session_event(app_ctx: &mut AppContext, phase: &str) -> std::io::Result<()> {
+    let event = embedded::session_event(
+        &app_ctx.config,
+        app_ctx.step_name,
+        phase,
+        app_ctx.timestamp_override,
+    )
+    .map_err(|e| err(&format!("{:#}", e)))?;
+    serde_json::to_writer(&mut app_ctx.writer, &event)?;
+    app_ctx.writer.write(b"\n")?;
+    Ok(())
+}
+
+/// End the reviewer's session once there are no documents left
+fn write_session_end(app_ctx: &mut AppContext) -> std::io::Result<()> {
+    if !app_ctx.session_ended {
+        write_session_event(app_ctx, "end")?;
+        app_ctx.session_ended = true;
+    }
+    Ok(())
+}
+
 fn write_port_event(app_ctx_mutex: Data<Mutex<AppContext>>, port: u16) -> std::io::Result<()> {
     let mut app_ctx = app_ctx_mutex.lock().unwrap();
     let mut data = BTreeMap::new();
@@ -260,7 +344,8 @@ fn write_port_event(app_ctx_mutex: Data<Mutex<AppContext>>, port: u16) -> std::i
         r#type: String::from("control"),
         uri: None,
     };
-    port_event.hash = Some(match event::event_hash(port_event.clone()) {
+    let hash_algorithm = app_ctx.config.hash_algorithm.unwrap_or_default();
+    port_event.hash = Some(match event::hash_event(&port_event, hash_algorithm) {
         Ok(hash) => Ok(hash),
         Err(_) => Err(err("Failed to calculate event hash")),
     }?);
@@ -272,6 +357,7 @@ fn write_port_event(app_ctx_mutex: Data<Mutex<AppContext>>, port: u16) -> std::i
 
 #[actix_web::main]
 async fn serve(
+    step_name: &'static str,
     port: u16,
     map_ctx: MapContext,
     html: String,
@@ -283,16 +369,26 @@ async fn serve(
         label_events: HashMap::new(),
         next_doc: None,
     };
+    let signing_key =
+        signature::signing_key(&map_ctx.config).map_err(|e| err(&format!("{:#}", e)))?;
     let mut app_ctx = AppContext {
         config: map_ctx.config,
         current_doc_events: doc_events.next().transpose()?,
+        displayed_ms: None,
         doc_events,
         html,
         html_file_path: html_file_path.clone(),
+        session_ended: false,
+        signing_key,
+        step_name,
         timestamp_override: map_ctx.timestamp_override,
         writer: map_ctx.writer,
     };
+    write_session_event(&mut app_ctx, "start")?;
     write_leading_non_docs(&mut app_ctx)?;
+    if app_ctx.current_doc_events.is_none() {
+        write_session_end(&mut app_ctx)?;
+    }
     let app_ctx_mutex = Data::new(Mutex::new(app_ctx));
     let acm = app_ctx_mutex.to_owned();
     let num_workers = match &url {
@@ -305,6 +401,7 @@ async fn serve(
             .wrap(middleware::Compress::default())
             .app_data(app_ctx_mutex.to_owned())
             .service(get_config)
+            .service(get_blob)
             .service(get_current_doc_events)
             .service(get_index)
             .service(post_submit_label_answers);
@@ -340,7 +437,12 @@ async fn serve(
     server.run().await
 }
 
-pub fn run_with_html(html: String, path: Option<PathBuf>, url: Option<Url>) -> Result<()> {
+pub fn run_with_html(
+    step_name: &'static str,
+    html: String,
+    path: Option<PathBuf>,
+    url: Option<Url>,
+) -> Result<()> {
     let map_ctx = embedded::get_map_context()?;
     let port = map_ctx
         .config
@@ -351,12 +453,12 @@ pub fn run_with_html(html: String, path: Option<PathBuf>, url: Option<Url>) -> R
         .flatten()
         .unwrap_or(0) as u16;
 
-    serve(port, map_ctx, html, path, url).with_context(|| "Error starting server")
+    serve(step_name, port, map_ctx, html, path, url).with_context(|| "Error starting server")
 }
 
 pub fn run(file_or_url: &str) -> Result<()> {
     info! {"Serving HTML step from {}", file_or_url};
     let (html, path, url) = common::get_file_or_url_string(&Client::default(), file_or_url)?;
     debug! {"Read {} bytes", html.len()};
-    run_with_html(html, path, url)
+    run_with_html("html", html, path, url)
 }
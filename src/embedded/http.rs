@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 
 use lib_sr::event::Event;
+use lib_sr::in_process::StepContext;
 use lib_sr::{event, Config};
 
 use crate::embedded;
@@ -28,6 +29,7 @@ fn do_request(
     client: &Client,
     url: &Url,
     config: &Config,
+    token: Option<&str>,
     events: Vec<Event>,
 ) -> Result<Vec<Event>> {
     let er = EventsRequest {
@@ -37,7 +39,7 @@ fn do_request(
 
     let mut request = client.post(url.clone()).json(&er);
 
-    if let Ok(token) = env::var("SRVC_TOKEN") {
+    if let Some(token) = token {
         request = request.header("Authorization", format!("Bearer {}", token));
     }
 
@@ -61,15 +63,20 @@ fn do_request(
     }
 }
 
-pub fn run(url_str: &str) -> Result<()> {
+/// Send each document and the events that follow it to a URL, and
+/// emit the events in the response
+pub fn run_with<F>(
+    url_str: &str,
+    config: &Config,
+    in_events: impl Iterator<Item = Result<Event>>,
+    token: Option<&str>,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(Event) -> Result<()>,
+{
     let url = Url::parse(url_str).with_context(|| format! {"Cannot parse URL: {}", url_str})?;
 
-    let MapContext {
-        config,
-        in_events,
-        timestamp_override: _,
-        mut writer,
-    } = embedded::get_map_context()?;
     let mut hashes = HashSet::new();
     let mut events = Vec::new();
     let client = Client::builder()
@@ -85,28 +92,56 @@ pub fn run(url_str: &str) -> Result<()> {
 
         if event.r#type == "document" {
             if events.len() != 0 {
-                for mut ev in do_request(&client, &url, &config, events)? {
-                    event::ensure_hash(&mut ev)?;
+                for mut ev in do_request(&client, &url, config, token, events)? {
+                    event::ensure_hash_with(&mut ev, config.hash_algorithm.unwrap_or_default())?;
                     // Write events from response
-                    embedded::write_event_dedupe(&mut writer, &ev, &mut hashes)?;
+                    embedded::emit_dedupe(f, ev, &mut hashes)?;
                 }
             }
             events = vec![event];
         } else if events.len() == 0 {
             // Write leading non-docs
-            embedded::write_event_dedupe(&mut writer, &event, &mut hashes)?;
+            embedded::emit_dedupe(f, event, &mut hashes)?;
         } else {
             events.push(event);
         }
     }
 
     if events.len() != 0 {
-        for mut ev in do_request(&client, &url, &config, events)? {
-            event::ensure_hash(&mut ev)?;
+        for mut ev in do_request(&client, &url, config, token, events)? {
+            event::ensure_hash_with(&mut ev, config.hash_algorithm.unwrap_or_default())?;
             // Write events from response
-            embedded::write_event_dedupe(&mut writer, &ev, &mut hashes)?;
+            embedded::emit_dedupe(f, ev, &mut hashes)?;
         }
     }
 
     Ok(())
 }
+
+pub fn run(url_str: &str) -> Result<()> {
+    let MapContext {
+        config,
+        in_events,
+        timestamp_override: _,
+        mut writer,
+    } = embedded::get_map_context()?;
+    let token = env::var("SRVC_TOKEN").ok();
+    let mut f = |event| embedded::write_event(&mut writer, &event);
+    run_with(url_str, &config, in_events, token.as_deref(), &mut f)
+}
+
+pub fn run_in_process(url_str: &str, context: StepContext) -> Result<()> {
+    let token = context.var("SRVC_TOKEN").map(String::from);
+    let output = context
+        .output
+        .ok_or(Error::msg("Missing output for http step"))?;
+    let mut f = |event| embedded::send_event(&output, event);
+    let in_events = embedded::channel_events(context.input);
+    run_with(
+        url_str,
+        &context.config,
+        in_events,
+        token.as_deref(),
+        &mut f,
+    )
+}
@@ -7,6 +7,7 @@ use serde_json::json;
 
 use lib_sr::event;
 use lib_sr::event::Event;
+use lib_sr::signature;
 use lib_sr::Label;
 
 use crate::embedded;
@@ -218,21 +219,36 @@ pub fn run() -> Result<()> {
         mut writer,
     } = embedded::get_map_context()?;
     let mut hashes = HashSet::new();
-    let labels = config.current_labels.unwrap_or(Vec::new());
+    let hash_algorithm = config.hash_algorithm.unwrap_or_default();
+    let signing_key = signature::signing_key(&config)?;
+    let labels = config.current_labels.clone().unwrap_or(Vec::new());
     let reviewer = config
         .reviewer
+        .clone()
         .ok_or(Error::msg("\"reviewer\" not set in config"))?;
 
+    let start = embedded::session_event(&config, "label", "start", timestamp_override)?;
+    embedded::write_event(&mut writer, &start)?;
+
     for result in in_events {
         let event = result?;
         embedded::write_event_dedupe(&mut writer, &event, &mut hashes)?;
 
         if event.r#type == "document" {
             print_doc(&event)?;
+            let displayed_ms = embedded::epoch_ms(timestamp_override)?;
             for label in &labels {
                 match read_answer(label, &event, reviewer.clone(), timestamp_override)? {
                     Some(mut answer) => {
-                        answer.hash = Some(event::event_hash(answer.clone())?);
+                        embedded::insert_time_on_task(
+                            &mut answer,
+                            displayed_ms,
+                            timestamp_override,
+                        )?;
+                        match &signing_key {
+                            Some(key) => signature::sign_event(&mut answer, key, hash_algorithm)?,
+                            None => answer.hash = Some(event::hash_event(&answer, hash_algorithm)?),
+                        }
                         embedded::write_event_dedupe(&mut writer, &answer, &mut hashes)?;
                     }
                     None => {}
@@ -241,5 +257,7 @@ pub fn run() -> Result<()> {
         }
     }
 
+    let end = embedded::session_event(&config, "label", "end", timestamp_override)?;
+    embedded::write_event(&mut writer, &end)?;
     Ok(())
 }
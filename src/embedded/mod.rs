@@ -3,20 +3,24 @@ use std::env;
 use std::fs::File;
 use std::io::{BufReader, LineWriter, Write};
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, SyncSender};
 
 use anyhow::{Context, Error, Result};
-use lib_sr::common::get_epoch_sec;
+use lib_sr::common::{get_epoch_ms, get_epoch_sec};
 use serde_json::json;
 
+use lib_sr::blob::{self, BlobStore};
 use lib_sr::event::Event;
 use lib_sr::{common, event, Config};
 
+pub mod dedupe;
 pub mod generator;
 pub mod html;
 pub mod http;
 pub mod label;
 pub mod label_web;
+pub mod revisions;
 pub mod run_using;
 pub mod sink;
 pub mod skip_reviewed;
@@ -96,6 +100,16 @@ pub fn is_remote_target(db: &str) -> bool {
     target.starts_with("http://") || target.starts_with("https://")
 }
 
+/// The blob store of the project that holds the db. Blobs for a
+/// remote db or stdout go in the current directory.
+pub fn blob_store(config: &Config) -> BlobStore {
+    if config.db == "-" || is_remote_target(&config.db) {
+        BlobStore::new(PathBuf::from(blob::BLOB_DIR))
+    } else {
+        BlobStore::for_file(Path::new(&config.db))
+    }
+}
+
 pub fn api_route(remote: &str, path: &str) -> String {
     format!(
         "{}{}api/v1/{}",
@@ -163,6 +177,39 @@ pub fn write_event(mut writer: &mut Box<dyn Write + Send + Sync>, event: &Event)
     Ok(())
 }
 
+/// The events that a step running in the flow process reads from its
+/// input channel. A step with no input reads no events.
+pub fn channel_events(input: Option<Receiver<Event>>) -> impl Iterator<Item = Result<Event>> {
+    input
+        .into_iter()
+        .flat_map(|receiver| receiver.into_iter().map(Ok))
+}
+
+/// Send an event to the output channel of a step running in the flow
+/// process
+pub fn send_event(output: &SyncSender<Event>, event: Event) -> Result<()> {
+    output
+        .send(event)
+        .with_context(|| "Failed to send event to step server")
+}
+
+/// Call `f` with an event unless an event with the same hash was
+/// already passed to it
+pub fn emit_dedupe<F>(f: &mut F, event: Event, hashes: &mut HashSet<String>) -> Result<()>
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    let hash = match event.hash.clone() {
+        Some(s) => s,
+        None => Err(Error::msg("Tried to write event with no hash"))?,
+    };
+    if !hashes.contains(&hash) {
+        f(event)?;
+        hashes.insert(hash);
+    }
+    Ok(())
+}
+
 pub fn write_event_dedupe(
     writer: &mut Box<dyn Write + Send + Sync>,
     event: &Event,
@@ -189,3 +236,65 @@ pub fn insert_timestamp(
     data.insert(String::from("timestamp"), json!(timestamp));
     Ok(())
 }
+
+/// The Unix time in milliseconds. SR_TIMESTAMP_OVERRIDE is in seconds,
+/// so an override is scaled to milliseconds.
+pub fn epoch_ms(timestamp_override: Option<u64>) -> Result<u64> {
+    match timestamp_override {
+        Some(v) => Ok(v * 1000),
+        None => get_epoch_ms(),
+    }
+}
+
+/// Record when a document was displayed and when the label-answer for
+/// it was submitted. Times already in the answer are kept.
+pub fn insert_time_on_task(
+    answer: &mut Event,
+    displayed_ms: u64,
+    timestamp_override: Option<u64>,
+) -> Result<()> {
+    let submitted_ms = epoch_ms(timestamp_override)?;
+    if let Some(serde_json::Value::Object(data)) = &mut answer.data {
+        data.entry("displayed-ms").or_insert(json!(displayed_ms));
+        data.entry("submitted-ms").or_insert(json!(submitted_ms));
+    }
+    Ok(())
+}
+
+/// A control event that marks the start or end of a reviewer's
+/// session in a labeling step. The host and revision depend on where
+/// the step runs, so they are left out when the timestamp is
+/// overridden to keep the event reproducible.
+pub fn session_event(
+    config: &Config,
+    step: &str,
+    phase: &str,
+    timestamp_override: Option<u64>,
+) -> Result<Event> {
+    let mut session = BTreeMap::new();
+    if timestamp_override.is_none() {
+        session.insert("host", json!(gethostname::gethostname().to_string_lossy()));
+        if let Some(rev) = crate::REV {
+            session.insert("srvc-revision", json!(rev));
+        }
+    }
+    session.insert("phase", json!(phase));
+    session.insert("reviewer", json!(config.reviewer));
+    session.insert("srvc-version", json!(crate::VERSION));
+    session.insert("step", json!(step));
+    let mut data = BTreeMap::new();
+    data.insert(String::from("reviewer-session"), json!(session));
+    insert_timestamp(&mut data, timestamp_override)?;
+    let mut event = Event {
+        data: Some(json!(data)),
+        extra: BTreeMap::new(),
+        hash: None,
+        r#type: String::from("control"),
+        uri: None,
+    };
+    event.hash = Some(event::hash_event(
+        &event,
+        config.hash_algorithm.unwrap_or_default(),
+    )?);
+    Ok(event)
+}
@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use lib_sr::answers;
+use lib_sr::event;
+use lib_sr::event::{Event, LabelAnswerData, RevisionOfData, Typed, TypedEvent};
+use lib_sr::signature;
+use lib_sr::Config;
+
+/// Which versions of a document the generator emits
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // Every version, each after a link to the version before it
+    All,
+    // Only the newest version, after the links for the whole chain
+    Latest,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CarryMode {
+    // Emit a copy of each answer for the newer version
+    Copy,
+    // Mark the revision-of event as carrying the answers
+    #[default]
+    Link,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CarryAnswers {
+    // JSON pointers to the parts of data that may change without
+    // invalidating answers
+    pub fields: Vec<String>,
+    #[serde(default)]
+    pub mode: CarryMode,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    #[serde(alias = "carry_answers", rename = "carry-answers")]
+    pub carry_answers: Option<CarryAnswers>,
+    pub revisions: Option<Mode>,
+}
+
+impl Options {
+    pub fn from_config(config: &Config) -> Result<Options> {
+        let extra = match &config.current_step {
+            Some(step) => step.extra.to_owned(),
+            None => BTreeMap::new(),
+        };
+        let options: Options = serde_json::from_value(Value::Object(extra.into_iter().collect()))
+            .with_context(|| "Invalid options for generator step")?;
+        if options.carry_answers.is_some() && options.revisions.is_none() {
+            return Err(Error::msg("carry-answers requires the revisions option"));
+        }
+        Ok(options)
+    }
+}
+
+fn escape_pointer_token(s: &str) -> String {
+    s.replace('~', "~0").replace('/', "~1")
+}
+
+fn diff(pointer: String, old: &Value, new: &Value, changed: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let child = format!("{}/{}", pointer, escape_pointer_token(key));
+                match (old.get(key), new.get(key)) {
+                    (Some(old), Some(new)) => diff(child, old, new, changed),
+                    _ => changed.push(child),
+                }
+            }
+        }
+        _ if old == new => {}
+        _ => changed.push(pointer),
+    }
+}
+
+/// JSON pointers to the parts of new that differ from old. Objects
+/// are compared key by key, and other values as a whole.
+pub fn changed_fields(old: Option<&Value>, new: Option<&Value>) -> Vec<String> {
+    let mut changed = Vec::new();
+    diff(
+        String::new(),
+        old.unwrap_or(&Value::Null),
+        new.unwrap_or(&Value::Null),
+        &mut changed,
+    );
+    changed
+}
+
+/// True if every changed pointer is one of fields or is inside one
+fn within_fields(changed: &[String], fields: &[String]) -> bool {
+    changed.iter().all(|pointer| {
+        fields.iter().any(|field| {
+            pointer == field
+                || pointer
+                    .strip_prefix(field.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    })
+}
+
+fn copy_answer(
+    config: &Config,
+    answer: &Typed<LabelAnswerData>,
+    doc_hash: &str,
+) -> Result<Typed<LabelAnswerData>> {
+    // Copies of copies refer to the answer that was first given
+    let carried_from = answer
+        .data
+        .extra
+        .get("carried-from")
+        .cloned()
+        .unwrap_or_else(|| Value::String(answer.hash.clone().expect("hash")));
+    let mut extra = BTreeMap::new();
+    extra.insert(String::from("carried-from"), carried_from);
+    let mut event: Event = TypedEvent::LabelAnswer(Typed {
+        data: LabelAnswerData {
+            answer: answer.data.answer.clone(),
+            event: doc_hash.to_owned(),
+            extra,
+            label: answer.data.label.clone(),
+            reviewer: answer.data.reviewer.clone(),
+            timestamp: answer.data.timestamp,
+        },
+        extra: BTreeMap::new(),
+        hash: None,
+        uri: None,
+    })
+    .into();
+    event::ensure_hash_with(&mut event, config.hash_algorithm.unwrap_or_default())?;
+    match TypedEvent::try_from(event)? {
+        TypedEvent::LabelAnswer(copy) => Ok(copy),
+        _ => unreachable!("copy is a label-answer"),
+    }
+}
+
+/// How answers are carried to a new version. Copies are not signed,
+/// so answers are linked rather than copied in a reviewer's name when
+/// a signature is expected.
+fn carry_mode(
+    config: &Config,
+    carry: &CarryAnswers,
+    answers: &[Typed<LabelAnswerData>],
+) -> CarryMode {
+    let signed = config.require_signatures.unwrap_or(false)
+        || answers
+            .iter()
+            .any(|answer| answer.extra.contains_key(signature::SIGNATURE_PROPERTY));
+    match carry.mode {
+        CarryMode::Copy if signed => CarryMode::Link,
+        mode => mode,
+    }
+}
+
+fn revision_of_event(config: &Config, data: RevisionOfData) -> Result<Event> {
+    let mut event: Event = TypedEvent::RevisionOf(Typed {
+        data,
+        extra: BTreeMap::new(),
+        hash: None,
+        uri: None,
+    })
+    .into();
+    event::ensure_hash_with(&mut event, config.hash_algorithm.unwrap_or_default())?;
+    Ok(event)
+}
+
+/// The documents in events that share a uri with another document,
+/// grouped by uri and oldest first
+pub fn chains<'a>(
+    events: impl Iterator<Item = &'a Event>,
+    excluded: &HashSet<String>,
+) -> Vec<Vec<Event>> {
+    let mut by_uri: HashMap<&str, Vec<&Event>> = HashMap::new();
+    let mut uris = Vec::new();
+    for event in events {
+        let (hash, uri) = match (&event.hash, &event.uri) {
+            (Some(hash), Some(uri)) if event.r#type == "document" => (hash, uri),
+            _ => continue,
+        };
+        if excluded.contains(hash) {
+            continue;
+        }
+        let versions = by_uri.entry(uri).or_insert_with(|| {
+            uris.push(uri.as_str());
+            Vec::new()
+        });
+        if !versions
+            .iter()
+            .any(|version| version.hash.as_ref() == Some(hash))
+        {
+            versions.push(event);
+        }
+    }
+    uris.into_iter()
+        .map(|uri| by_uri.remove(uri).expect("versions"))
+        .filter(|versions| versions.len() > 1)
+        .map(|versions| versions.into_iter().cloned().collect())
+        .collect()
+}
+
+/// The events that the generator adds or leaves out around each
+/// version of a document
+#[derive(Default)]
+pub struct Plan {
+    // Copied answers to emit after each document's own answers
+    after: HashMap<String, Vec<Event>>,
+    // revision-of events to emit before each document
+    before: HashMap<String, Vec<Event>>,
+    // Older versions that are left out
+    skip: HashSet<String>,
+}
+
+impl Plan {
+    /// Plan the output for chains of versions. related returns the
+    /// label-answers for a document and the retractions of those
+    /// answers.
+    pub fn new<R>(
+        options: &Options,
+        config: &Config,
+        chains: Vec<Vec<Event>>,
+        related: &mut R,
+    ) -> Result<Plan>
+    where
+        R: FnMut(&str) -> Result<Vec<TypedEvent>>,
+    {
+        let mut plan = Plan::default();
+        let mode = match options.revisions {
+            Some(mode) => mode,
+            None => return Ok(plan),
+        };
+
+        for chain in chains {
+            let newest = chain.last().and_then(|doc| doc.hash.clone()).expect("hash");
+            let mut previous: Option<(&Event, Vec<Typed<LabelAnswerData>>)> = None;
+            for doc in &chain {
+                let hash = doc.hash.clone().expect("hash");
+                let mut events = related(&hash)?;
+                if let Some((prev, prev_answers)) = &previous {
+                    let changed = changed_fields(prev.data.as_ref(), doc.data.as_ref());
+                    let carry = options
+                        .carry_answers
+                        .as_ref()
+                        .filter(|carry| within_fields(&changed, &carry.fields))
+                        .map(|carry| carry_mode(config, carry, prev_answers));
+                    if carry == Some(CarryMode::Copy) {
+                        // Reviewers keep the answers that they gave to
+                        // this version
+                        let answered: HashSet<(String, String)> =
+                            answers::current_answers(&hash, &events)
+                                .into_iter()
+                                .map(|answer| {
+                                    (answer.data.label.clone(), answer.data.reviewer.clone())
+                                })
+                                .collect();
+                        let mut copies = Vec::new();
+                        for answer in prev_answers {
+                            let key = (answer.data.label.clone(), answer.data.reviewer.clone());
+                            if !answered.contains(&key) {
+                                copies.push(copy_answer(config, answer, &hash)?);
+                            }
+                        }
+                        if mode == Mode::All || hash == newest {
+                            plan.after.insert(
+                                hash.clone(),
+                                copies
+                                    .iter()
+                                    .cloned()
+                                    .map(|copy| TypedEvent::LabelAnswer(copy).into())
+                                    .collect(),
+                            );
+                        }
+                        events.extend(copies.into_iter().map(TypedEvent::LabelAnswer));
+                    }
+                    let link = revision_of_event(
+                        config,
+                        RevisionOfData {
+                            answers: prev_answers
+                                .iter()
+                                .cloned()
+                                .map(|answer| TypedEvent::LabelAnswer(answer).into())
+                                .collect(),
+                            carry_answers: (carry == Some(CarryMode::Link)).then_some(true),
+                            changed,
+                            event: hash.clone(),
+                            extra: BTreeMap::new(),
+                            revision_of: prev.hash.clone().expect("hash"),
+                        },
+                    )?;
+                    let target = match mode {
+                        Mode::All => hash.clone(),
+                        Mode::Latest => newest.clone(),
+                    };
+                    plan.before.entry(target).or_default().push(link);
+                }
+                if mode == Mode::Latest && hash != newest {
+                    plan.skip.insert(hash.clone());
+                }
+                let in_effect = answers::current_answers(&hash, &events)
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                previous = Some((doc, in_effect));
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Emit a document with the events planned around it.
+    /// write_answers emits the document's own answers.
+    pub fn emit<F, W>(&self, doc: Event, f: &mut F, write_answers: &mut W) -> Result<()>
+    where
+        F: FnMut(Event) -> Result<()>,
+        W: FnMut(&str, &mut F) -> Result<()>,
+    {
+        let hash = doc.hash.clone().expect("hash");
+        if self.skip.contains(&hash) {
+            return Ok(());
+        }
+        for link in self.before.get(&hash).into_iter().flatten() {
+            f(link.clone())?;
+        }
+        f(doc)?;
+        write_answers(&hash, f)?;
+        for copy in self.after.get(&hash).into_iter().flatten() {
+            f(copy.clone())?;
+        }
+        Ok(())
+    }
+}
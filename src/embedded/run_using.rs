@@ -1,19 +1,62 @@
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Error, Result};
+
+use lib_sr::step_source::{self, SourceCache, StepSource};
+
+fn command(source: &StepSource) -> Result<Command> {
+    match source {
+        StepSource::Command { dir, run } => {
+            let args = shell_words::split(run)
+                .with_context(|| format!("Failed to parse run command: {}", run))?;
+            let (program, args) = args
+                .split_first()
+                .ok_or(Error::msg(format!("No command to run in {:?}", dir)))?;
+            // A relative path is relative to the step directory, while
+            // a bare name is looked up in PATH
+            let program = if program.contains('/') {
+                dir.join(program)
+            } else {
+                PathBuf::from(program)
+            };
+            let mut cmd = Command::new(program);
+            cmd.args(args)
+                .env("SR_STEP_DIR", dir.canonicalize().unwrap_or(dir.to_owned()));
+            Ok(cmd)
+        }
+        StepSource::Flake(flake) => {
+            let mut cmd = Command::new("nix");
+            cmd.args(vec![
+                "--extra-experimental-features",
+                "nix-command",
+                "--extra-experimental-features",
+                "flakes",
+                "run",
+                flake,
+            ]);
+            Ok(cmd)
+        }
+    }
+}
+
+fn check_status(uses: &str, status: ExitStatus) -> Result<()> {
+    if status.success() {
+        return Ok(());
+    }
+    Err(Error::msg(match status.code() {
+        Some(code) => format!("Step \"{}\" failed with exit code {}", uses, code),
+        None => format!("Step \"{}\" was terminated by a signal", uses),
+    }))
+}
 
 pub fn run(uses: &str) -> Result<()> {
-    Command::new("nix")
-        .args(vec![
-            "--extra-experimental-features",
-            "nix-command",
-            "--extra-experimental-features",
-            "flakes",
-            "run",
-            &uses,
-        ])
+    let cache = SourceCache::new(PathBuf::from(step_source::SOURCE_CACHE_DIR));
+    let source = cache
+        .resolve(uses)
+        .with_context(|| format!("Failed to resolve step \"{}\"", uses))?;
+    let status = command(&source)?
         .status()
-        .with_context(|| "Failed to start step sub-process")?;
-
-    Ok(())
+        .with_context(|| format!("Failed to start step \"{}\"", uses))?;
+    check_status(uses, status)
 }
@@ -1,10 +1,11 @@
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::LineWriter;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{env, io};
 
 use anyhow::{Context, Error, Result};
@@ -12,113 +13,110 @@ use log::{debug, error, info};
 use reqwest::blocking::Client;
 use serde::Serialize;
 
+use lib_sr::common;
 use lib_sr::event;
-use lib_sr::event::Event;
+use lib_sr::event::{Event, TypedEvent};
+use lib_sr::in_process::StepContext;
+use lib_sr::signature::SignatureChecker;
 use lib_sr::sqlite;
+use lib_sr::validation::Validator;
 use lib_sr::Config;
-use lib_sr::{common, json_schema};
 
 use crate::embedded;
 
-pub fn read_hashes(file: File) -> Result<HashSet<String>> {
+/// Read the hashes of the events in a sink file, and the redactions
+/// in it by the hash of the redacted event. The redactions are added
+/// to validator.
+pub fn read_hashes(
+    file: File,
+    validator: &mut Validator,
+) -> Result<(HashSet<String>, HashMap<String, String>)> {
     let reader = BufReader::new(file);
     let events = event::events(reader);
     let mut hashes = HashSet::new();
+    let mut redactions = HashMap::new();
 
     for result in events {
-        let hash = result?.hash.ok_or(Error::msg("No hash for event"))?;
+        let event = result?;
+        let hash = event.hash.clone().ok_or(Error::msg("No hash for event"))?;
+        if let Some(target) = redaction_target(&event)? {
+            validator.add_redaction(&hash, &target);
+            redactions.insert(target, hash.clone());
+        }
         hashes.insert(hash);
     }
 
-    Ok(hashes)
+    Ok((hashes, redactions))
 }
 
-fn validation_error_message(e: jsonschema::ValidationError) -> String {
-    // Work around lifetime complications caused by jsonschema's
-    // ValidationError referencing the schema data
-    let instance_path = e.instance_path.to_string();
-    let path = if instance_path.is_empty() {
-        String::from("root")
-    } else {
-        instance_path
-    };
-    format!(
-        "JSON schema validation failed at {}: {}",
-        path,
-        e.to_string()
-    )
+/// The hash of the event that a redaction removes, or None if the
+/// event is not a redaction
+fn redaction_target(event: &Event) -> Result<Option<String>> {
+    if event.r#type != "redaction" {
+        return Ok(None);
+    }
+    match TypedEvent::try_from(event.to_owned())? {
+        TypedEvent::Redaction(redaction) => Ok(Some(redaction.data.event)),
+        _ => Ok(None),
+    }
 }
 
-fn validate_answer(
-    answer: &Event,
-    data: &serde_json::Value,
-    schema: &serde_json::Value,
-) -> Result<()> {
-    let event_hash = answer.hash.as_ref().unwrap();
-    match data.get("answer") {
-        Some(answer) => {
-            let schema = json_schema::compile(schema)?;
-            match schema.validate(answer) {
-                Ok(_) => (),
-                Err(errs) => {
-                    for e in errs {
-                        Err(Error::msg(format!(
-                            "label-answer {} failed JSON schema validation: {}",
-                            event_hash,
-                            validation_error_message(e)
-                        )))?
-                    }
-                }
-            };
+/// Replace the event with the given hash in a sink file with its
+/// tombstone. The other lines are left as they are.
+fn redact_jsonl(db: &str, hash: &str, redaction_hash: &str) -> Result<()> {
+    let path = Path::new(db);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let reader =
+        BufReader::new(File::open(path).with_context(|| format!("Failed to open db: \"{}\"", db))?);
+    let mut file = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {:?}", dir))?;
+    for line in reader.lines() {
+        let line = line.with_context(|| format!("Failed to read db: \"{}\"", db))?;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        match event::parse_event_opt(line) {
+            Ok(Some(event)) if event.hash.as_deref() == Some(hash) => {
+                serde_json::to_writer(&mut file, &event::tombstone(&event, redaction_hash))
+                    .with_context(|| "Event serialization failed")?;
+            }
+            _ => file
+                .write_all(line.as_bytes())
+                .with_context(|| "Buffer write failed")?,
         }
-        None => (),
+        file.write_all(NEWLINE)
+            .with_context(|| "Buffer write failed")?;
     }
+    file.persist(path)
+        .with_context(|| format!("Failed to write db: \"{}\"", db))?;
     Ok(())
 }
 
-fn prep_event(labels: &mut HashMap<String, Event>, result: Result<Event>) -> Result<Event> {
+fn prep_event(config: &Config, validator: &mut Validator, result: Result<Event>) -> Result<Event> {
     let mut event = result.with_context(|| "Cannot parse line as JSON")?;
-    event::ensure_hash(&mut event)?;
-    if event.r#type == "label" {
-        labels.insert(event.hash.as_ref().unwrap().to_string(), event.clone());
-    } else if event.r#type == "label-answer" {
-        let data = event.data.as_ref().expect("data");
-        let label_hash = data.get("label").expect("label").as_str().expect("string");
-        let label = match labels.get(label_hash) {
-            Some(lbl) => Ok(lbl),
-            None => {
-                debug!("prep_event Label not found with hash: {}", label_hash);
-                debug!("prep_event event: {:?}", event);
-                debug!("prep_event labels: {:?}", labels);
-                Err(Error::msg(format!(
-                    "Label not found with hash: {}",
-                    label_hash
-                )))
-            }
-        }?;
-        match label
-            .data
-            .as_ref()
-            .expect("data")
-            .as_object()
-            .expect("object")
-            .get("json_schema")
-        {
-            Some(val) => validate_answer(&event, data, val)?,
-            None => (),
-        }
-    }
+    event::ensure_hash_with(&mut event, config.hash_algorithm.unwrap_or_default())?;
+    validator.check(&event)?;
     Ok(event)
 }
 
-fn run_remote(config: &Config, in_events: impl Iterator<Item = Result<Event>>) -> Result<()> {
+#[cfg(unix)]
+const NEWLINE: &[u8] = b"\n";
+#[cfg(windows)]
+const NEWLINE: &[u8] = b"\r\n";
+
+fn run_remote(
+    config: &Config,
+    in_events: impl Iterator<Item = Result<Event>>,
+    token: Option<&str>,
+) -> Result<()> {
     let mut hashes = HashSet::new();
-    let mut labels = HashMap::new();
+    let mut validator = Validator::for_sink(SignatureChecker::new(config));
     let client = Client::new();
     let url = embedded::api_route(&config.db, "upload");
 
     for result in in_events {
-        let event = prep_event(&mut labels, result)?;
+        let event = prep_event(config, &mut validator, result)?;
         let hash = event.hash.clone().expect("Hash not set");
 
         if !hashes.contains(&hash) && event.r#type != "control" || config.sink_control_events {
@@ -128,7 +126,7 @@ fn run_remote(config: &Config, in_events: impl Iterator<Item = Result<Event>>) -
                 .header("Content-Type", "application/json")
                 .body(json);
 
-            if let Ok(token) = env::var("SRVC_TOKEN") {
+            if let Some(token) = token {
                 request = request.header("Authorization", format!("Bearer {}", token));
             }
 
@@ -176,49 +174,72 @@ fn run_local_jsonl(config: &Config, in_events: impl Iterator<Item = Result<Event
     } else {
         Some(File::open(&config.db))
     };
-    let mut hashes = match maybe_db {
-        None => HashSet::new(),         // The file is stdout
-        Some(Err(_)) => HashSet::new(), // The file may not exist yet
-        Some(Ok(file)) => read_hashes(file)?,
+    let mut validator = Validator::for_sink(SignatureChecker::new(config));
+    let (mut hashes, mut redactions) = match maybe_db {
+        None => Default::default(),         // The file is stdout
+        Some(Err(_)) => Default::default(), // The file may not exist yet
+        Some(Ok(file)) => read_hashes(file, &mut validator)?,
     };
-    let mut labels = HashMap::new();
     let mut writer = open_jsonl(&config.db)?;
 
     for result in in_events {
-        let event = prep_event(&mut labels, result)?;
+        let mut event = prep_event(config, &mut validator, result)?;
         let hash = event.hash.clone().expect("Hash not set");
+        if let Some(redaction_hash) = redactions.get(&hash) {
+            event = event::tombstone(&event, redaction_hash);
+        }
+        let target = redaction_target(&event)?;
 
         if !hashes.contains(&hash) && event.r#type != "control" || config.sink_control_events {
             info! {"Writing event to sink: {} {}", event.r#type, hash};
             event
                 .serialize(&mut serde_json::Serializer::new(&mut writer))
                 .with_context(|| "Event serialization failed")?;
-
-            #[cfg(unix)]
-            let newline = b"\n";
-            #[cfg(windows)]
-            let newline = b"\r\n";
             writer
-                .write(newline)
+                .write(NEWLINE)
                 .with_context(|| "Buffer write failed")?;
-            hashes.insert(hash);
+            hashes.insert(hash.clone());
         };
+
+        if let Some(target) = target {
+            // Stdout can't be rewritten
+            if hashes.contains(&target) && &config.db != "-" {
+                info! {"Redacting event in sink: {}", target};
+                writer.flush().with_context(|| "Buffer write failed")?;
+                redact_jsonl(&config.db, &target, &hash)?;
+                writer = open_jsonl(&config.db)?;
+            }
+            redactions.insert(target, hash);
+        }
     }
 
     Ok(())
 }
 
 fn run_local_sqlite(config: &Config, in_events: impl Iterator<Item = Result<Event>>) -> Result<()> {
-    let mut labels = HashMap::new();
+    let mut validator = Validator::for_sink(SignatureChecker::new(config));
     let conn = sqlite::open(&PathBuf::from(&config.db))?;
+    for (redaction_hash, event_hash) in sqlite::redactions(&conn)? {
+        validator.add_redaction(&redaction_hash, &event_hash);
+    }
 
     for result in in_events {
-        let event = prep_event(&mut labels, result)?;
+        let mut event = prep_event(config, &mut validator, result)?;
+        let hash = event.hash.clone().expect("Hash not set");
+        if let Some(redaction_hash) = sqlite::redaction_for(&conn, &hash)? {
+            event = event::tombstone(&event, &redaction_hash);
+        }
+        let target = redaction_target(&event)?;
 
         if event.r#type != "control" || config.sink_control_events {
-            info! {"Writing event to sink: {} {}", event.r#type, event.hash.to_owned().expect("hash")};
+            info! {"Writing event to sink: {} {}", event.r#type, hash};
             sqlite::insert_event(&conn, event)?;
         }
+
+        if let Some(target) = target {
+            info! {"Redacting event in sink: {}", target};
+            sqlite::redact_event(&conn, &target, &hash)?;
+        }
     }
 
     sqlite::close(conn)
@@ -227,9 +248,10 @@ fn run_local_sqlite(config: &Config, in_events: impl Iterator<Item = Result<Even
 pub fn run_with_events(
     config: &Config,
     in_events: impl Iterator<Item = Result<Event>>,
+    token: Option<&str>,
 ) -> Result<()> {
     if embedded::is_remote_target(&config.db) {
-        run_remote(config, in_events)
+        run_remote(config, in_events, token)
     } else if common::has_sqlite_ext(&config.db) {
         run_local_sqlite(config, in_events)
     } else {
@@ -246,5 +268,12 @@ pub fn run() -> Result<()> {
         Error::msg("Missing value for SR_INPUT")
     })?;
     let in_events = embedded::input_events(&input_addr)?;
-    run_with_events(&config, in_events)
+    let token = env::var("SRVC_TOKEN").ok();
+    run_with_events(&config, in_events, token.as_deref())
+}
+
+pub fn run_in_process(context: StepContext) -> Result<()> {
+    let token = context.var("SRVC_TOKEN").map(String::from);
+    let in_events = embedded::channel_events(context.input);
+    run_with_events(&context.config, in_events, token.as_deref())
 }
@@ -6,41 +6,105 @@ use anyhow::{Context, Error, Result};
 use reqwest::blocking::Client;
 
 use lib_sr::event;
-use lib_sr::event::Event;
+use lib_sr::event::{Event, RevisionOfData, Typed, TypedEvent};
+use lib_sr::in_process::StepContext;
+use lib_sr::Config;
 
 use crate::embedded;
 use crate::embedded::MapContext;
 
+/// The document that a link carries reviews from, and the document
+/// that it carries them to. Duplicates and revisions that carry
+/// answers count as reviewed if the document they link to was.
+fn carried_link(event: &TypedEvent) -> Option<(&str, &str)> {
+    match event {
+        TypedEvent::DuplicateOf(link) => Some((&link.data.duplicate_of, &link.data.event)),
+        TypedEvent::RevisionOf(link) if link.data.carry_answers == Some(true) => {
+            Some((&link.data.revision_of, &link.data.event))
+        }
+        _ => None,
+    }
+}
+
+/// True if a revision carries an answer that the reviewer gave to an
+/// earlier version. In the latest revisions mode, the generator leaves
+/// earlier versions out and these answers are only found in the links.
+fn carries_answer_from(link: &Typed<RevisionOfData>, reviewer: &str) -> bool {
+    link.data.carry_answers == Some(true)
+        && link.data.answers.iter().any(|answer| {
+            answer
+                .data
+                .as_ref()
+                .and_then(|data| data.get("reviewer"))
+                .and_then(|v| v.as_str())
+                == Some(reviewer)
+        })
+}
+
+/// Read the hashes of the documents that a reviewer has reviewed, by
+/// the event that each of the reviewer's label-answers refers to. A
+/// document that is linked as a duplicate to a reviewed document
+/// counts as reviewed, and so does the document that it duplicates.
+/// A revision that carries answers counts as reviewed if the version
+/// before it was.
 pub fn read_reviewed_docs(file: File, reviewer: &str) -> Result<HashSet<String>> {
     let reader = BufReader::new(file);
     let events = event::events(reader);
     let mut hashes = HashSet::new();
+    let mut links = Vec::new();
+    let mut revisions = Vec::new();
 
     for result in events {
-        let event = result?;
-        if event.r#type == "label-answer" {
-            let data = event.data.unwrap_or(serde_json::Value::Null);
-            let document = data["document"].as_str();
-            let rvwr = data["reviewer"].as_str();
-            if document.and(rvwr).is_some()
-                && rvwr.expect("label-answer must have a reviewer") == reviewer
-            {
-                hashes.insert(document.unwrap().to_string());
+        match TypedEvent::from_event_or_other(result?) {
+            TypedEvent::DuplicateOf(link) => {
+                links.push((link.data.event, link.data.duplicate_of));
+            }
+            TypedEvent::LabelAnswer(answer) => {
+                if answer.data.reviewer == reviewer {
+                    hashes.insert(answer.data.event);
+                }
+            }
+            TypedEvent::RevisionOf(link) if link.data.carry_answers == Some(true) => {
+                if carries_answer_from(&link, reviewer) {
+                    hashes.insert(link.data.event.clone());
+                }
+                revisions.push((link.data.event, link.data.revision_of));
+            }
+            _ => {}
+        }
+    }
+
+    // Revisions form chains, so follow them until nothing changes
+    loop {
+        let count = hashes.len();
+        for (revision, previous) in &revisions {
+            if hashes.contains(previous) {
+                hashes.insert(revision.to_owned());
             }
         }
+        if hashes.len() == count {
+            break;
+        }
     }
 
+    // Duplicates always link to the kept document, so one pass
+    // covers every document with the same identity
+    let mut linked = Vec::new();
+    for (duplicate, kept) in links {
+        if hashes.contains(&kept) {
+            linked.push(duplicate);
+        } else if hashes.contains(&duplicate) {
+            linked.push(kept);
+        }
+    }
+    hashes.extend(linked);
+
     Ok(hashes)
 }
 
-pub fn remote_reviewed(
-    client: &Client,
-    remote: &str,
-    event: &Event,
-    reviewer: &str,
-) -> Result<bool> {
+pub fn remote_reviewed(client: &Client, remote: &str, hash: &str, reviewer: &str) -> Result<bool> {
     let mut path = String::from("document/");
-    path.push_str(event.hash.as_ref().expect("Event must have hash"));
+    path.push_str(hash);
     path.push_str("/label-answers");
     let url = embedded::api_route(remote, &path);
     let response = client
@@ -53,21 +117,12 @@ pub fn remote_reviewed(
             .text()
             .with_context(|| "Error getting response text")?;
         for line in text.lines() {
-            let answer: Event =
-                serde_json::from_str(line).with_context(|| "Error deserializing label-answer")?;
-            match answer
-                .data
-                .expect("label-answer must have data")
-                .get("reviewer")
-                .unwrap_or(&serde_json::Value::Null)
-                .as_str()
-            {
-                Some(answer_reviewer) => {
-                    if reviewer == answer_reviewer {
-                        return Ok(true);
-                    }
+            let answer =
+                event::parse_event(line).with_context(|| "Error deserializing label-answer")?;
+            if let TypedEvent::LabelAnswer(answer) = TypedEvent::from_event_or_other(answer) {
+                if answer.data.reviewer == reviewer {
+                    return Ok(true);
                 }
-                None => {}
             }
         }
         Ok(false)
@@ -84,40 +139,88 @@ pub fn remote_reviewed(
     }
 }
 
-pub fn run() -> Result<()> {
-    let MapContext {
-        config,
-        in_events,
-        timestamp_override: _,
-        mut writer,
-    } = embedded::get_map_context()?;
+/// Pass through the events for documents that the reviewer has not
+/// reviewed
+pub fn run_with<F>(
+    config: &Config,
+    in_events: impl Iterator<Item = Result<Event>>,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(Event) -> Result<()>,
+{
     let reviewer = config
         .reviewer
+        .as_ref()
         .ok_or(Error::msg("\"reviewer\" not set in config"))?;
-    let mut hashes = HashSet::new();
+    let mut reviewed = HashSet::new();
     let is_remote = embedded::is_remote_target(&config.db);
     let client = Client::new();
 
     if !is_remote {
         let db_file = File::open(&config.db);
-        hashes = match db_file {
-            Err(_) => hashes, // The file may not exist yet
-            Ok(file) => read_reviewed_docs(file, &reviewer)?,
+        reviewed = match db_file {
+            Err(_) => reviewed, // The file may not exist yet
+            Ok(file) => read_reviewed_docs(file, reviewer)?,
         };
     }
+    // Reviewed documents and the events that have been emitted
+    let mut hashes = reviewed.clone();
 
     for result in in_events {
         let event = result?;
         let hash = event.hash.clone().unwrap_or("".to_string());
+        // duplicate-of and revision-of events come before the document
+        // that they link. Both are skipped if the linked document was
+        // reviewed.
+        if event.r#type == "duplicate-of" || event.r#type == "revision-of" {
+            let typed = TypedEvent::from_event_or_other(event.clone());
+            if let Some((from, to)) = carried_link(&typed) {
+                let carries_answer = match &typed {
+                    TypedEvent::RevisionOf(link) => carries_answer_from(link, reviewer),
+                    _ => false,
+                };
+                if reviewed.contains(from)
+                    || carries_answer
+                    || is_remote && remote_reviewed(&client, &config.db, from, reviewer)?
+                {
+                    // Later links in a chain of revisions carry this
+                    // review further
+                    reviewed.insert(to.to_owned());
+                    hashes.insert(to.to_owned());
+                    continue;
+                }
+            }
+        }
         if is_remote
             && !hashes.contains(&hash)
-            && remote_reviewed(&client, &config.db, &event, &reviewer)?
+            && remote_reviewed(&client, &config.db, &hash, reviewer)?
         {
             hashes.insert(hash.clone());
         } else {
-            embedded::write_event_dedupe(&mut writer, &event, &mut hashes)?;
+            embedded::emit_dedupe(f, event, &mut hashes)?;
         }
     }
 
     Ok(())
 }
+
+pub fn run() -> Result<()> {
+    let MapContext {
+        config,
+        in_events,
+        timestamp_override: _,
+        mut writer,
+    } = embedded::get_map_context()?;
+    let mut f = |event| embedded::write_event(&mut writer, &event);
+    run_with(&config, in_events, &mut f)
+}
+
+pub fn run_in_process(context: StepContext) -> Result<()> {
+    let output = context
+        .output
+        .ok_or(Error::msg("Missing output for skip-reviewed step"))?;
+    let mut f = |event| embedded::send_event(&output, event);
+    let in_events = embedded::channel_events(context.input);
+    run_with(&context.config, in_events, &mut f)
+}
@@ -1,28 +1,19 @@
 use std::io;
 use std::io::{BufReader, BufWriter, Write};
 
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Result};
 use serde::Serialize;
 
-use lib_sr::event;
+use lib_sr::event::{self, HashAlgorithm};
 
-pub fn run() -> Result<()> {
+pub fn run(algorithm: HashAlgorithm) -> Result<()> {
     let reader = BufReader::new(io::stdin());
     let in_events = event::events(reader);
     let mut writer = BufWriter::new(io::stdout());
 
     for result in in_events {
         let mut event = result.with_context(|| "Cannot parse line as JSON")?;
-        let expected_hash = lib_sr::event::event_hash(event.clone())?;
-        let hash = event.hash.clone().unwrap_or("".to_string());
-        if hash == "" {
-            event.hash = Some(expected_hash);
-        } else if expected_hash != hash {
-            return Err(Error::msg(format!(
-                "Incorrect event hash. Expected: \"{}\". Found: \"{}\".",
-                expected_hash, hash
-            )));
-        }
+        event::ensure_hash_with(&mut event, algorithm)?;
         event
             .serialize(&mut serde_json::Serializer::new(&mut writer))
             .with_context(|| "Event serialization failed")?;
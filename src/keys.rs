@@ -0,0 +1,45 @@
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Error, Result};
+
+use lib_sr::signature;
+
+fn key_file(file: Option<PathBuf>) -> Result<PathBuf> {
+    file.or_else(signature::key_file).ok_or_else(|| {
+        Error::msg(format!(
+            "No signing key file. Set {} or use --file.",
+            signature::KEY_FILE_VAR
+        ))
+    })
+}
+
+fn print_public_key(key: &signature::SigningKey) -> Result<()> {
+    writeln!(
+        io::stdout(),
+        "{}",
+        signature::public_key_string(&key.verifying_key())
+    )
+    .with_context(|| "Failed to write to stdout")
+}
+
+/// Generate a signing key and print its public key
+pub fn run_generate(file: Option<PathBuf>) -> Result<()> {
+    let path = key_file(file)?;
+    if path.exists() {
+        return Err(Error::msg(format!(
+            "A signing key already exists at {:?}",
+            path
+        )));
+    }
+    let key = signature::generate_key(&path)?;
+    print_public_key(&key)
+}
+
+/// Print the public key of the signing key
+pub fn run_public(file: Option<PathBuf>) -> Result<()> {
+    let path = key_file(file)?;
+    let key = signature::read_key(&path)?;
+    print_public_key(&key)
+}
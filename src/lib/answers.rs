@@ -0,0 +1,106 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::event::{LabelAnswerData, Typed, TypedEvent};
+
+/// The label-answers for an event that are in effect, at most one per
+/// label and reviewer. events may hold any events, and must include
+/// the retractions of the event's label-answers.
+///
+/// An answer is not in effect if a later answer supersedes it or if
+/// its reviewer retracted it. If more than one answer remains for a
+/// label and reviewer, the one with the latest timestamp is in effect.
+pub fn current_answers<'a>(
+    event_hash: &str,
+    events: &'a [TypedEvent],
+) -> Vec<&'a Typed<LabelAnswerData>> {
+    let mut answers = Vec::new();
+    let mut retracted = HashSet::new();
+    let mut superseded = HashSet::new();
+    for event in events {
+        match event {
+            TypedEvent::LabelAnswer(answer) if answer.data.event == event_hash => {
+                if let Some(hash) = answer.data.supersedes() {
+                    superseded.insert((hash, &answer.data.label, &answer.data.reviewer));
+                }
+                answers.push(answer);
+            }
+            TypedEvent::LabelAnswerRetraction(retraction) => {
+                retracted.insert((&retraction.data.event, &retraction.data.reviewer));
+            }
+            _ => {}
+        }
+    }
+
+    let mut current: BTreeMap<(&String, &String), &Typed<LabelAnswerData>> = BTreeMap::new();
+    for answer in answers {
+        let hash = match &answer.hash {
+            Some(hash) => hash,
+            None => continue,
+        };
+        let data = &answer.data;
+        if retracted.contains(&(hash, &data.reviewer))
+            || superseded.contains(&(hash.as_str(), &data.label, &data.reviewer))
+        {
+            continue;
+        }
+        let key = (&data.label, &data.reviewer);
+        let is_latest = match current.get(&key) {
+            Some(other) => (other.data.timestamp, &other.hash) < (data.timestamp, &answer.hash),
+            None => true,
+        };
+        if is_latest {
+            current.insert(key, answer);
+        }
+    }
+    current.into_values().collect()
+}
+
+/// The hashes of the label-answers for an event in the order that they
+/// should be emitted. Each answer comes after the answer that it
+/// supersedes, and the answers in effect come last. Answers otherwise
+/// keep their order in events.
+pub fn answer_order<'a>(event_hash: &str, events: &'a [TypedEvent]) -> Vec<&'a str> {
+    let current: HashSet<&str> = current_answers(event_hash, events)
+        .into_iter()
+        .filter_map(|answer| answer.hash.as_deref())
+        .collect();
+    let mut answers: Vec<&Typed<LabelAnswerData>> = events
+        .iter()
+        .filter_map(|event| match event {
+            TypedEvent::LabelAnswer(answer)
+                if answer.data.event == event_hash && answer.hash.is_some() =>
+            {
+                Some(answer)
+            }
+            _ => None,
+        })
+        .collect();
+    answers.sort_by_key(|answer| current.contains(answer.hash.as_deref().expect("hash")));
+
+    let by_hash: HashMap<&str, &Typed<LabelAnswerData>> = answers
+        .iter()
+        .map(|answer| (answer.hash.as_deref().expect("hash"), *answer))
+        .collect();
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    for answer in &answers {
+        push_answer(answer, &by_hash, &mut seen, &mut order);
+    }
+    order
+}
+
+fn push_answer<'a>(
+    answer: &'a Typed<LabelAnswerData>,
+    by_hash: &HashMap<&str, &'a Typed<LabelAnswerData>>,
+    seen: &mut HashSet<&'a str>,
+    order: &mut Vec<&'a str>,
+) {
+    let hash = answer.hash.as_deref().expect("hash");
+    if !seen.insert(hash) {
+        return;
+    }
+    if let Some(superseded) = answer.data.supersedes().and_then(|h| by_hash.get(h)) {
+        push_answer(superseded, by_hash, seen, order);
+    }
+    order.push(hash);
+}
@@ -0,0 +1,301 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use multihash::MultihashDigest;
+use reqwest::blocking::Client;
+use url::Url;
+
+use crate::event::{self, Event, HashAlgorithm};
+
+/// Where blobs are stored, relative to the project directory
+pub const BLOB_DIR: &str = ".srvc/blobs";
+
+/// The hash of a blob's bytes. Blob hashes use the same multihash
+/// and base58 encoding as event hashes.
+pub fn hash_bytes(bytes: &[u8], algorithm: HashAlgorithm) -> String {
+    bs58::encode(algorithm.code().digest(bytes).to_bytes()).into_string()
+}
+
+/// Check that a hash is a multihash with a supported algorithm, so
+/// that it is safe to use as a file name
+fn check_hash(hash: &str) -> Result<HashAlgorithm> {
+    let mh = event::decode_hash(hash)
+        .ok_or_else(|| Error::msg(format!("Invalid blob hash: \"{}\"", hash)))?;
+    HashAlgorithm::from_code(mh.code()).ok_or_else(|| {
+        Error::msg(format!(
+            "Unsupported hash algorithm (multihash code 0x{:x}) in blob hash \"{}\"",
+            mh.code(),
+            hash
+        ))
+    })
+}
+
+/// The hashes of the blobs that an event references in its blobs
+/// property
+pub fn blob_hashes(event: &Event) -> Vec<&str> {
+    match event.extra.get("blobs").and_then(|blobs| blobs.as_array()) {
+        Some(blobs) => blobs
+            .iter()
+            .filter_map(|blob| blob.get("hash").and_then(|hash| hash.as_str()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The media-type of a blob that an event references, if it has one
+pub fn media_type<'a>(event: &'a Event, hash: &str) -> Option<&'a str> {
+    event
+        .extra
+        .get("blobs")?
+        .as_array()?
+        .iter()
+        .find(|blob| blob.get("hash").and_then(|h| h.as_str()) == Some(hash))?
+        .get("media-type")?
+        .as_str()
+}
+
+/// A content-addressed store of files, such as the PDFs or full texts
+/// of documents. Each blob is a file named by the hash of its bytes.
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf) -> BlobStore {
+        BlobStore { dir }
+    }
+
+    /// The blob store of the project that a db or event file is in
+    pub fn for_file(path: &Path) -> BlobStore {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        BlobStore::new(dir.join(BLOB_DIR))
+    }
+
+    pub fn path(&self, hash: &str) -> Result<PathBuf> {
+        check_hash(hash)?;
+        Ok(self.dir.join(hash))
+    }
+
+    pub fn contains(&self, hash: &str) -> Result<bool> {
+        Ok(self.path(hash)?.exists())
+    }
+
+    /// Add a blob and return its hash
+    pub fn add(&self, bytes: &[u8], algorithm: HashAlgorithm) -> Result<String> {
+        let hash = hash_bytes(bytes, algorithm);
+        let path = self.dir.join(&hash);
+        if path.exists() {
+            return Ok(hash);
+        }
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create blob directory: {:?}", self.dir))?;
+        // Write to a temporary file first so that a partial blob is
+        // never read
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)
+            .with_context(|| "Failed to create blob file")?;
+        file.write_all(bytes)
+            .with_context(|| "Failed to write blob file")?;
+        file.persist(&path)
+            .with_context(|| format!("Failed to write blob file: {:?}", path))?;
+        Ok(hash)
+    }
+
+    /// Add a blob that should have the given hash
+    pub fn add_expected(&self, hash: &str, bytes: &[u8]) -> Result<()> {
+        let algorithm = check_hash(hash)?;
+        let actual = hash_bytes(bytes, algorithm);
+        if actual != hash {
+            return Err(Error::msg(format!(
+                "Incorrect blob hash. Expected: \"{}\". Found: \"{}\".",
+                actual, hash
+            )));
+        }
+        self.add(bytes, algorithm)?;
+        Ok(())
+    }
+
+    pub fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(hash)?;
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read blob: {:?}", path)),
+        }
+    }
+
+    /// The hashes of all blobs in the store
+    pub fn hashes(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read blob directory: {:?}", self.dir))
+            }
+        };
+        let mut hashes = Vec::new();
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("Failed to read blob directory: {:?}", self.dir))?;
+            if let Some(name) = entry.file_name().to_str() {
+                // Skip temporary files
+                if !name.starts_with('.') {
+                    hashes.push(name.to_owned());
+                }
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    /// Check that a blob exists and that its bytes match its hash
+    pub fn verify(&self, hash: &str) -> Result<()> {
+        let algorithm = check_hash(hash)?;
+        let bytes = self
+            .get(hash)?
+            .ok_or_else(|| Error::msg(format!("Blob not found: {}", hash)))?;
+        let actual = hash_bytes(&bytes, algorithm);
+        if actual != hash {
+            return Err(Error::msg(format!(
+                "Blob {} is corrupt. Its contents have the hash {}.",
+                hash, actual
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Where the blobs that a file or URL of events references are read
+/// from. This is the blob store of the project that holds the file,
+/// or .srvc/blobs relative to the URL.
+pub enum BlobSource {
+    Store(BlobStore),
+    Url(Url),
+}
+
+impl BlobSource {
+    pub fn new(file_or_url: &str) -> Option<BlobSource> {
+        match Url::parse(file_or_url) {
+            Ok(url) => Some(BlobSource::Url(url)),
+            Err(_) if file_or_url == "-" => None,
+            Err(_) => Some(BlobSource::Store(BlobStore::for_file(Path::new(
+                file_or_url,
+            )))),
+        }
+    }
+
+    pub fn get(&self, client: &Client, hash: &str) -> Result<Vec<u8>> {
+        check_hash(hash)?;
+        match self {
+            BlobSource::Store(store) => store
+                .get(hash)?
+                .ok_or_else(|| Error::msg(format!("Blob not found: {}", hash))),
+            BlobSource::Url(url) => {
+                let url = url
+                    .join(&format!("{}/{}", BLOB_DIR, hash))
+                    .with_context(|| format!("Invalid blob URL for {}", hash))?;
+                let mut request = client.get(url.clone());
+                if let Ok(token) = env::var("SRVC_TOKEN") {
+                    request = request.header("Authorization", format!("Bearer {}", token));
+                }
+                let response = request
+                    .send()
+                    .with_context(|| format!("Failed to complete HTTP request to {}", url))?;
+                let status = response.status().as_u16();
+                if status != 200 {
+                    return Err(Error::msg(format!(
+                        "Unexpected {} status for {}",
+                        status, url
+                    )));
+                }
+                let bytes = response
+                    .bytes()
+                    .with_context(|| format!("Failed to read blob from {}", url))?;
+                Ok(bytes.to_vec())
+            }
+        }
+    }
+}
+
+/// Copy the blobs that an event references from source to target,
+/// skipping blobs that target already has
+pub fn transfer(
+    client: &Client,
+    source: &BlobSource,
+    target: &BlobStore,
+    event: &Event,
+) -> Result<()> {
+    for hash in blob_hashes(event) {
+        if target.contains(hash)? {
+            continue;
+        }
+        let bytes = source.get(client, hash).with_context(|| {
+            format!(
+                "Failed to get blob {} for event {}",
+                hash,
+                event.hash.as_deref().unwrap_or_default()
+            )
+        })?;
+        target.add_expected(hash, &bytes)?;
+    }
+    Ok(())
+}
+
+/// Add files to the blob store and print their hashes
+pub fn run_add(files: Vec<PathBuf>, algorithm: HashAlgorithm) -> Result<()> {
+    let store = BlobStore::new(PathBuf::from(BLOB_DIR));
+    for file in files {
+        let bytes = fs::read(&file).with_context(|| format!("Failed to read file: {:?}", file))?;
+        let hash = store.add(&bytes, algorithm)?;
+        writeln!(io::stdout(), "{}", hash).with_context(|| "Failed to write to stdout")?;
+    }
+    Ok(())
+}
+
+/// Write a blob to a file, or to stdout if out is None
+pub fn run_get(hash: &str, out: Option<PathBuf>) -> Result<()> {
+    let store = BlobStore::new(PathBuf::from(BLOB_DIR));
+    let bytes = store
+        .get(hash)?
+        .ok_or_else(|| Error::msg(format!("Blob not found: {}", hash)))?;
+    match out {
+        Some(path) => {
+            fs::write(&path, bytes).with_context(|| format!("Failed to write file: {:?}", path))
+        }
+        None => io::stdout()
+            .write_all(&bytes)
+            .with_context(|| "Failed to write to stdout"),
+    }
+}
+
+/// Check blobs against their hashes. Checks every blob in the store
+/// if hashes is empty.
+pub fn run_verify(hashes: Vec<String>) -> Result<()> {
+    let store = BlobStore::new(PathBuf::from(BLOB_DIR));
+    let hashes = if hashes.is_empty() {
+        store.hashes()?
+    } else {
+        hashes
+    };
+    let mut failures = 0;
+    for hash in &hashes {
+        if let Err(e) = store.verify(hash) {
+            writeln!(io::stderr(), "{:#}", e).with_context(|| "Failed to write to stderr")?;
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        return Err(Error::msg(format!(
+            "{} of {} blobs failed verification",
+            failures,
+            hashes.len()
+        )));
+    }
+    writeln!(io::stdout(), "Verified {} blobs", hashes.len())
+        .with_context(|| "Failed to write to stdout")?;
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+
+use crate::event::{self, Event};
+use crate::{common, sqlite};
+
+/// Whether checkpoints can be kept for a db. Remote dbs and stdout
+/// have no place for a sidecar file.
+pub fn is_local_db(db: &str) -> bool {
+    let target = db.to_lowercase();
+    db != "-" && !target.starts_with("http://") && !target.starts_with("https://")
+}
+
+/// The path of the checkpoint file for a db
+pub fn path(db: &str) -> PathBuf {
+    PathBuf::from(format!("{}.checkpoint", db))
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StepCheckpoint {
+    pub index: usize,
+    // The last document that the step finished
+    #[serde(rename = "last-document")]
+    pub last_document: Option<String>,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FlowCheckpoint {
+    pub steps: Vec<StepCheckpoint>,
+}
+
+impl FlowCheckpoint {
+    /// The last document that went through every step before the sink
+    pub fn last_document(&self) -> Option<&str> {
+        self.steps
+            .last()
+            .and_then(|step| step.last_document.as_deref())
+    }
+}
+
+/// The progress of each flow that has run against a db
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Checkpoints {
+    pub flows: BTreeMap<String, FlowCheckpoint>,
+}
+
+impl Checkpoints {
+    /// Read a checkpoint file. Returns empty checkpoints if the file
+    /// does not exist.
+    pub fn read(path: &Path) -> Result<Checkpoints> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(Checkpoints::default()),
+        };
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse checkpoint file: {:?}", path))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // Write to a temporary file first so that a flow that is
+        // killed never leaves a partial checkpoint
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| "Failed to create checkpoint file")?;
+        {
+            let mut writer = BufWriter::new(&mut file);
+            serde_json::to_writer(&mut writer, self)
+                .with_context(|| "Failed to serialize checkpoint")?;
+            writer
+                .write_all(b"\n")
+                .with_context(|| "Failed to write checkpoint file")?;
+            writer
+                .flush()
+                .with_context(|| "Failed to write checkpoint file")?;
+        }
+        file.persist(path)
+            .with_context(|| format!("Failed to write checkpoint file: {:?}", path))?;
+        Ok(())
+    }
+}
+
+/// Return the hashes of the documents in a local db
+pub fn document_hashes(db: &str) -> Result<HashSet<String>> {
+    let mut hashes = HashSet::new();
+    let path = PathBuf::from(db);
+    if !path.exists() {
+        return Ok(hashes);
+    }
+    if common::has_sqlite_ext(db) {
+        let conn = sqlite::open_ro(&path)?;
+        {
+            let mut stmt = sqlite::prepare_cached(
+                &conn,
+                "SELECT hash FROM srvc_event WHERE type = 'document'",
+            )?;
+            let rows = stmt
+                .query_map([], |row| row.get(0))
+                .with_context(|| "Failed to execute query")?;
+            for row in rows {
+                hashes.insert(row.with_context(|| "Failed to retrieve rows")?);
+            }
+        }
+        sqlite::close(conn)?;
+    } else {
+        let file = File::open(&path).with_context(|| format!("Failed to open db: \"{}\"", db))?;
+        for result in event::events(BufReader::new(file)) {
+            let event = result.with_context(|| format!("Failed to read db: \"{}\"", db))?;
+            if event.r#type == "document" {
+                hashes.insert(event.hash.ok_or(Error::msg("No hash for event"))?);
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Skips the documents that already went through a flow, along with
+/// the events that refer to them.
+///
+/// A document is skipped if it is in the db and the generator has not
+/// yet passed the last document recorded in the checkpoint. Documents
+/// after that point are never skipped, since the interrupted run did
+/// not finish them.
+#[derive(Clone, Debug)]
+pub struct Resume {
+    done: HashSet<String>,
+    last_document: Option<String>,
+    skipped: HashSet<String>,
+}
+
+impl Resume {
+    pub fn new(checkpoint: &FlowCheckpoint, done: HashSet<String>) -> Resume {
+        Resume {
+            done,
+            last_document: checkpoint.last_document().map(String::from),
+            skipped: HashSet::new(),
+        }
+    }
+
+    /// Returns true if the event should not be passed on
+    pub fn skip(&mut self, event: &Event) -> bool {
+        let hash = event.hash.as_deref().expect("hash");
+        if event.r#type == "document" {
+            let last_document = match &self.last_document {
+                Some(s) => s,
+                None => return false,
+            };
+            let skip = self.done.contains(hash);
+            if skip {
+                self.skipped.insert(hash.to_owned());
+            }
+            if last_document == hash {
+                self.last_document = None;
+            }
+            skip
+        } else if event.r#type == "control" {
+            false
+        } else {
+            event
+                .data
+                .as_ref()
+                .and_then(|data| data.get("event"))
+                .and_then(|v| v.as_str())
+                .map(|doc_hash| self.skipped.contains(doc_hash))
+                .unwrap_or(false)
+        }
+    }
+}
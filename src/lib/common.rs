@@ -1,15 +1,19 @@
 use std::{
     env,
     fs::File,
-    io::{self, BufRead, BufReader},
+    io::{self, BufRead, BufReader, Cursor, Read},
     path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Context, Error, Result};
+use flate2::read::MultiGzDecoder;
 use reqwest::blocking::Client;
 use url::Url;
 
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
 pub fn get_epoch_sec() -> Result<u64> {
     Ok(SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -17,6 +21,14 @@ pub fn get_epoch_sec() -> Result<u64> {
         .as_secs())
 }
 
+pub fn get_epoch_ms() -> Result<u64> {
+    let ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Failed to calculate timestamp")?
+        .as_millis();
+    u64::try_from(ms).context("Failed to calculate timestamp")
+}
+
 pub fn get_timestamp_override() -> Result<Option<u64>> {
     Ok(match env::var("SR_TIMESTAMP_OVERRIDE") {
         Ok(s) => Some(
@@ -27,6 +39,27 @@ pub fn get_timestamp_override() -> Result<Option<u64>> {
     })
 }
 
+/// Parse a duration such as "500ms", "2s", "5m", or "1h".
+/// A bare number is interpreted as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n = n
+        .parse::<u64>()
+        .with_context(|| format!("Invalid duration: {:?}", s))?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 60 * 60)),
+        _ => Err(Error::msg(format!(
+            "Invalid duration: {:?}. Expected a unit of ms, s, m, or h",
+            s
+        ))),
+    }
+}
+
 pub fn has_sqlite_ext(filename: &str) -> bool {
     let name = filename.to_lowercase();
     if name.ends_with(".db") || name.ends_with(".sqlite") {
@@ -40,6 +73,34 @@ pub fn open_browser(url: &str) -> Result<()> {
     webbrowser::open(url).with_context(|| format!("Failed to open browser for URL: {}", url))
 }
 
+/// Decompress a gzip or zstd stream. Compressed streams are
+/// recognized by their first bytes, and other streams are returned
+/// unchanged.
+pub fn decompress(
+    mut reader: Box<dyn BufRead + Send + Sync>,
+) -> Result<Box<dyn BufRead + Send + Sync>> {
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    reader
+        .by_ref()
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .with_context(|| "Failed to read stream")?;
+    let is_gzip = magic.starts_with(GZIP_MAGIC);
+    let is_zstd = magic.starts_with(ZSTD_MAGIC);
+    let reader = Cursor::new(magic).chain(reader);
+    if is_gzip {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else if is_zstd {
+        let decoder =
+            zstd::Decoder::with_buffer(reader).with_context(|| "Failed to start zstd decoder")?;
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Open a file, URL, or stdin if file_or_url is "-". gzip and zstd
+/// data is decompressed.
 pub fn get_file_or_url(
     client: &Client,
     file_or_url: &str,
@@ -57,7 +118,9 @@ pub fn get_file_or_url(
                 .with_context(|| format!("Failed to complete HTTP request to {}", url))?;
             let status = response.status().as_u16();
             if status == 200 {
-                Ok((Box::new(BufReader::new(response)), None, Some(url)))
+                let reader = decompress(Box::new(BufReader::new(response)))
+                    .with_context(|| format!("Failed to read {}", url))?;
+                Ok((reader, None, Some(url)))
             } else {
                 Err(Error::msg(format!(
                     "Unexpected {} status for {}",
@@ -67,13 +130,16 @@ pub fn get_file_or_url(
         }
         Err(_) => {
             if file_or_url == "-" {
-                Ok((Box::new(BufReader::new(io::stdin())), None, None))
+                let reader = decompress(Box::new(BufReader::new(io::stdin())))
+                    .with_context(|| "Failed to read stdin")?;
+                Ok((reader, None, None))
             } else {
                 let path = PathBuf::from(file_or_url);
                 let file = File::open(&path)
                     .with_context(|| format!("Failed to open file {}", file_or_url))?;
-                let reader = BufReader::new(file);
-                Ok((Box::new(reader), Some(path), None))
+                let reader = decompress(Box::new(BufReader::new(file)))
+                    .with_context(|| format!("Failed to read file {}", file_or_url))?;
+                Ok((reader, Some(path), None))
             }
         }
     }
@@ -90,3 +156,19 @@ pub fn get_file_or_url_string(
         .with_context(|| format!("Buffer read failed for file {}", file_or_url))?;
     Ok((s, pathbuf, url))
 }
+
+/// Format a number of bytes for display, e.g. "1.5 MiB"
+pub fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut n = n as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, UNITS[unit])
+    } else {
+        format!("{:.1} {}", n, UNITS[unit])
+    }
+}
@@ -1,14 +1,66 @@
 use std::collections::BTreeMap;
 use std::io::BufRead;
+use std::str::FromStr;
 
 use anyhow::{Context, Error, Result};
 use log::trace;
 use log::warn;
-use multihash::MultihashDigest;
+use multihash::{Multihash, MultihashDigest};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_with::skip_serializing_none;
 
+use crate::Label;
+
+/// The hash functions that event hashes can use. Hashes are
+/// multihashes, so events hashed with different algorithms can be
+/// mixed in the same stream or sink.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum HashAlgorithm {
+    #[serde(rename = "blake3")]
+    Blake3,
+    #[default]
+    #[serde(rename = "sha2-256")]
+    Sha2_256,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn code(self) -> multihash::Code {
+        match self {
+            HashAlgorithm::Blake3 => multihash::Code::Blake3_256,
+            HashAlgorithm::Sha2_256 => multihash::Code::Sha2_256,
+        }
+    }
+
+    pub(crate) fn from_code(code: u64) -> Option<HashAlgorithm> {
+        [HashAlgorithm::Blake3, HashAlgorithm::Sha2_256]
+            .into_iter()
+            .find(|algorithm| u64::from(algorithm.code()) == code)
+    }
+
+    /// The algorithm of an event hash, if it is a multihash with a
+    /// supported code
+    pub fn of_hash(hash: &str) -> Option<HashAlgorithm> {
+        decode_hash(hash).and_then(|mh| HashAlgorithm::from_code(mh.code()))
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha2-256" => Ok(HashAlgorithm::Sha2_256),
+            _ => Err(Error::msg(format!(
+                "Unknown hash algorithm \"{}\". Expected blake3 or sha2-256.",
+                s
+            ))),
+        }
+    }
+}
+
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Event {
@@ -21,7 +73,7 @@ pub struct Event {
 }
 
 #[skip_serializing_none]
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct LabelAnswerData {
     pub answer: serde_json::Value,
     #[serde(alias = "document")]
@@ -33,16 +85,371 @@ pub struct LabelAnswerData {
     pub timestamp: u64,
 }
 
-pub fn event_hash(mut event: Event) -> Result<String> {
-    event.hash = None;
-    let bytes = serde_ipld_dagcbor::to_vec(&event).with_context(|| "Failed to serialize event")?;
-    let hash = multihash::Code::Sha2_256.digest(&bytes);
-    let base58 = bs58::encode(hash.to_bytes());
-    Ok(base58.into_string())
+impl LabelAnswerData {
+    /// The hash of the earlier label-answer that this one replaces.
+    /// It must have the same event, label, and reviewer.
+    pub fn supersedes(&self) -> Option<&str> {
+        self.extra.get("supersedes").and_then(|v| v.as_str())
+    }
+}
+
+/// Links a document to an earlier document that has the same identity,
+/// such as the same paper from another source
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DuplicateOfData {
+    // The hash of the document that is kept
+    #[serde(alias = "duplicate_of", rename = "duplicate-of")]
+    pub duplicate_of: String,
+    // The hash of the duplicate document
+    pub event: String,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+    // The identity keys that matched
+    pub keys: Vec<String>,
+}
+
+/// Links a document to the previous version of the same record. Both
+/// versions have the same uri.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RevisionOfData {
+    // The label-answers in effect for the previous version
+    pub answers: Vec<Event>,
+    // True if the previous version's answers apply to this version
+    #[serde(alias = "carry_answers", rename = "carry-answers")]
+    pub carry_answers: Option<bool>,
+    // JSON pointers to the parts of data that changed
+    pub changed: Vec<String>,
+    // The hash of the newer document
+    pub event: String,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+    // The hash of the previous version
+    #[serde(alias = "revision_of", rename = "revision-of")]
+    pub revision_of: String,
+}
+
+/// Withdraws a label-answer without replacing it
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LabelAnswerRetractionData {
+    // The hash of the label-answer
+    pub event: String,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+    pub reviewer: String,
+    pub timestamp: u64,
+}
+
+/// Removes the content of an event. Sinks replace the event with a
+/// tombstone that keeps its hash, so events that refer to it stay
+/// valid.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RedactionData {
+    // The hash of the redacted event
+    pub event: String,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+    pub reason: Option<String>,
+    pub reviewer: String,
+    pub timestamp: u64,
+}
+
+/// The property of a tombstone that holds the hash of the redaction
+pub const REDACTED_PROPERTY: &str = "redacted";
+
+/// Whether an event is a tombstone left by a redaction. A tombstone
+/// keeps only the type and hash of the redacted event.
+pub fn is_tombstone(event: &Event) -> bool {
+    event.data.is_none()
+        && event.uri.is_none()
+        && event.extra.len() == 1
+        && event
+            .extra
+            .get(REDACTED_PROPERTY)
+            .is_some_and(|v| v.is_string())
+}
+
+/// The tombstone that replaces an event with a hash when it is
+/// redacted
+pub fn tombstone(event: &Event, redaction_hash: &str) -> Event {
+    Event {
+        data: None,
+        extra: BTreeMap::from([(
+            String::from(REDACTED_PROPERTY),
+            serde_json::Value::from(redaction_hash),
+        )]),
+        hash: event.hash.clone(),
+        r#type: event.r#type.clone(),
+        uri: None,
+    }
+}
+
+/// An event with its data parsed as T. The other fields are the same
+/// as in Event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Typed<T> {
+    pub data: T,
+    pub extra: BTreeMap<String, serde_json::Value>,
+    pub hash: Option<String>,
+    pub uri: Option<String>,
+}
+
+/// An event with data parsed according to its type. Converting an
+/// Event to a TypedEvent and back gives the same event and hash.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypedEvent {
+    Control(Typed<Option<serde_json::Value>>),
+    Document(Typed<Option<serde_json::Value>>),
+    DuplicateOf(Typed<DuplicateOfData>),
+    Label(Typed<Label>),
+    LabelAnswer(Typed<LabelAnswerData>),
+    LabelAnswerRetraction(Typed<LabelAnswerRetractionData>),
+    Redaction(Typed<RedactionData>),
+    RevisionOf(Typed<RevisionOfData>),
+    Other(Event),
+}
+
+impl TypedEvent {
+    /// Like TypedEvent::try_from, but an event whose data does not
+    /// parse as its type, such as a label written by an older
+    /// version, becomes TypedEvent::Other instead of an error. For
+    /// steps that pass events through rather than check them.
+    pub fn from_event_or_other(event: Event) -> TypedEvent {
+        to_typed(event).unwrap_or_else(|untyped| {
+            let (event, e) = *untyped;
+            trace!("Reading event as untyped: {:#}", e);
+            TypedEvent::Other(event)
+        })
+    }
+
+    pub fn hash(&self) -> Option<&str> {
+        match self {
+            TypedEvent::Control(e) | TypedEvent::Document(e) => e.hash.as_deref(),
+            TypedEvent::DuplicateOf(e) => e.hash.as_deref(),
+            TypedEvent::Label(e) => e.hash.as_deref(),
+            TypedEvent::LabelAnswer(e) => e.hash.as_deref(),
+            TypedEvent::LabelAnswerRetraction(e) => e.hash.as_deref(),
+            TypedEvent::Redaction(e) => e.hash.as_deref(),
+            TypedEvent::RevisionOf(e) => e.hash.as_deref(),
+            TypedEvent::Other(e) => e.hash.as_deref(),
+        }
+    }
+
+    pub fn type_name(&self) -> &str {
+        match self {
+            TypedEvent::Control(_) => "control",
+            TypedEvent::Document(_) => "document",
+            TypedEvent::DuplicateOf(_) => "duplicate-of",
+            TypedEvent::Label(_) => "label",
+            TypedEvent::LabelAnswer(_) => "label-answer",
+            TypedEvent::LabelAnswerRetraction(_) => "label-answer-retraction",
+            TypedEvent::Redaction(_) => "redaction",
+            TypedEvent::RevisionOf(_) => "revision-of",
+            TypedEvent::Other(e) => &e.r#type,
+        }
+    }
+}
+
+fn untyped<T>(event: Event, data: T) -> Typed<T> {
+    Typed {
+        data,
+        extra: event.extra,
+        hash: event.hash,
+        uri: event.uri,
+    }
+}
+
+/// Parse the data of an event as T. The data must serialize back to
+/// the same value, or the event hash would change when the event is
+/// converted back.
+fn parse_data<T>(event: &Event) -> Result<T>
+where
+    T: DeserializeOwned + Serialize,
+{
+    let hash = event.hash.as_deref().unwrap_or("none");
+    let data = event.data.as_ref().ok_or_else(|| {
+        Error::msg(format!(
+            "{} is missing the \"data\" property. Event hash: {}",
+            event.r#type, hash
+        ))
+    })?;
+    let parsed: T = serde_json::from_value(data.to_owned())
+        .with_context(|| format!("{} has invalid data. Event hash: {}", event.r#type, hash))?;
+    let canonical = serde_json::to_value(&parsed)
+        .with_context(|| format!("Failed to serialize {} data", event.r#type))?;
+    if &canonical != data {
+        return Err(Error::msg(format!(
+            "{} data is not in canonical form. Event hash: {}",
+            event.r#type, hash
+        )));
+    }
+    Ok(parsed)
+}
+
+/// Parse the data of an event into a TypedEvent variant. The event is
+/// returned with the error if its data does not parse.
+fn parse_typed<T, F>(
+    event: Event,
+    variant: F,
+) -> std::result::Result<TypedEvent, Box<(Event, Error)>>
+where
+    T: DeserializeOwned + Serialize,
+    F: FnOnce(Typed<T>) -> TypedEvent,
+{
+    match parse_data(&event) {
+        Ok(data) => Ok(variant(untyped(event, data))),
+        Err(e) => Err(Box::new((event, e))),
+    }
+}
+
+fn to_typed(mut event: Event) -> std::result::Result<TypedEvent, Box<(Event, Error)>> {
+    // Tombstones have no data to parse
+    if is_tombstone(&event) {
+        return Ok(TypedEvent::Other(event));
+    }
+    match event.r#type.as_str() {
+        "control" => {
+            let data = event.data.take();
+            Ok(TypedEvent::Control(untyped(event, data)))
+        }
+        "document" => {
+            let data = event.data.take();
+            Ok(TypedEvent::Document(untyped(event, data)))
+        }
+        "duplicate-of" => parse_typed(event, TypedEvent::DuplicateOf),
+        "label" => parse_typed(event, TypedEvent::Label),
+        "label-answer" => parse_typed(event, TypedEvent::LabelAnswer),
+        "label-answer-retraction" => parse_typed(event, TypedEvent::LabelAnswerRetraction),
+        "redaction" => parse_typed(event, TypedEvent::Redaction),
+        "revision-of" => parse_typed(event, TypedEvent::RevisionOf),
+        _ => Ok(TypedEvent::Other(event)),
+    }
+}
+
+impl TryFrom<Event> for TypedEvent {
+    type Error = Error;
+
+    fn try_from(event: Event) -> Result<TypedEvent> {
+        to_typed(event).map_err(|untyped| untyped.1)
+    }
+}
+
+fn typed_event<T: Serialize>(r#type: &str, typed: Typed<T>) -> Event {
+    Event {
+        // The data was parsed from JSON or built from types that
+        // serialize without errors
+        data: Some(serde_json::to_value(typed.data).expect("serializable data"))
+            .filter(|data| !data.is_null()),
+        extra: typed.extra,
+        hash: typed.hash,
+        r#type: r#type.to_owned(),
+        uri: typed.uri,
+    }
+}
+
+impl From<TypedEvent> for Event {
+    fn from(typed: TypedEvent) -> Event {
+        match typed {
+            TypedEvent::Control(e) => typed_event("control", e),
+            TypedEvent::Document(e) => typed_event("document", e),
+            TypedEvent::DuplicateOf(e) => typed_event("duplicate-of", e),
+            TypedEvent::Label(e) => typed_event("label", e),
+            TypedEvent::LabelAnswer(e) => typed_event("label-answer", e),
+            TypedEvent::LabelAnswerRetraction(e) => typed_event("label-answer-retraction", e),
+            TypedEvent::Redaction(e) => typed_event("redaction", e),
+            TypedEvent::RevisionOf(e) => typed_event("revision-of", e),
+            TypedEvent::Other(e) => e,
+        }
+    }
+}
+
+/// The fields of an event that its hash covers. Serializes the same
+/// way as an Event without a hash, but without cloning the event.
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct Hashed<'a> {
+    data: Option<&'a serde_json::Value>,
+    #[serde(flatten)]
+    extra: &'a BTreeMap<String, serde_json::Value>,
+    r#type: &'a str,
+    uri: Option<&'a str>,
+}
+
+fn hash_digest(event: &Event, algorithm: HashAlgorithm) -> Result<Multihash> {
+    let hashed = Hashed {
+        data: event.data.as_ref(),
+        extra: &event.extra,
+        r#type: &event.r#type,
+        uri: event.uri.as_deref(),
+    };
+    let bytes = serde_ipld_dagcbor::to_vec(&hashed).with_context(|| "Failed to serialize event")?;
+    Ok(algorithm.code().digest(&bytes))
+}
+
+pub(crate) fn decode_hash(hash: &str) -> Option<Multihash> {
+    let bytes = bs58::decode(hash).into_vec().ok()?;
+    Multihash::from_bytes(&bytes).ok()
+}
+
+/// Compute the hash of an event with the given algorithm. Any hash
+/// already set on the event is ignored.
+pub fn hash_event(event: &Event, algorithm: HashAlgorithm) -> Result<String> {
+    let hash = hash_digest(event, algorithm)?;
+    Ok(bs58::encode(hash.to_bytes()).into_string())
+}
+
+/// Compute the SHA-256 hash of an event
+pub fn event_hash(event: Event) -> Result<String> {
+    hash_event(&event, HashAlgorithm::Sha2_256)
+}
+
+fn hash_algorithm(mh: &Multihash, hash: &str) -> Result<HashAlgorithm> {
+    HashAlgorithm::from_code(mh.code()).ok_or_else(|| {
+        Error::msg(format!(
+            "Unsupported hash algorithm (multihash code 0x{:x}) in event hash \"{}\"",
+            mh.code(),
+            hash
+        ))
+    })
+}
+
+/// Check the hash of an event with the algorithm that the hash names
+pub fn verify_hash(event: &Event, hash: &str) -> Result<()> {
+    let expected = match decode_hash(hash) {
+        Some(mh) => {
+            let algorithm = hash_algorithm(&mh, hash)?;
+            // Compare digests to avoid encoding the expected hash
+            let digest = hash_digest(event, algorithm)?;
+            if digest == mh {
+                return Ok(());
+            }
+            digest
+        }
+        // Report the hash that was expected in the default algorithm
+        None => hash_digest(event, HashAlgorithm::default())?,
+    };
+    Err(Error::msg(format!(
+        "Incorrect event hash. Expected: \"{}\". Found: \"{}\".",
+        bs58::encode(expected.to_bytes()).into_string(),
+        hash
+    )))
+}
+
+/// Check the hash of a tombstone. The content that the hash covers is
+/// gone, so only the form of the hash can be checked here. Validator
+/// checks that a redaction removed the event.
+fn verify_tombstone_hash(hash: &str) -> Result<()> {
+    let mh = decode_hash(hash)
+        .ok_or_else(|| Error::msg(format!("Invalid hash in redacted event: \"{}\"", hash)))?;
+    hash_algorithm(&mh, hash)?;
+    Ok(())
 }
 
 pub fn process_event_data(mut event: Event) -> Result<Event> {
-    if event.r#type == "label-answer" {
+    if event.r#type == "label-answer" && !is_tombstone(&event) {
         // Check that label-answers have the required data
         match event.data {
             Some(data) => {
@@ -61,7 +468,8 @@ pub fn process_event_data(mut event: Event) -> Result<Event> {
                         old_hash
                     );
                     event.hash = None;
-                    ensure_hash(&mut event)?;
+                    let algorithm = HashAlgorithm::of_hash(&old_hash).unwrap_or_default();
+                    ensure_hash_with(&mut event, algorithm)?;
                     warn!(
                         "Updated label-answer {}. New hash: {}",
                         old_hash,
@@ -103,43 +511,90 @@ pub fn parse_event_opt(s: &str) -> Result<Option<Event>> {
     }
 }
 
-pub fn events(reader: impl BufRead) -> impl Iterator<Item = Result<Event>> {
-    reader
-        .lines()
-        .map(|line| match line.with_context(|| "Failed to read line") {
-            Ok(line_str) => match parse_event_opt(&line_str) {
-                Ok(parsed_line) => Ok(parsed_line),
+/// Reads events from JSON lines. Yields each event with its line
+/// number, starting at 1. Blank lines are skipped.
+///
+/// A line that can't be parsed doesn't end the stream, so a caller
+/// can skip it and continue with the next line. The stream ends after
+/// an error reading from the underlying reader.
+pub struct EventReader<R> {
+    buf: Vec<u8>,
+    done: bool,
+    line_no: usize,
+    reader: R,
+}
+
+impl<R: BufRead> EventReader<R> {
+    pub fn new(reader: R) -> EventReader<R> {
+        EventReader {
+            buf: Vec::new(),
+            done: false,
+            line_no: 0,
+            reader,
+        }
+    }
+
+    fn parse_line(&self) -> Result<Option<Event>> {
+        let line =
+            std::str::from_utf8(&self.buf).map_err(|_| Error::msg("Line is not valid UTF-8"))?;
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        parse_event_opt(line).inspect_err(|_| {
+            trace! {"Failed to parse line as JSON: {}", line};
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for EventReader<R> {
+    type Item = (usize, Result<Event>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buf.clear();
+            match self.reader.read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.line_no += 1;
+                    match self.parse_line() {
+                        Ok(Some(event)) => return Some((self.line_no, Ok(event))),
+                        Ok(None) => {}
+                        Err(e) => return Some((self.line_no, Err(e))),
+                    }
+                }
                 Err(e) => {
-                    trace! {"Failed to parse line as JSON: {}", line_str};
-                    Err(e)
+                    self.done = true;
+                    return Some((
+                        self.line_no + 1,
+                        Err(e).with_context(|| "Failed to read line"),
+                    ));
                 }
-            },
-            Err(e) => Err(e),
-        })
-        // Remove blank lines
-        .filter(|x| match x {
-            Ok(Some(_)) => true,
-            Ok(None) => false,
-            Err(_) => true,
-        })
-        .map(|x| match x {
-            Ok(Some(event)) => Ok(event),
-            Ok(None) => panic!("Unexpected None"),
-            Err(e) => Err(e),
-        })
+            }
+        }
+        None
+    }
 }
 
+pub fn events(reader: impl BufRead) -> impl Iterator<Item = Result<Event>> {
+    EventReader::new(reader).map(|(_, result)| result)
+}
+
+/// Check the hash of an event, or set it with the default algorithm
+/// if the event has no hash
 pub fn ensure_hash(event: &mut Event) -> Result<()> {
-    let expected_hash = event_hash(event.clone())?;
-    let hash = event.hash.clone().unwrap_or("".to_string());
-    if hash == "" {
-        event.hash = Some(expected_hash);
-    } else if expected_hash != hash {
-        return Err(Error::msg(format!(
-            "Incorrect event hash. Expected: \"{}\". Found: \"{}\".",
-            expected_hash, hash
-        ))
-        .into());
+    ensure_hash_with(event, HashAlgorithm::default())
+}
+
+/// Check the hash of an event, or set it with the given algorithm if
+/// the event has no hash. Existing hashes are checked with the
+/// algorithm that they name. Tombstones must have a hash.
+pub fn ensure_hash_with(event: &mut Event, algorithm: HashAlgorithm) -> Result<()> {
+    match event.hash.as_deref() {
+        None | Some("") if is_tombstone(event) => Err(Error::msg("Redacted event has no hash")),
+        Some(hash) if is_tombstone(event) => verify_tombstone_hash(hash),
+        None | Some("") => {
+            event.hash = Some(hash_event(event, algorithm)?);
+            Ok(())
+        }
+        Some(hash) => verify_hash(event, hash),
     }
-    Ok(())
 }
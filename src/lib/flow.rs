@@ -1,34 +1,240 @@
-use std::collections::BTreeMap;
-use std::fs::File;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::{self, File};
 use std::io::{BufReader, BufWriter, LineWriter, Write};
-use std::net::{SocketAddr, TcpListener};
-use std::path::PathBuf;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::process::ExitStatus;
 use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env, io};
 
 use anyhow::{Context, Error, Result};
-use log::trace;
+use log::{info, trace, warn};
 use reqwest::blocking::Client;
-use serde::Serialize;
+use serde_json::json;
 use tempfile::TempDir;
 use uuid::Uuid;
 
-use crate::{event, sr_yaml};
+use crate::checkpoint::{self, Checkpoints, FlowCheckpoint, Resume, StepCheckpoint};
+use crate::event::{Event, HashAlgorithm};
+use crate::in_process::{self, StepContext, StepFn};
+use crate::metrics::{self, EdgeMetrics, FlowMetrics, Progress, StepRef};
+use crate::plan::{self, DryRunOptions};
+use crate::step_cache::{self, CacheRun, StepCache};
+use crate::step_log::{self, StepLog};
+use crate::step_manifest::StepKind;
+use crate::validation::Validator;
+use crate::{common, event, limits, process_group, sandbox, secrets, sr_yaml, step_manifest};
 use crate::{Config, Flow, Opts, Step};
 
+/// Options that control how a flow is run, but that are not part
+/// of the config passed to steps
+#[derive(Clone, Debug, Default)]
+pub struct FlowOptions {
+    // Print the plan of the flow instead of running it
+    pub dry_run: Option<DryRunOptions>,
+    // Runs embedded steps in the flow process. When None, every step
+    // runs in its own process.
+    pub in_process: Option<in_process::Resolver>,
+    pub log_dir: Option<PathBuf>,
+    pub metrics_file: Option<PathBuf>,
+    pub quiet: bool,
+    // Write the events that each step emits to a file in this directory
+    pub record_dir: Option<PathBuf>,
+    pub resume: bool,
+    pub strict: bool,
+    // Passed to steps that run in their own process as
+    // SR_TIMESTAMP_OVERRIDE
+    pub timestamp_override: Option<u64>,
+    pub use_free_ports: bool,
+}
+
 #[derive(Debug)]
 pub struct StepProcess {
-    process: process::Child,
+    attempts: u32,
+    cache: Option<Arc<CacheRun>>,
+    // None when the step runs in the flow process
+    cmd: Option<process::Command>,
+    // The algorithm for hashing control events about the step
+    hash_algorithm: HashAlgorithm,
+    idle_timeout: Option<Duration>,
+    index: usize,
+    input_state: Option<Arc<StepServerState>>,
+    log: Option<StepLog>,
+    // None until the step is started
+    process: Option<process::Child>,
+    restart_at: Option<Instant>,
+    started: Instant,
+    step: Step,
     step_server: Option<StepServer>,
+    // Present when the step runs in the flow process
+    thread: Option<thread::JoinHandle<Result<()>>>,
+    timeout: Option<Duration>,
+    // The timestamp of control events about the step. Takes
+    // precedence over SR_TIMESTAMP_OVERRIDE.
+    timestamp_override: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct StepServer {
     input_port: u16,
     output_port: u16,
+    // Present until the next step starts, when it runs in the flow
+    // process
+    receiver: Mutex<Option<Receiver<Event>>>,
+    // Present until the step starts, when it runs in the flow process
+    sender: Option<SyncSender<Event>>,
+    state: Arc<StepServerState>,
+}
+
+/// How a step on either side of a step server runs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Runner {
+    // A thread in the flow process, connected by a channel
+    InProcess,
+    Process,
+    // A process that may be restarted by the retry option
+    RetryProcess,
+}
+
+impl Runner {
+    fn of(step: &Step, options: &FlowOptions) -> Runner {
+        if step.retry.is_some() {
+            Runner::RetryProcess
+        } else if in_process::step_fn(step, options.in_process).is_some() {
+            Runner::InProcess
+        } else {
+            Runner::Process
+        }
+    }
+}
+
+/// Events that were sent to a step that may be restarted, but that
+/// the step has not yet acknowledged by passing them through to its
+/// own output.
+#[derive(Debug, Default)]
+struct ReplayBuffer {
+    events: VecDeque<Event>,
+}
+
+impl ReplayBuffer {
+    /// Returns true if the event was waiting to be acknowledged
+    fn ack(&mut self, hash: &str) -> bool {
+        match self
+            .events
+            .iter()
+            .position(|event| event.hash.as_deref() == Some(hash))
+        {
+            Some(i) => {
+                self.events.drain(..=i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Hashes of events already written downstream, so that a restarted
+/// step or cached output doesn't repeat them
+#[derive(Debug, Default)]
+struct WrittenHashes {
+    // Events written before the step first acknowledged an event, such
+    // as labels that it emits when it starts. None until then.
+    header: Option<HashSet<String>>,
+    recent: HashSet<String>,
+}
+
+impl WrittenHashes {
+    /// Returns false if the hash was already written
+    fn insert(&mut self, hash: &str) -> bool {
+        if let Some(header) = &self.header {
+            if header.contains(hash) {
+                return false;
+            }
+        }
+        self.recent.insert(hash.to_owned())
+    }
+
+    /// Forget the events written before the step acknowledged an
+    /// event. The events it received before that are not replayed,
+    /// so a restarted step only emits its header again.
+    fn acknowledged(&mut self) {
+        let recent = std::mem::take(&mut self.recent);
+        if self.header.is_none() {
+            self.header = Some(recent);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Downstream {
+    closed: bool,
+    connected: bool,
+    // Only tracked for steps that may be restarted or are cached
+    hashes: Option<WrittenHashes>,
+    writer: Option<DownstreamWriter>,
+}
+
+#[derive(Debug)]
+enum DownstreamWriter {
+    // The next step runs in the flow process
+    Channel(SyncSender<Event>),
+    Socket(LineWriter<TcpStream>),
+}
+
+/// Tracks the documents that a step has emitted. A step has finished
+/// a document when it emits the next one, or when the flow completes.
+#[derive(Debug, Default)]
+struct DocumentProgress {
+    current: Option<String>,
+    finished: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Upstream {
+    done: bool,
+    exited: u32,
+}
+
+#[derive(Debug)]
+struct StepServerState {
+    // Present when the step writing to the server is cached
+    cache: Option<Arc<CacheRun>>,
+    // Whether to count the bytes of events sent over a channel, which
+    // are otherwise never serialized
+    count_bytes: bool,
+    downstream: Mutex<Downstream>,
+    downstream_cv: Condvar,
+    documents: Mutex<DocumentProgress>,
+    // An error that stopped the server, reported by wait_for_steps
+    error: Mutex<Option<Error>>,
+    // The algorithm for hashing events that the step emits without a
+    // hash
+    hash_algorithm: HashAlgorithm,
+    // When the last event was received from the step
+    last_event: Mutex<Instant>,
+    metrics: Arc<Mutex<EdgeMetrics>>,
+    // Describes the step writing to the server, e.g. "1 (sh)"
+    producer: String,
+    // Present when the flow is recorded
+    recorder: Option<Mutex<LineWriter<File>>>,
+    // Present when the next step may be restarted
+    replay: Option<Arc<Mutex<ReplayBuffer>>>,
+    // Present on the step servers of steps that add documents when
+    // resuming a flow
+    resume: Mutex<Option<Resume>>,
+    retry: bool,
+    // Whether to validate events in addition to checking hashes
+    strict: bool,
+    upstream: Mutex<Upstream>,
+    upstream_cv: Condvar,
+    // The replay buffer of the previous step server, when the step
+    // writing to this server may be restarted
+    upstream_replay: Option<Arc<Mutex<ReplayBuffer>>>,
 }
 
 fn write_str_pretty(v: &impl serde::Serialize) -> Result<String> {
@@ -40,30 +246,278 @@ fn writeln_err(s: &str) -> Result<()> {
     Ok(())
 }
 
-fn run_step_server(input_listener: TcpListener, output_listener: TcpListener) -> Result<()> {
-    trace! {"run_step_server"};
-    let (input, _) = input_listener.accept().with_context(|| "Listen error")?;
-    let (output, _) = output_listener.accept().with_context(|| "Listen error")?;
-    let reader = BufReader::new(input);
-    let mut writer = LineWriter::new(output);
-
-    let events = event::events(reader);
-    for result in events {
-        let mut event = result.with_context(|| "Cannot parse line as JSON")?;
-        let expected_hash = event::event_hash(event.clone())?;
-        let hash = event.hash.clone().unwrap_or("".to_string());
-        if hash == "" {
-            event.hash = Some(expected_hash);
-        } else if expected_hash != hash {
-            return Err(Error::msg(format!(
-                "Incorrect event hash. Expected: \"{}\". Found: \"{}\".",
-                expected_hash, hash
-            )));
+/// Write an event and return the number of bytes written
+fn write_event_line(writer: &mut LineWriter<TcpStream>, event: &Event) -> Result<usize> {
+    let mut line = serde_json::to_vec(event).with_context(|| "Event serialization failed")?;
+    line.push(b'\n');
+    writer
+        .write_all(&line)
+        .with_context(|| "Buffer write failed")?;
+    Ok(line.len())
+}
+
+/// Counts the bytes written to it
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The length of the line that an event would be written as
+fn event_line_len(event: &Event) -> Result<usize> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, event).with_context(|| "Event serialization failed")?;
+    Ok(counter.0 + 1)
+}
+
+impl StepServerState {
+    fn write_event(&self, event: Event) -> Result<()> {
+        let start = Instant::now();
+        let mut downstream = self.downstream.lock().unwrap();
+        while !downstream.connected {
+            downstream = self.downstream_cv.wait(downstream).unwrap();
+        }
+        // The next step is started after resume is set, so it is
+        // always set by the time the step connects
+        if let Some(resume) = &mut *self.resume.lock().unwrap() {
+            if resume.skip(&event) {
+                return Ok(());
+            }
+        }
+        if let Some(hashes) = &mut downstream.hashes {
+            if !hashes.insert(event.hash.as_ref().expect("hash")) {
+                return Ok(());
+            }
+        }
+        if let Some(recorder) = &self.recorder {
+            let mut recorder = recorder.lock().unwrap();
+            serde_json::to_writer(&mut *recorder, &event)
+                .with_context(|| "Event serialization failed")?;
+            recorder
+                .write_all(b"\n")
+                .with_context(|| "Failed to write to recording")?;
+        }
+        if event.r#type == "document" {
+            let mut documents = self.documents.lock().unwrap();
+            documents.finished = documents.current.take();
+            documents.current = event.hash.clone();
+        }
+        if let Some(replay) = &self.replay {
+            replay.lock().unwrap().events.push_back(event.clone());
+        }
+        let event_type = event.r#type.clone();
+        let result = match &mut downstream.writer {
+            Some(DownstreamWriter::Channel(sender)) => {
+                let bytes = if self.count_bytes {
+                    event_line_len(&event)?
+                } else {
+                    0
+                };
+                match sender.send(event) {
+                    Ok(()) => Some(Ok(bytes)),
+                    Err(_) => {
+                        // The next step stopped reading. It reports
+                        // its own error if it failed.
+                        downstream.writer = None;
+                        None
+                    }
+                }
+            }
+            Some(DownstreamWriter::Socket(writer)) => Some(write_event_line(writer, &event)),
+            None => None,
+        };
+        if let Some(result) = result {
+            match result {
+                Ok(bytes) => {
+                    self.metrics
+                        .lock()
+                        .unwrap()
+                        .record(&event_type, bytes, start.elapsed());
+                }
+                Err(e) => {
+                    if self.replay.is_some() {
+                        // The next step stopped. The event will be replayed
+                        // if it is restarted.
+                        warn!("Lost connection to step: {:?}", e);
+                        downstream.writer = None;
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        let mut downstream = self.downstream.lock().unwrap();
+        downstream.closed = true;
+        downstream.writer = None;
+        self.downstream_cv.notify_all();
+    }
+
+    fn wait_closed(&self) {
+        let mut downstream = self.downstream.lock().unwrap();
+        while !downstream.closed {
+            downstream = self.downstream_cv.wait(downstream).unwrap();
+        }
+    }
+
+    fn set_error(&self, e: Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(e);
+        }
+        drop(error);
+        self.close();
+    }
+
+    fn take_error(&self) -> Option<Error> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Wait for the step to exit after it closes its connection.
+    /// Returns true if the step is being restarted.
+    fn wait_for_restart(&self, exited: u32) -> bool {
+        if !self.retry {
+            return false;
+        }
+        let mut upstream = self.upstream.lock().unwrap();
+        while upstream.exited <= exited {
+            upstream = self.upstream_cv.wait(upstream).unwrap();
+        }
+        !upstream.done
+    }
+
+    fn step_exited(&self, success: bool) {
+        let mut upstream = self.upstream.lock().unwrap();
+        upstream.exited += 1;
+        upstream.done = success;
+        self.upstream_cv.notify_all();
+    }
+}
+
+/// Pass on an event that a step emitted, after its hash is checked
+fn receive_event(
+    state: &StepServerState,
+    validator: &mut Option<Validator>,
+    event: Event,
+) -> Result<()> {
+    if let Some(validator) = validator {
+        validator.check(&event).map_err(|e| {
+            Error::msg(format!(
+                "Step {} emitted an invalid event: {:#}",
+                state.producer, e
+            ))
+        })?;
+    }
+    *state.last_event.lock().unwrap() = Instant::now();
+    if let Some(cache) = &state.cache {
+        cache.record(&event);
+    }
+    let acked = match &state.upstream_replay {
+        Some(upstream_replay) => upstream_replay
+            .lock()
+            .unwrap()
+            .ack(event.hash.as_ref().expect("hash")),
+        None => false,
+    };
+    state.write_event(event)?;
+    if acked {
+        if let Some(hashes) = &mut state.downstream.lock().unwrap().hashes {
+            hashes.acknowledged();
+        }
+    }
+    Ok(())
+}
+
+fn new_validator(state: &StepServerState) -> Option<Validator> {
+    if state.strict {
+        Some(Validator::new())
+    } else {
+        None
+    }
+}
+
+fn run_step_server_input(input_listener: TcpListener, state: Arc<StepServerState>) -> Result<()> {
+    trace! {"run_step_server_input"};
+    let mut validator = new_validator(&state);
+    for stream in input_listener.incoming() {
+        let input = stream.with_context(|| "Listen error")?;
+        let exited = state.upstream.lock().unwrap().exited;
+        let reader = BufReader::new(input);
+        for result in event::events(reader) {
+            let mut event = match result.with_context(|| "Cannot parse line as JSON") {
+                Ok(event) => event,
+                // A step that is killed may leave a partial line
+                Err(e) if state.retry => {
+                    warn!("Error reading from step: {:?}", e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            event::ensure_hash_with(&mut event, state.hash_algorithm)?;
+            receive_event(&state, &mut validator, event)?;
+        }
+        if !state.wait_for_restart(exited) {
+            break;
+        }
+    }
+    state.close();
+    Ok(())
+}
+
+/// Receive events from a step that runs in the flow process. Embedded
+/// steps hash every event that they emit, so the hashes are not
+/// checked again.
+fn run_step_server_channel(receiver: Receiver<Event>, state: Arc<StepServerState>) -> Result<()> {
+    trace! {"run_step_server_channel"};
+    let mut validator = new_validator(&state);
+    for mut event in receiver {
+        if event.hash.is_none() {
+            event::ensure_hash_with(&mut event, state.hash_algorithm)?;
+        }
+        receive_event(&state, &mut validator, event)?;
+    }
+    state.close();
+    Ok(())
+}
+
+fn run_step_server_output(output_listener: TcpListener, state: Arc<StepServerState>) -> Result<()> {
+    trace! {"run_step_server_output"};
+    for stream in output_listener.incoming() {
+        let output = stream.with_context(|| "Listen error")?;
+        let mut writer = LineWriter::new(output);
+        let mut downstream = state.downstream.lock().unwrap();
+        let mut connected = true;
+        if let Some(replay) = &state.replay {
+            // Copy the events so that the buffer isn't locked during IO
+            let events: Vec<Event> = replay.lock().unwrap().events.iter().cloned().collect();
+            for event in events {
+                if let Err(e) = write_event_line(&mut writer, &event) {
+                    warn!("Lost connection to step during replay: {:?}", e);
+                    connected = false;
+                    break;
+                }
+            }
+        }
+        downstream.connected = true;
+        downstream.writer = if downstream.closed || !connected {
+            None
+        } else {
+            Some(DownstreamWriter::Socket(writer))
+        };
+        state.downstream_cv.notify_all();
+        if state.replay.is_none() {
+            break;
         }
-        event
-            .serialize(&mut serde_json::Serializer::new(&mut writer))
-            .with_context(|| "Event serialization failed")?;
-        writer.write(b"\n").with_context(|| "Buffer write failed")?;
     }
     Ok(())
 }
@@ -79,25 +533,134 @@ fn get_port(listener: &TcpListener) -> Result<u16> {
         .port())
 }
 
-fn make_step_server() -> Result<StepServer> {
+/// Start a server that receives events from a step and passes them
+/// on to the next step. The server listens on a port for each side
+/// that runs in its own process, and uses a channel for each side that
+/// runs in the flow process.
+#[allow(clippy::too_many_arguments)]
+fn make_step_server(
+    producer_name: String,
+    producer: Runner,
+    consumer: Runner,
+    upstream_replay: Option<Arc<Mutex<ReplayBuffer>>>,
+    cache: Option<Arc<CacheRun>>,
+    record: Option<PathBuf>,
+    hash_algorithm: HashAlgorithm,
+    options: &FlowOptions,
+) -> Result<StepServer> {
     let addr =
         SocketAddr::from_str("127.0.0.1:0").with_context(|| "Failed to create SocketAddr")?;
-    let input_listener = make_listener(&addr)?;
-    let output_listener = make_listener(&addr)?;
-    let input_port = get_port(&input_listener)?;
-    let output_port = get_port(&output_listener)?;
-
-    thread::spawn(|| match run_step_server(input_listener, output_listener) {
-        Ok(_) => {}
-        Err(e) => eprintln!("Error in step server: {:?}", e),
+    let retry = producer == Runner::RetryProcess;
+    // Cached output may repeat events that the step emits
+    let dedupe = retry || cache.is_some();
+    let recorder = match record {
+        Some(path) => Some(Mutex::new(LineWriter::new(
+            File::create(&path)
+                .with_context(|| format!("Failed to create recording file: {:?}", path))?,
+        ))),
+        None => None,
+    };
+    let (writer, receiver) = if consumer == Runner::InProcess {
+        let (sender, receiver) = mpsc::sync_channel(in_process::CHANNEL_CAPACITY);
+        (Some(DownstreamWriter::Channel(sender)), Some(receiver))
+    } else {
+        (None, None)
+    };
+    let state = Arc::new(StepServerState {
+        cache,
+        count_bytes: options.metrics_file.is_some() || metrics::can_show_progress(),
+        downstream: Mutex::new(Downstream {
+            closed: false,
+            connected: false,
+            hashes: if dedupe {
+                Some(WrittenHashes::default())
+            } else {
+                None
+            },
+            writer,
+        }),
+        downstream_cv: Condvar::new(),
+        documents: Mutex::new(DocumentProgress::default()),
+        error: Mutex::new(None),
+        hash_algorithm,
+        last_event: Mutex::new(Instant::now()),
+        metrics: Arc::new(Mutex::new(EdgeMetrics::default())),
+        producer: producer_name,
+        recorder,
+        replay: if consumer == Runner::RetryProcess {
+            Some(Arc::new(Mutex::new(ReplayBuffer::default())))
+        } else {
+            None
+        },
+        resume: Mutex::new(None),
+        retry,
+        strict: options.strict,
+        upstream: Mutex::new(Upstream::default()),
+        upstream_cv: Condvar::new(),
+        upstream_replay: if retry { upstream_replay } else { None },
     });
 
+    let (input_port, sender) = if producer == Runner::InProcess {
+        let (sender, receiver) = mpsc::sync_channel(in_process::CHANNEL_CAPACITY);
+        let input_state = state.clone();
+        thread::spawn(move || {
+            if let Err(e) = run_step_server_channel(receiver, input_state.clone()) {
+                input_state.set_error(e);
+            }
+        });
+        (0, Some(sender))
+    } else {
+        let input_listener = make_listener(&addr)?;
+        let input_port = get_port(&input_listener)?;
+        let input_state = state.clone();
+        thread::spawn(move || {
+            if let Err(e) = run_step_server_input(input_listener, input_state.clone()) {
+                input_state.set_error(e);
+            }
+        });
+        (input_port, None)
+    };
+    let output_port = if consumer == Runner::InProcess {
+        0
+    } else {
+        let output_listener = make_listener(&addr)?;
+        let output_port = get_port(&output_listener)?;
+        let output_state = state.clone();
+        thread::spawn(move || {
+            if let Err(e) = run_step_server_output(output_listener, output_state.clone()) {
+                output_state.set_error(e);
+            }
+        });
+        output_port
+    };
+
     Ok(StepServer {
         input_port,
         output_port,
+        receiver: Mutex::new(receiver),
+        sender,
+        state,
     })
 }
 
+impl StepServer {
+    /// Connect a step that runs in the flow process to the server's
+    /// output. Like a step that connects to the output port, it
+    /// receives events from then on.
+    fn take_receiver(&self) -> Result<Receiver<Event>> {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(Error::msg("Step server has no channel for the next step"))?;
+        let mut downstream = self.state.downstream.lock().unwrap();
+        downstream.connected = true;
+        self.state.downstream_cv.notify_all();
+        Ok(receiver)
+    }
+}
+
 pub fn make_config(config: &Config, dir: &tempfile::TempDir) -> Result<PathBuf> {
     let mut filename = String::from("config-");
     filename.push_str(&Uuid::new_v4().to_string());
@@ -118,9 +681,12 @@ pub fn step_config(config: Config, step: Step) -> Result<Config> {
             .ok_or(Error::msg(format!("Label not defined: {}", label_id)))?;
         labels.push(label.to_owned());
     }
+    // Secret values are never serialized, but steps have no need
+    // to know which secrets exist either
     Ok(Config {
         current_labels: Some(labels),
         current_step: Some(step),
+        secrets: BTreeMap::new(),
         ..config
     })
 }
@@ -166,69 +732,508 @@ pub fn get_run_command(step: &Step, exe_path: PathBuf) -> Result<(PathBuf, Vec<S
     })
 }
 
+/// The name of the file that the events a step emits are recorded to
+fn recording_file_name(index: usize, step: &Step) -> String {
+    format!("{}-{}.jsonl", index, step_log::step_name(step))
+}
+
+/// Create a recording directory and describe the flow in it, so that
+/// steps can be replayed from the recording
+fn start_recording(dir: &PathBuf, flow_name: &str, steps: &[Step]) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create recording directory: {:?}", dir))?;
+    let step_files: Vec<serde_json::Value> = steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let mut v = json!({
+                "index": i,
+                "name": step_log::step_name(step),
+            });
+            // The sink emits no events
+            if i + 1 < steps.len() {
+                v["file"] = json!(recording_file_name(i, step));
+            }
+            v
+        })
+        .collect();
+    let path = dir.join(RECORDING_FILE);
+    let file = File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    serde_json::to_writer_pretty(
+        BufWriter::new(file),
+        &json!({
+            "flow": flow_name,
+            "steps": step_files,
+        }),
+    )
+    .with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(())
+}
+
+/// Describes a recorded flow
+const RECORDING_FILE: &str = "recording.json";
+
+/// Return the secrets to pass to a step, by the names in its env.
+/// SRVC_TOKEN is passed from the environment if it is not defined as
+/// a secret.
+pub fn forwarded_secrets(config: &Config, step: &Step) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for name in step.env.iter().flatten() {
+        match secrets::value(&config.secrets, name)? {
+            Some(value) => vars.push((name.to_owned(), value.to_owned())),
+            None => {
+                if name == "SRVC_TOKEN" {
+                    if let Ok(token) = env::var(name) {
+                        vars.push((name.to_owned(), token));
+                    }
+                }
+            }
+        }
+    }
+    Ok(vars)
+}
+
 pub fn run_step(
     config: &Config,
     dir: &tempfile::TempDir,
+    index: usize,
     step: &Step,
-    input_port: Option<u16>,
-    output: bool,
-    exe_path: PathBuf,
+    input: Option<&StepServer>,
+    next_step: Option<&Step>,
+    options: &FlowOptions,
 ) -> Result<StepProcess> {
     let step_config = step_config(config.to_owned(), step.to_owned())?;
-    let config_path = make_config(&step_config, dir)?;
-    let step_server = if output {
-        Some(make_step_server()?)
-    } else {
-        None
+    let cache_run = match step.cache {
+        Some(true) => Some(Arc::new(CacheRun::new(
+            StepCache::new(PathBuf::from(step_cache::CACHE_DIR)),
+            step,
+            step_config.current_labels.as_deref().unwrap_or_default(),
+        )?)),
+        _ => None,
     };
-    let (program, args) = get_run_command(step, exe_path)?;
-    let sr_input = match input_port {
-        Some(port) => format!("127.0.0.1:{}", port.to_string()),
-        None => "".into(),
+    let mut step_server = match next_step {
+        Some(next_step) => Some(make_step_server(
+            format!("{} ({})", index, step_log::step_name(step)),
+            Runner::of(step, options),
+            Runner::of(next_step, options),
+            input.and_then(|ss| ss.state.replay.clone()),
+            cache_run.clone(),
+            options
+                .record_dir
+                .as_ref()
+                .map(|dir| dir.join(recording_file_name(index, step))),
+            config.hash_algorithm.unwrap_or_default(),
+            options,
+        )?),
+        None => None,
+    };
+    if let Some(step_fn) = in_process::step_fn(step, options.in_process) {
+        let context = StepContext {
+            config: step_config,
+            env: forwarded_secrets(config, step)?,
+            input: input.map(|ss| ss.take_receiver()).transpose()?,
+            output: step_server.as_mut().and_then(|ss| ss.sender.take()),
+        };
+        return Ok(run_step_in_process(
+            index,
+            step,
+            input,
+            step_server,
+            step_fn,
+            context,
+        ));
+    }
+    let config_path = make_config(&step_config, dir)?;
+    let (program, args) = get_run_command(step, get_exe_path()?)?;
+    let sr_input = match (&cache_run, &step_server) {
+        (Some(cache_run), Some(ss)) => start_cache_proxy(cache_run.clone(), input, ss)?,
+        _ => match input {
+            Some(ss) => format!("127.0.0.1:{}", ss.output_port),
+            None => "".into(),
+        },
     };
     let sr_output = match &step_server {
-        Some(ss) => format!("127.0.0.1:{}", ss.input_port.to_string()),
+        Some(ss) => format!("127.0.0.1:{}", ss.input_port),
         None => "".into(),
     };
 
+    // A sandboxed step may only connect to its own input and output
+    let ports: Vec<u16> = [&sr_input, &sr_output]
+        .iter()
+        .filter_map(|addr| addr.rsplit(':').next().and_then(|s| s.parse().ok()))
+        .collect();
+
     let mut cmd = process::Command::new(program);
     cmd.args(args)
         .env("SR_CONFIG", config_path)
         .env("SR_INPUT", sr_input)
         .env("SR_OUTPUT", sr_output)
         .env_remove("SRVC_TOKEN");
+    for (name, secret) in &config.secrets {
+        for var in secret.env_vars(name) {
+            cmd.env_remove(var);
+        }
+    }
+    for (name, value) in forwarded_secrets(config, step)? {
+        cmd.env(name, value);
+    }
+    if let Some(timestamp) = options.timestamp_override {
+        cmd.env("SR_TIMESTAMP_OVERRIDE", timestamp.to_string());
+    }
+
+    if let Some(limits) = &step.limits {
+        limits::apply(&mut cmd, limits);
+    }
+    // Interactive steps stay in the terminal's process group so that
+    // they can read from it
+    if !step_log::is_interactive(step) {
+        process_group::configure(&mut cmd);
+    }
+    if let Some(sandbox) = &step.sandbox {
+        sandbox::apply(&mut cmd, sandbox, dir.path(), &ports)?;
+        cmd.env("TMPDIR", dir.path());
+    }
+    // Interactive steps need the terminal. The sink may write
+    // events to stdout, so only its stderr is captured.
+    let log = if step_log::is_interactive(step) {
+        None
+    } else {
+        let capture_stdout = next_step.is_some();
+        let log = StepLog::new(
+            index,
+            step,
+            capture_stdout,
+            options.log_dir.as_ref(),
+            options.quiet,
+        )?;
+        log.configure(&mut cmd);
+        Some(log)
+    };
+    let timeout = step
+        .timeout
+        .as_ref()
+        .map(|s| common::parse_duration(s))
+        .transpose()?;
+    let idle_timeout = step
+        .idle_timeout
+        .as_ref()
+        .map(|s| common::parse_duration(s))
+        .transpose()?;
+
+    let mut process = StepProcess {
+        attempts: 0,
+        cache: cache_run,
+        cmd: Some(cmd),
+        hash_algorithm: config.hash_algorithm.unwrap_or_default(),
+        idle_timeout,
+        index,
+        input_state: input.map(|ss| ss.state.clone()),
+        log,
+        process: None,
+        restart_at: None,
+        started: Instant::now(),
+        step: step.to_owned(),
+        step_server,
+        thread: None,
+        timeout,
+        timestamp_override: options.timestamp_override,
+    };
+    // Cached steps are started when there is input that is not in
+    // the cache
+    if process.cache.is_none() {
+        if let Err(e) = process.start() {
+            writeln_err(&format!("Step failed:\n{}", write_str_pretty(step)?))?;
+            return Err(e);
+        }
+    }
+    Ok(process)
+}
+
+/// Start an embedded step as a thread in the flow process
+fn run_step_in_process(
+    index: usize,
+    step: &Step,
+    input: Option<&StepServer>,
+    step_server: Option<StepServer>,
+    step_fn: StepFn,
+    context: StepContext,
+) -> StepProcess {
+    let name = step_log::step_name(step);
+    let hash_algorithm = context.config.hash_algorithm.unwrap_or_default();
+    let thread = thread::spawn(move || {
+        step_fn(context)
+            .map_err(|e| Error::msg(format!("Step {} ({}) failed: {:#}", index, name, e)))
+    });
+    StepProcess {
+        attempts: 0,
+        cache: None,
+        cmd: None,
+        hash_algorithm,
+        idle_timeout: None,
+        index,
+        input_state: input.map(|ss| ss.state.clone()),
+        log: None,
+        process: None,
+        restart_at: None,
+        started: Instant::now(),
+        step: step.to_owned(),
+        step_server,
+        thread: Some(thread),
+        timeout: None,
+        timestamp_override: None,
+    }
+}
+
+/// Start a proxy between a cached step and the previous step server.
+/// Returns the address that the step should read input from.
+fn start_cache_proxy(
+    cache_run: Arc<CacheRun>,
+    input: Option<&StepServer>,
+    step_server: &StepServer,
+) -> Result<String> {
+    let (upstream, listener, sr_input) = match input {
+        Some(ss) => {
+            let addr = SocketAddr::from_str("127.0.0.1:0")
+                .with_context(|| "Failed to create SocketAddr")?;
+            let listener = make_listener(&addr)?;
+            let sr_input = format!("127.0.0.1:{}", get_port(&listener)?);
+            let upstream = SocketAddr::from(([127, 0, 0, 1], ss.output_port));
+            (Some(upstream), Some(listener), sr_input)
+        }
+        None => (None, None, String::new()),
+    };
+    let state = step_server.state.clone();
+    thread::spawn(move || {
+        let emit_state = state.clone();
+        let close_state = state.clone();
+        if let Err(e) = cache_run.run_proxy(
+            upstream,
+            listener,
+            |event| emit_state.write_event(event),
+            || close_state.close(),
+        ) {
+            state.set_error(e);
+        }
+    });
+    Ok(sr_input)
+}
+
+fn exit_code_str(status: &ExitStatus) -> String {
+    status
+        .code()
+        .map(|i| i.to_string())
+        .unwrap_or(String::from("None"))
+}
+
+fn retry_event(
+    index: usize,
+    attempt: u32,
+    max: u32,
+    exit_code: Option<i32>,
+    hash_algorithm: HashAlgorithm,
+    timestamp_override: Option<u64>,
+) -> Result<Event> {
+    let timestamp_override = match timestamp_override {
+        Some(v) => Some(v),
+        None => common::get_timestamp_override()?,
+    };
+    let timestamp = match timestamp_override {
+        Some(v) => v,
+        None => common::get_epoch_sec()?,
+    };
+    let mut event = Event {
+        data: Some(json!({
+            "retry": {
+                "attempt": attempt,
+                "exit-code": exit_code,
+                "max": max,
+                "step": index,
+            },
+            "timestamp": timestamp,
+        })),
+        extra: BTreeMap::new(),
+        hash: None,
+        r#type: String::from("control"),
+        uri: None,
+    };
+    event::ensure_hash_with(&mut event, hash_algorithm)?;
+    Ok(event)
+}
 
-    if let Some(env) = &step.env {
-        if env.contains(&String::from("SRVC_TOKEN")) {
-            if let Ok(token) = env::var("SRVC_TOKEN") {
-                cmd.env("SRVC_TOKEN", token);
+impl StepProcess {
+    fn exited(&self, success: bool) {
+        if let Some(ss) = &self.step_server {
+            ss.state.step_exited(success);
+        }
+    }
+
+    /// Describe why the step failed. A step with limits that failed
+    /// for no reason that its exit status shows also reports its
+    /// limits, since any of them may have caused the failure.
+    fn failure_message(&self, status: &ExitStatus) -> String {
+        let limits = self.step.limits.as_ref();
+        if let Some(limit) = limits.and_then(|limits| limits::exceeded(limits, status)) {
+            return format!("Step {} exceeded its {}", self.index, limit);
+        }
+        let message = match limits::signal(status) {
+            Some(signal) => format!("Step {} was stopped by {}", self.index, signal),
+            None => format!("Step failed with exit code {}", exit_code_str(status)),
+        };
+        match limits.and_then(limits::describe) {
+            Some(limits) => format!("{} with limits {}", message, limits),
+            None => message,
+        }
+    }
+
+    /// Return a message if the step has run longer than its timeout,
+    /// or has gone longer than its idle-timeout without events.
+    fn check_timeouts(&self) -> Option<String> {
+        let now = Instant::now();
+        if let Some(timeout) = self.timeout {
+            if now.duration_since(self.started) > timeout {
+                return Some(format!(
+                    "Step {} exceeded its timeout ({})",
+                    self.index,
+                    self.step.timeout.as_deref().unwrap_or_default()
+                ));
             }
         }
+        if let Some(idle_timeout) = self.idle_timeout {
+            let mut last_event = self.started;
+            for state in [
+                self.input_state.as_ref(),
+                self.step_server.as_ref().map(|ss| &ss.state),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                last_event = last_event.max(*state.last_event.lock().unwrap());
+            }
+            if now.duration_since(last_event) > idle_timeout {
+                return Some(format!(
+                    "Step {} exceeded its idle-timeout ({})",
+                    self.index,
+                    self.step.idle_timeout.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        None
     }
 
-    match cmd
-        .spawn()
-        .with_context(|| "Failed to start step sub-process")
-    {
-        Ok(process) => Ok(StepProcess {
-            step_server,
-            process: process,
-        }),
-        Err(e) => {
-            writeln_err(&format!("Step failed:\n{}", write_str_pretty(step)?))?;
-            Err(e)
+    fn in_process_group(&self) -> bool {
+        !step_log::is_interactive(&self.step)
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        let grouped = self.in_process_group();
+        if let Some(process) = &mut self.process {
+            kill_child(process, grouped).with_context(|| "Failed to kill child process")?;
+            process
+                .wait()
+                .with_context(|| "Error waiting for child process")?;
+        }
+        Ok(())
+    }
+
+    /// Schedule a restart of a failed step. Returns false if the step
+    /// cannot be retried.
+    fn schedule_retry(&mut self, exit_code: Option<i32>, message: &str) -> Result<bool> {
+        let retry = match &self.step.retry {
+            Some(retry) if self.attempts < retry.max => retry.to_owned(),
+            _ => return Ok(false),
+        };
+        let backoff = match &retry.backoff {
+            Some(s) => common::parse_duration(s)?,
+            None => Duration::ZERO,
+        };
+        // Double the backoff after each failed attempt
+        let delay = backoff * 2u32.saturating_pow(self.attempts);
+        self.attempts += 1;
+        warn!(
+            "{}. Retrying step {} in {:?} (attempt {} of {})",
+            message, self.index, delay, self.attempts, retry.max
+        );
+        self.restart_at = Some(Instant::now() + delay);
+
+        if let Some(ss) = &self.step_server {
+            let event = retry_event(
+                self.index,
+                self.attempts,
+                retry.max,
+                exit_code,
+                self.hash_algorithm,
+                self.timestamp_override,
+            )?;
+            let state = ss.state.clone();
+            // Don't block the flow while waiting on the next step
+            thread::spawn(move || {
+                if let Err(e) = state.write_event(event) {
+                    eprintln!("Error in step server: {:?}", e);
+                }
+            });
         }
+        Ok(true)
+    }
+
+    /// Store the output of a cached step that finished successfully
+    fn store_cache(&self) -> Result<()> {
+        if let (Some(cache), Some(ss)) = (&self.cache, &self.step_server) {
+            // Wait for the step server to read all of the step's output
+            ss.state.wait_closed();
+            cache.store()?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.started = Instant::now();
+        let mut process = self
+            .cmd
+            .as_mut()
+            .expect("cmd")
+            .spawn()
+            .with_context(|| "Failed to start step sub-process")?;
+        if let Some(log) = &mut self.log {
+            log.capture(&mut process);
+        }
+        if self.in_process_group() {
+            process_group::register(&process);
+        }
+        self.process = Some(process);
+        Ok(())
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.restart_at = None;
+        self.start()
+            .with_context(|| "Failed to restart step sub-process")
+    }
+}
+
+/// Kill a step process, and the processes that it started if it runs
+/// in its own process group
+fn kill_child(child: &mut process::Child, grouped: bool) -> io::Result<()> {
+    if grouped {
+        process_group::kill(child)
+    } else {
+        child.kill()
     }
 }
 
 fn end_steps(processes: Vec<StepProcess>) -> Result<()> {
     let mut error = None;
-    for mut process in processes {
-        let result = process.process.try_wait();
+    for process in processes {
+        let grouped = process.in_process_group();
+        let mut process = match process.process {
+            Some(process) => process,
+            None => continue,
+        };
+        let result = process.try_wait();
+        process_group::unregister(&process);
         match result {
             Ok(Some(status)) => {
                 if status.code().is_none() {
-                    match process.process.kill() {
+                    match kill_child(&mut process, grouped) {
                         Ok(_) => {}
                         Err(e) => {
                             error = Some(Err(e).with_context(|| "Failed to kill child process"))
@@ -236,12 +1241,12 @@ fn end_steps(processes: Vec<StepProcess>) -> Result<()> {
                     }
                 }
             }
-            Ok(None) => match process.process.kill() {
+            Ok(None) => match kill_child(&mut process, grouped) {
                 Ok(_) => {}
                 Err(e) => error = Some(Err(e).with_context(|| "Failed to kill child process")),
             },
             Err(e) => {
-                let _ = process.process.kill();
+                let _ = kill_child(&mut process, grouped);
                 error = Some(Err(e).with_context(|| "Failed to read exit status of child process"))
             }
         }
@@ -252,86 +1257,326 @@ fn end_steps(processes: Vec<StepProcess>) -> Result<()> {
     }
 }
 
-fn wait_for_steps(mut processes: Vec<StepProcess>) -> Result<()> {
-    let mut exit_status = None;
+/// Periodically records the last document that each step finished
+/// in a file next to the db
+struct CheckpointWriter {
+    checkpoints: Checkpoints,
+    flow_name: String,
+    last_write: Option<Instant>,
+    path: PathBuf,
+    // The checkpoint of an earlier run, used for steps that have not
+    // finished a document in this run
+    previous: FlowCheckpoint,
+    steps: Vec<(StepRef, Arc<StepServerState>)>,
+}
+
+impl CheckpointWriter {
+    fn new(flow_name: &str, db: &str, processes: &[StepProcess]) -> Result<CheckpointWriter> {
+        let path = checkpoint::path(db);
+        let checkpoints = Checkpoints::read(&path)?;
+        let previous = checkpoints
+            .flows
+            .get(flow_name)
+            .cloned()
+            .unwrap_or_default();
+        let steps = processes
+            .iter()
+            .filter_map(|process| {
+                process
+                    .step_server
+                    .as_ref()
+                    .map(|ss| (step_ref(process), ss.state.clone()))
+            })
+            .collect();
+        Ok(CheckpointWriter {
+            checkpoints,
+            flow_name: flow_name.to_owned(),
+            last_write: None,
+            path,
+            previous,
+            steps,
+        })
+    }
+
+    /// Write the checkpoint. `complete` is true when every step has
+    /// exited successfully, so that steps have finished their last
+    /// document.
+    fn write(&mut self, complete: bool) -> Result<()> {
+        let steps = self
+            .steps
+            .iter()
+            .map(|(step, state)| {
+                let documents = state.documents.lock().unwrap();
+                let finished = if complete && documents.current.is_some() {
+                    documents.current.clone()
+                } else {
+                    documents.finished.clone()
+                };
+                let last_document = finished.or_else(|| {
+                    self.previous
+                        .steps
+                        .iter()
+                        .find(|prev| prev.index == step.index)
+                        .and_then(|prev| prev.last_document.clone())
+                });
+                StepCheckpoint {
+                    index: step.index,
+                    last_document,
+                    name: step.name.clone(),
+                }
+            })
+            .collect();
+        self.checkpoints
+            .flows
+            .insert(self.flow_name.clone(), FlowCheckpoint { steps });
+        self.checkpoints.write(&self.path)?;
+        self.last_write = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Write the checkpoint if enough time has passed since the last
+    /// write
+    fn write_periodic(&mut self) -> Result<()> {
+        match self.last_write {
+            Some(t) if t.elapsed() < CHECKPOINT_INTERVAL => Ok(()),
+            _ => self.write(false),
+        }
+    }
+}
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn server_error(servers: &[Arc<StepServerState>]) -> Option<Error> {
+    servers.iter().find_map(|state| state.take_error())
+}
+
+fn wait_for_steps(
+    mut processes: Vec<StepProcess>,
+    mut checkpoint: Option<&mut CheckpointWriter>,
+) -> Result<()> {
+    let servers: Vec<Arc<StepServerState>> = processes
+        .iter()
+        .filter_map(|process| process.step_server.as_ref())
+        .map(|ss| ss.state.clone())
+        .collect();
+    let mut failure = None;
+    // An error returned by a step that runs in the flow process
+    let mut step_failure = None;
     // Start with a small timeout so small tasks exit quickly,
     // but scale up the timeout to avoid excessive CPU usage in
     // long-running flows.
     let mut timeout = Duration::from_millis(10);
-    while processes.len() > 0 && exit_status.is_none() {
+    while processes.len() > 0 && failure.is_none() {
         let mut next_processes = Vec::new();
         for mut process in processes {
             thread::sleep(timeout);
-            match process.process.try_wait() {
+            // Errors below are recorded as the failure rather than
+            // returned, so that the other steps are still ended
+            if let Some(restart_at) = process.restart_at {
+                if Instant::now() >= restart_at {
+                    match process.restart() {
+                        Ok(()) => timeout = Duration::from_millis(10),
+                        Err(e) => failure = failure.or(Some(e)),
+                    }
+                }
+                next_processes.push(process);
+                continue;
+            }
+            if let Some(thread) = process.thread.take() {
+                if !thread.is_finished() {
+                    process.thread = Some(thread);
+                    next_processes.push(process);
+                    continue;
+                }
+                let result = thread.join().unwrap_or_else(|_| {
+                    Err(Error::msg(format!("Step {} panicked", process.index)))
+                });
+                process.exited(result.is_ok());
+                if let Err(e) = result {
+                    // The step's own error is more specific than any
+                    // error it causes in a step server
+                    step_failure = Some(e);
+                }
+                continue;
+            }
+            let child = match &mut process.process {
+                Some(child) => child,
+                None => {
+                    let cache = process.cache.clone().expect("cache");
+                    if cache.start_requested() {
+                        match process.start() {
+                            Ok(()) => timeout = Duration::from_millis(10),
+                            Err(e) => failure = failure.or(Some(e)),
+                        }
+                        next_processes.push(process);
+                    } else if !cache.finished_without_step() {
+                        next_processes.push(process);
+                    }
+                    continue;
+                }
+            };
+            let (exit_code, message) = match child.try_wait() {
                 Ok(Some(status)) => {
-                    if status.code() != Some(0) {
-                        exit_status = Some(status);
+                    process_group::unregister(child);
+                    if status.code() == Some(0) {
+                        process.exited(true);
+                        if let Err(e) = process.store_cache() {
+                            failure = failure.or(Some(e));
+                        }
+                        continue;
+                    }
+                    (status.code(), process.failure_message(&status))
+                }
+                Ok(None) => match process.check_timeouts() {
+                    Some(message) => {
+                        if let Err(e) = process.kill() {
+                            failure = failure.or(Some(e));
+                        }
+                        (None, message)
+                    }
+                    None => {
+                        next_processes.push(process);
+                        continue;
                     }
+                },
+                Err(e) => {
+                    let e = Error::from(e).context("Error waiting for child process");
+                    failure = failure.or(Some(e));
+                    next_processes.push(process);
+                    continue;
+                }
+            };
+            process.exited(false);
+            if let Some(log) = &mut process.log {
+                if let Err(e) = log.dump() {
+                    failure = failure.or(Some(e));
                 }
-                Ok(None) => next_processes.push(process),
-                Err(e) => return Err(e).with_context(|| "Error waiting for child process"),
+            }
+            match process.schedule_retry(exit_code, &message) {
+                Ok(true) => next_processes.push(process),
+                Ok(false) => failure = Some(Error::msg(message)),
+                Err(e) => failure = Some(e),
             }
         }
         processes = next_processes;
+        // A step server error usually causes a step to fail, so it
+        // takes precedence as the more specific error
+        if let Some(e) = server_error(&servers) {
+            failure = Some(e);
+        }
+        if let Some(e) = step_failure.take() {
+            failure = Some(e);
+        }
+        if let Some(checkpoint) = &mut checkpoint {
+            if let Err(e) = checkpoint.write_periodic() {
+                failure = failure.or(Some(e));
+            }
+        }
         if timeout < Duration::from_millis(500) {
             timeout *= 2;
         }
     }
 
-    match exit_status {
-        Some(status) => {
+    match failure.or_else(|| server_error(&servers)) {
+        Some(e) => {
             end_steps(processes)?;
-            Err(Error::msg(format!(
-                "Step failed with exit code {}",
-                status
-                    .code()
-                    .map(|i| i.to_string())
-                    .unwrap_or(String::from("None"))
-            )))
+            Err(e)
         }
         None => Ok(()),
     }
 }
 
-pub fn run_flow_in_dir(flow: &Flow, config: &Config, dir: &TempDir) -> Result<()> {
-    if flow.steps.is_empty() {
-        return Err(Error::msg("No steps in flow"));
-    }
-
+/// Return every step that runs in a flow: the sources, the steps of
+/// the flow, and the sink
+pub fn flow_steps(flow: &Flow, config: &Config) -> Vec<Step> {
     let mut steps = Vec::new();
-    let flow_steps = &flow.steps.clone();
-
     for source in &config.sources {
-        steps.push(&source.step);
+        steps.push(source.step.clone());
     }
-    steps.extend(flow_steps);
-    let sink_step = Step {
+    steps.extend(flow.steps.iter().cloned());
+    steps.push(sink_step());
+    steps
+}
+
+/// The step that is added to the end of every flow
+pub fn sink_step() -> Step {
+    Step {
+        cache: None,
         env: Some(vec![String::from("SRVC_TOKEN")]),
         extra: BTreeMap::new(),
+        idle_timeout: None,
         labels: Vec::new(),
+        limits: None,
+        manifest: None,
+        retry: None,
         run: None,
         run_embedded: Some(String::from("sink")),
+        sandbox: None,
+        timeout: None,
+    }
+}
+
+pub fn run_flow_in_dir(
+    flow_name: &str,
+    flow: &Flow,
+    config: &Config,
+    dir: &TempDir,
+    options: &FlowOptions,
+) -> Result<()> {
+    if flow.steps.is_empty() {
+        return Err(Error::msg("No steps in flow"));
+    }
+
+    let steps = flow_steps(flow, config);
+    step_manifest::check_order(&steps)?;
+    if let Some(dir) = &options.record_dir {
+        start_recording(dir, flow_name, &steps)?;
+    }
+
+    let resume = if options.resume {
+        if !checkpoint::is_local_db(&config.db) {
+            return Err(Error::msg(format!(
+                "Cannot resume a flow that writes to \"{}\". Only local dbs have checkpoints.",
+                config.db
+            )));
+        }
+        let checkpoints = Checkpoints::read(&checkpoint::path(&config.db))?;
+        match checkpoints.flows.get(flow_name) {
+            Some(flow_checkpoint) => Some(Resume::new(
+                flow_checkpoint,
+                checkpoint::document_hashes(&config.db)?,
+            )),
+            None => {
+                info!(
+                    "No checkpoint for flow \"{}\". Running every document.",
+                    flow_name
+                );
+                None
+            }
+        }
+    } else {
+        None
     };
-    steps.push(&sink_step);
 
-    let exe_path = get_exe_path()?;
     let mut processes = Vec::new();
+    let started = Instant::now();
 
-    for step in steps {
-        let is_last_step = step == &sink_step;
+    for (i, step) in steps.iter().enumerate() {
         let last_ss = processes
             .last()
             .map(|x: &StepProcess| x.step_server.as_ref())
             .flatten();
-        match run_step(
-            config,
-            &dir,
-            &step,
-            last_ss.map(|x| x.output_port),
-            !is_last_step,
-            exe_path.clone(),
-        ) {
-            Ok(process) => processes.push(process),
+        match run_step(config, &dir, i, &step, last_ss, steps.get(i + 1), options) {
+            Ok(process) => {
+                // Skip documents at each step where they enter the
+                // flow, so that no later step sees them
+                if let Some(ss) = &process.step_server {
+                    if adds_documents(config, i, step) {
+                        *ss.state.resume.lock().unwrap() = resume.clone();
+                    }
+                }
+                processes.push(process)
+            }
             Err(e) => {
                 end_steps(processes)?;
                 return Err(e);
@@ -339,7 +1584,63 @@ pub fn run_flow_in_dir(flow: &Flow, config: &Config, dir: &TempDir) -> Result<()
         }
     }
 
-    wait_for_steps(processes)
+    let metrics = Arc::new(flow_metrics(&processes, started));
+    // A progress line would interfere with interactive steps
+    let mut progress = if metrics::can_show_progress()
+        && !steps.iter().any(|step| step_log::is_interactive(step))
+    {
+        Some(Progress::start(metrics.clone()))
+    } else {
+        None
+    };
+    let mut checkpoint = if checkpoint::is_local_db(&config.db) {
+        Some(CheckpointWriter::new(flow_name, &config.db, &processes)?)
+    } else {
+        None
+    };
+    let result = wait_for_steps(processes, checkpoint.as_mut());
+    let checkpoint_result = match &mut checkpoint {
+        Some(checkpoint) => checkpoint.write(result.is_ok()),
+        None => Ok(()),
+    };
+    if let Some(progress) = &mut progress {
+        progress.stop();
+        write!(io::stderr(), "{}", metrics.summary_table())
+            .with_context(|| "Failed to write to stderr")?;
+    }
+    if let Some(path) = &options.metrics_file {
+        metrics.write_file(path)?;
+    }
+    result.and(checkpoint_result)
+}
+
+/// Whether documents can enter the flow at a step: the first step, a
+/// source, or a generator that emits documents after its input
+fn adds_documents(config: &Config, index: usize, step: &Step) -> bool {
+    index == 0
+        || index < config.sources.len()
+        || step_manifest::get(step).map(|manifest| manifest.kind) == Some(StepKind::Generator)
+}
+
+fn flow_metrics(processes: &[StepProcess], started: Instant) -> FlowMetrics {
+    let mut metrics = FlowMetrics::new(started);
+    for pair in processes.windows(2) {
+        if let Some(ss) = &pair[0].step_server {
+            metrics.add_edge(
+                step_ref(&pair[0]),
+                step_ref(&pair[1]),
+                ss.state.metrics.clone(),
+            );
+        }
+    }
+    metrics
+}
+
+fn step_ref(process: &StepProcess) -> StepRef {
+    StepRef {
+        index: process.index,
+        name: step_log::step_name(&process.step),
+    }
 }
 
 fn remove_step_ports(mut flow: Flow) -> Flow {
@@ -352,30 +1653,27 @@ fn remove_step_ports(mut flow: Flow) -> Flow {
     flow
 }
 
-pub fn run_flow(flow: &Flow, config: &Config) -> Result<()> {
+pub fn run_flow(
+    flow_name: &str,
+    flow: &Flow,
+    config: &Config,
+    options: &FlowOptions,
+) -> Result<()> {
     let dir = tempfile::Builder::new()
         .prefix("srvc-")
         .tempdir()
         .with_context(|| "Failed to create temporary directory")?;
-    let result = run_flow_in_dir(flow, config, &dir);
+    let result = run_flow_in_dir(flow_name, flow, config, &dir, options);
     dir.close()
         .with_context(|| "Failed to delete temporary directory")?;
     return result;
 }
 
-pub fn run(
-    opts: &mut Opts,
-    db: Option<String>,
-    def: Option<String>,
-    flow_name: String,
-    reviewer: Option<String>,
-    sink_control_events: bool,
-    use_free_ports: bool,
-) -> Result<()> {
+/// Read the config and set the db and reviewer
+fn load_config(opts: &Opts, db: Option<String>, reviewer: Option<String>) -> Result<Config> {
     let yaml_config = sr_yaml::get_config(PathBuf::from(&opts.config))?;
     let mut config = sr_yaml::parse_config(yaml_config)?;
     config.db = db.unwrap_or(config.db);
-    config.sink_control_events = sink_control_events;
 
     let reviewer = match reviewer {
         Some(s) => s,
@@ -385,6 +1683,30 @@ pub fn run(
     };
     sr_yaml::validate_reviewer(&reviewer)?;
     config.reviewer = Some(reviewer);
+    Ok(config)
+}
+
+fn get_flow(opts: &Opts, config: &Config, flow_name: &str) -> Result<Flow> {
+    match config.flows.get(flow_name) {
+        Some(flow) => Ok(flow.clone()),
+        None => Err(Error::msg(format!(
+            "No flow named \"{}\" in \"{}\"",
+            flow_name, &opts.config
+        ))),
+    }
+}
+
+pub fn run(
+    opts: &mut Opts,
+    db: Option<String>,
+    def: Option<String>,
+    flow_name: String,
+    reviewer: Option<String>,
+    sink_control_events: bool,
+    options: FlowOptions,
+) -> Result<()> {
+    let mut config = load_config(opts, db, reviewer)?;
+    config.sink_control_events = sink_control_events;
 
     if let Some(s) = def {
         let flow_sr_yaml = serde_json::from_str(&s)?;
@@ -392,22 +1714,177 @@ pub fn run(
         let flow = sr_yaml::parse_flow(&client, flow_sr_yaml)?;
         config.flows.insert(flow_name.clone(), flow);
     }
-    let flow = config.flows.get(&flow_name);
-    let flow = match flow {
-        Some(flow) => Ok(flow),
-        None => Err(Error::msg(format!(
-            "No flow named \"{}\" in \"{}\"",
-            flow_name, &opts.config
-        ))),
-    }?
-    .clone();
-    let flow = if use_free_ports {
+    let flow = get_flow(opts, &config, &flow_name)?;
+    let flow = if options.use_free_ports {
         let flow = remove_step_ports(flow);
-        config.flows.insert(flow_name, flow.clone());
+        config.flows.insert(flow_name.clone(), flow.clone());
         flow
     } else {
         flow
     };
-    run_flow(&flow, &config)?;
+    if let Some(dry_run) = &options.dry_run {
+        return plan::run(&flow_name, &flow, &config, dry_run, options.in_process);
+    }
+    run_flow(&flow_name, &flow, &config, &options)?;
+    Ok(())
+}
+
+/// Read the name of the recorded flow from the recording directory
+/// that holds an input file
+fn recorded_flow_name(input: &Path) -> Result<String> {
+    let path = input
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(RECORDING_FILE);
+    let file = File::open(&path).map_err(|_| {
+        Error::msg(format!(
+            "No flow name given, and no {} found next to the input file. Use --flow to name the flow.",
+            RECORDING_FILE
+        ))
+    })?;
+    let recording: serde_json::Value = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse {:?}", path))?;
+    recording["flow"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| Error::msg(format!("No flow name in {:?}", path)))
+}
+
+/// Send the events in a file to a step server
+fn feed_events(path: PathBuf, port: u16) -> Result<()> {
+    let file = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+    let stream = TcpStream::connect(("127.0.0.1", port))
+        .with_context(|| format!("Failed to connect to step server on port {}", port))?;
+    let mut writer = LineWriter::new(stream);
+    for result in event::events(BufReader::new(file)) {
+        let event = result.with_context(|| format!("Failed to read {:?}", path))?;
+        write_event_line(&mut writer, &event)?;
+    }
+    Ok(())
+}
+
+/// Read the events from a step server
+fn read_events(port: u16, mut on_event: impl FnMut(Event) -> Result<()>) -> Result<()> {
+    let stream = TcpStream::connect(("127.0.0.1", port))
+        .with_context(|| format!("Failed to connect to step server on port {}", port))?;
+    for result in event::events(BufReader::new(stream)) {
+        on_event(result.with_context(|| "Cannot parse line as JSON")?)?;
+    }
+    Ok(())
+}
+
+fn print_event(event: Event) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, &event).with_context(|| "Event serialization failed")?;
+    writeln!(stdout).with_context(|| "Failed to write to stdout")?;
     Ok(())
 }
+
+/// Run a single step outside of a flow. The events in `input` are
+/// sent to the step, and `on_event` is called with each event that
+/// the step emits.
+pub fn run_isolated(
+    config: &Config,
+    index: usize,
+    step: &Step,
+    next_step: Option<&Step>,
+    input: Option<PathBuf>,
+    options: &FlowOptions,
+    on_event: impl FnMut(Event) -> Result<()> + Send + 'static,
+) -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("srvc-")
+        .tempdir()
+        .with_context(|| "Failed to create temporary directory")?;
+    let input_server = match input {
+        Some(path) => {
+            // Replay input to the step if it is restarted
+            let ss = make_step_server(
+                String::from("input"),
+                Runner::Process,
+                Runner::of(step, options),
+                None,
+                None,
+                None,
+                config.hash_algorithm.unwrap_or_default(),
+                options,
+            )?;
+            let port = ss.input_port;
+            let state = ss.state.clone();
+            thread::spawn(move || {
+                if let Err(e) = feed_events(path, port) {
+                    state.set_error(e);
+                }
+            });
+            Some(ss)
+        }
+        None => None,
+    };
+    let process = run_step(
+        config,
+        &dir,
+        index,
+        step,
+        input_server.as_ref(),
+        next_step,
+        options,
+    )?;
+    let reader = process.step_server.as_ref().map(|ss| {
+        let port = ss.output_port;
+        thread::spawn(move || read_events(port, on_event))
+    });
+    let mut result = wait_for_steps(vec![process], None);
+    if let Some(ss) = &input_server {
+        if let Some(e) = ss.state.take_error() {
+            result = result.and(Err(e));
+        }
+    }
+    if let Some(reader) = reader {
+        if result.is_ok() {
+            result = reader.join().expect("join");
+        }
+    }
+    dir.close()
+        .with_context(|| "Failed to delete temporary directory")?;
+    result
+}
+
+/// Run one step of a flow by itself, with input from a file, and
+/// print the events that it emits.
+pub fn replay_step(
+    opts: &mut Opts,
+    flow_name: Option<String>,
+    index: usize,
+    input: Option<PathBuf>,
+) -> Result<()> {
+    let flow_name = match (flow_name, &input) {
+        (Some(name), _) => name,
+        (None, Some(input)) => recorded_flow_name(input)?,
+        (None, None) => return Err(Error::msg("Use --flow to name the flow")),
+    };
+    let mut config = load_config(opts, None, None)?;
+    let flow = get_flow(opts, &config, &flow_name)?;
+    let steps = flow_steps(&flow, &config);
+    let step = steps.get(index).ok_or_else(|| {
+        Error::msg(format!(
+            "Flow \"{}\" has no step {}. Its steps are numbered 0 to {}.",
+            flow_name,
+            index,
+            steps.len() - 1
+        ))
+    })?;
+    // Print the events that reach the sink instead of adding them
+    // to the db
+    if index + 1 == steps.len() {
+        config.db = String::from("-");
+    }
+    run_isolated(
+        &config,
+        index,
+        step,
+        steps.get(index + 1),
+        input,
+        &FlowOptions::default(),
+        print_event,
+    )
+}
@@ -0,0 +1,55 @@
+use std::sync::mpsc::{Receiver, SyncSender};
+
+use anyhow::Result;
+
+use crate::event::Event;
+use crate::{Config, Step};
+
+/// The number of events that can wait in a channel between a step
+/// that runs in the flow process and its step server
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// What an embedded step that runs in the flow process receives in
+/// place of SR_CONFIG, SR_INPUT, and SR_OUTPUT
+#[derive(Debug)]
+pub struct StepContext {
+    pub config: Config,
+    // The secrets and other variables that the step lists in env
+    pub env: Vec<(String, String)>,
+    pub input: Option<Receiver<Event>>,
+    pub output: Option<SyncSender<Event>>,
+}
+
+impl StepContext {
+    /// The value of a variable that the step would see in its
+    /// environment if it ran in its own process
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.env
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Runs an embedded step in the flow process
+pub type StepFn = Box<dyn FnOnce(StepContext) -> Result<()> + Send>;
+
+/// Returns the function that runs an embedded step in the flow
+/// process, or None if the step needs a process of its own
+pub type Resolver = fn(&Step) -> Option<StepFn>;
+
+/// Return the function that runs a step in the flow process. Steps
+/// with options that act on a process, such as limits or retry, always
+/// run in their own process.
+pub fn step_fn(step: &Step, resolver: Option<Resolver>) -> Option<StepFn> {
+    if step.cache == Some(true)
+        || step.idle_timeout.is_some()
+        || step.limits.is_some()
+        || step.retry.is_some()
+        || step.sandbox.is_some()
+        || step.timeout.is_some()
+    {
+        return None;
+    }
+    resolver.and_then(|f| f(step))
+}
@@ -9,23 +9,72 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_with::skip_serializing_none;
 
+pub mod answers;
+pub mod blob;
+pub mod checkpoint;
 pub mod common;
 pub mod event;
 pub mod flow;
+pub mod in_process;
 pub mod json_schema;
+pub mod limits;
+pub mod metrics;
+pub mod plan;
+pub mod process_group;
+pub mod sandbox;
+pub mod secrets;
+pub mod signature;
 pub mod sqlite;
 pub mod sr_yaml;
+pub mod step_cache;
+pub mod step_log;
+pub mod step_manifest;
+pub mod step_source;
+pub mod test_step;
+pub mod validation;
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Limits {
+    #[serde(rename = "cpu-seconds")]
+    pub cpu_seconds: Option<u64>,
+    pub memory: Option<u64>,
+    #[serde(rename = "open-files")]
+    pub open_files: Option<u64>,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Retry {
+    pub backoff: Option<String>,
+    pub max: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Sandbox {
+    pub network: bool,
+    pub read: Vec<String>,
+    pub write: Vec<String>,
+}
 
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Step {
+    pub cache: Option<bool>,
     pub env: Option<Vec<String>>,
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
+    #[serde(rename = "idle-timeout")]
+    pub idle_timeout: Option<String>,
     pub labels: Vec<String>,
+    pub limits: Option<Limits>,
+    pub manifest: Option<step_manifest::StepManifest>,
+    pub retry: Option<Retry>,
     pub run: Option<String>,
     #[serde(rename = "run-embedded")]
     pub run_embedded: Option<String>,
+    pub sandbox: Option<Sandbox>,
+    pub timeout: Option<String>,
 }
 
 #[skip_serializing_none]
@@ -37,7 +86,7 @@ pub struct Flow {
 }
 
 #[skip_serializing_none]
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Label {
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
@@ -70,8 +119,21 @@ pub struct Config {
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
     pub flows: BTreeMap<String, Flow>,
+    // The algorithm for hashing new events. None means SHA-256.
+    #[serde(rename = "hash-algorithm")]
+    pub hash_algorithm: Option<event::HashAlgorithm>,
     pub labels: BTreeMap<String, Label>,
+    #[serde(rename = "require-signatures")]
+    pub require_signatures: Option<bool>,
     pub reviewer: Option<String>,
+    // The public keys of reviewers, by reviewer URI
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reviewers: BTreeMap<String, signature::Reviewer>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secrets: BTreeMap<String, secrets::Secret>,
+    // Whether to sign new label-answers. None means sign only if
+    // SR_SIGNING_KEY_FILE is set.
+    pub sign: Option<bool>,
     #[serde(
         alias = "sink-all-events",
         alias = "sink_all_events",
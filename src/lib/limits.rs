@@ -0,0 +1,130 @@
+use std::process::{Command, ExitStatus};
+
+#[cfg(not(target_os = "linux"))]
+use log::warn;
+
+use crate::Limits;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(all(target_os = "linux", not(target_env = "gnu")))]
+type Resource = libc::c_int;
+
+#[cfg(target_os = "linux")]
+fn set_rlimit(resource: Resource, soft: u64, hard: u64) -> std::io::Result<()> {
+    let rlim = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &rlim) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Apply resource limits to a command before it is spawned.
+#[cfg(target_os = "linux")]
+pub fn apply(cmd: &mut Command, limits: &Limits) {
+    use std::os::unix::process::CommandExt;
+
+    let limits = limits.to_owned();
+    // Safety: the closure only calls setrlimit, which is async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(n) = limits.cpu_seconds {
+                // The soft limit sends SIGXCPU, which lets us tell why
+                // the step stopped. The hard limit sends SIGKILL.
+                set_rlimit(libc::RLIMIT_CPU, n, n.saturating_add(1))?;
+            }
+            if let Some(n) = limits.memory {
+                set_rlimit(libc::RLIMIT_AS, n, n)?;
+            }
+            if let Some(n) = limits.open_files {
+                set_rlimit(libc::RLIMIT_NOFILE, n, n)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_cmd: &mut Command, _limits: &Limits) {
+    warn!("Step limits are only supported on Linux and will be ignored");
+}
+
+/// Return a description of the limit that stopped the process, if
+/// the exit status shows that it was stopped by one. Only the CPU
+/// limit stops a process with a signal of its own. Memory and open
+/// files limits make allocations and opens fail, and the process
+/// decides how to exit, so see describe for those.
+#[cfg(target_os = "linux")]
+pub fn exceeded(limits: &Limits, status: &ExitStatus) -> Option<String> {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.signal() {
+        Some(libc::SIGXCPU) => limits
+            .cpu_seconds
+            .map(|n| format!("cpu-seconds limit ({})", n)),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exceeded(_limits: &Limits, _status: &ExitStatus) -> Option<String> {
+    None
+}
+
+/// Describe the limits that were set on a process, such as
+/// "memory=1000000, open-files=16", or None if there were none. A
+/// step that fails with limits set may have failed because of them.
+#[cfg(target_os = "linux")]
+pub fn describe(limits: &Limits) -> Option<String> {
+    let limits: Vec<String> = [
+        ("cpu-seconds", limits.cpu_seconds),
+        ("memory", limits.memory),
+        ("open-files", limits.open_files),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|n| format!("{}={}", name, n)))
+    .collect();
+    if limits.is_empty() {
+        None
+    } else {
+        Some(limits.join(", "))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn describe(_limits: &Limits) -> Option<String> {
+    None
+}
+
+/// Describe the signal that stopped the process, if it was stopped
+/// by one
+#[cfg(unix)]
+pub fn signal(status: &ExitStatus) -> Option<String> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let signal = status.signal()?;
+    let name = match signal {
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGILL => "SIGILL",
+        libc::SIGINT => "SIGINT",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        _ => return Some(format!("signal {}", signal)),
+    };
+    Some(format!("signal {} ({})", signal, name))
+}
+
+#[cfg(not(unix))]
+pub fn signal(_status: &ExitStatus) -> Option<String> {
+    None
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::common;
+
+/// How often the progress line is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Counters for the events that pass from one step to the next
+#[derive(Debug, Default)]
+pub struct EdgeMetrics {
+    blocked: Duration,
+    bytes: u64,
+    events: u64,
+    events_by_type: BTreeMap<String, u64>,
+}
+
+impl EdgeMetrics {
+    /// Record an event written to the next step. `blocked` is the
+    /// time spent waiting for the next step to accept it.
+    pub fn record(&mut self, event_type: &str, bytes: usize, blocked: Duration) {
+        self.blocked += blocked;
+        self.bytes += bytes as u64;
+        self.events += 1;
+        *self
+            .events_by_type
+            .entry(event_type.to_string())
+            .or_insert(0) += 1;
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StepRef {
+    pub index: usize,
+    pub name: String,
+}
+
+#[derive(Debug)]
+struct Edge {
+    from: StepRef,
+    metrics: Arc<Mutex<EdgeMetrics>>,
+    to: StepRef,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EdgeReport {
+    pub blocked_ms: u128,
+    pub bytes: u64,
+    pub events: u64,
+    pub events_by_type: BTreeMap<String, u64>,
+    pub events_per_second: f64,
+    pub from: StepRef,
+    pub to: StepRef,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FlowReport {
+    pub duration_ms: u128,
+    pub edges: Vec<EdgeReport>,
+}
+
+/// Metrics for every edge between the steps of a flow
+#[derive(Debug)]
+pub struct FlowMetrics {
+    edges: Vec<Edge>,
+    started: Instant,
+}
+
+fn per_second(n: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        n as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+impl FlowMetrics {
+    pub fn new(started: Instant) -> FlowMetrics {
+        FlowMetrics {
+            edges: Vec::new(),
+            started,
+        }
+    }
+
+    pub fn add_edge(&mut self, from: StepRef, to: StepRef, metrics: Arc<Mutex<EdgeMetrics>>) {
+        self.edges.push(Edge { from, metrics, to });
+    }
+
+    pub fn report(&self) -> FlowReport {
+        let elapsed = self.started.elapsed();
+        let edges = self
+            .edges
+            .iter()
+            .map(|edge| {
+                let metrics = edge.metrics.lock().unwrap();
+                EdgeReport {
+                    blocked_ms: metrics.blocked.as_millis(),
+                    bytes: metrics.bytes,
+                    events: metrics.events,
+                    events_by_type: metrics.events_by_type.clone(),
+                    events_per_second: per_second(metrics.events, elapsed),
+                    from: edge.from.clone(),
+                    to: edge.to.clone(),
+                }
+            })
+            .collect();
+        FlowReport {
+            duration_ms: elapsed.as_millis(),
+            edges,
+        }
+    }
+
+    fn progress_line(&self) -> String {
+        let mut parts = Vec::new();
+        for edge in &self.report().edges {
+            parts.push(format!(
+                "{}→{} {} ({:.1}/s)",
+                edge.from.name, edge.to.name, edge.events, edge.events_per_second
+            ));
+        }
+        format!(
+            "[{}s] {}",
+            self.started.elapsed().as_secs(),
+            parts.join(" | ")
+        )
+    }
+
+    /// A table of the metrics for each edge, for printing at the end
+    /// of a flow
+    pub fn summary_table(&self) -> String {
+        let report = self.report();
+        let mut rows = vec![[
+            String::from("Edge"),
+            String::from("Events"),
+            String::from("Bytes"),
+            String::from("Events/s"),
+            String::from("Blocked"),
+        ]];
+        for edge in &report.edges {
+            rows.push([
+                format!(
+                    "{} {} → {} {}",
+                    edge.from.index, edge.from.name, edge.to.index, edge.to.name
+                ),
+                edge.events.to_string(),
+                common::format_bytes(edge.bytes),
+                format!("{:.1}", edge.events_per_second),
+                format!("{:.1}s", edge.blocked_ms as f64 / 1000.0),
+            ]);
+        }
+        let mut widths = [0; 5];
+        for row in &rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.chars().count());
+            }
+        }
+        let mut s = String::new();
+        for row in &rows {
+            let mut line = String::new();
+            for (i, cell) in row.iter().enumerate() {
+                let pad = " ".repeat(widths[i] - cell.chars().count());
+                if i == 0 {
+                    line.push_str(cell);
+                    line.push_str(&pad);
+                } else {
+                    line.push_str("  ");
+                    line.push_str(&pad);
+                    line.push_str(cell);
+                }
+            }
+            s.push_str(line.trim_end());
+            s.push('\n');
+        }
+        s.push_str(&format!(
+            "Total time: {:.1}s\n",
+            report.duration_ms as f64 / 1000.0
+        ));
+        s
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create metrics file: {:?}", path))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &self.report())
+            .with_context(|| "Failed to write metrics file")?;
+        writer
+            .write_all(b"\n")
+            .with_context(|| "Failed to write metrics file")?;
+        Ok(())
+    }
+}
+
+/// Whether a live progress line can be shown on stderr
+pub fn can_show_progress() -> bool {
+    io::stderr().is_terminal()
+}
+
+/// Redraws a progress line on stderr until stopped
+#[derive(Debug)]
+pub struct Progress {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Progress {
+    pub fn start(metrics: Arc<FlowMetrics>) -> Progress {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                let _ = write!(io::stderr().lock(), "\r\x1b[2K{}", metrics.progress_line());
+                thread::park_timeout(PROGRESS_INTERVAL);
+            }
+        });
+        Progress {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+        let _ = write!(io::stderr().lock(), "\r\x1b[2K");
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_with::skip_serializing_none;
+
+use crate::flow::{self, get_exe_path};
+use crate::in_process::{self, Resolver};
+use crate::{step_log, step_manifest, Config, Flow, Label, Step};
+
+/// Options for `sr flow --dry-run`
+#[derive(Clone, Debug, Default)]
+pub struct DryRunOptions {
+    // Write each step's config file to this directory
+    pub config_dir: Option<PathBuf>,
+    pub json: bool,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize)]
+pub struct StepPlan {
+    // The command runs the step when it runs in its own process
+    pub args: Vec<String>,
+    #[serde(rename = "current-labels")]
+    pub current_labels: Vec<Label>,
+    pub env: BTreeMap<String, String>,
+    // Whether the step runs as a thread in the flow process
+    #[serde(rename = "in-process")]
+    pub in_process: bool,
+    pub index: usize,
+    // The index of the step that this step reads events from
+    pub input: Option<usize>,
+    pub interactive: bool,
+    pub kind: String,
+    pub name: String,
+    // The index of the step that reads events from this step
+    pub output: Option<usize>,
+    pub port: Option<u64>,
+    pub program: String,
+    pub step: Step,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FlowPlan {
+    pub flow: String,
+    pub sink: String,
+    pub steps: Vec<StepPlan>,
+}
+
+fn step_kind(index: usize, sources: usize, steps: usize) -> &'static str {
+    if index < sources {
+        "source"
+    } else if index + 1 == steps {
+        "sink"
+    } else {
+        "step"
+    }
+}
+
+fn write_step_config(config: &Config, path: &PathBuf) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create config file: {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, config)
+        .with_context(|| "Failed to write config for step")?;
+    writeln!(writer).with_context(|| "Failed to write config for step")?;
+    Ok(())
+}
+
+/// Resolve everything that would run in a flow, without running it.
+/// resolver finds the steps that run in the flow process, as in
+/// FlowOptions.
+pub fn make_plan(
+    flow_name: &str,
+    flow: &Flow,
+    config: &Config,
+    options: &DryRunOptions,
+    resolver: Option<Resolver>,
+) -> Result<FlowPlan> {
+    if let Some(dir) = &options.config_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    }
+    let steps = flow::flow_steps(flow, config);
+    step_manifest::check_order(&steps)?;
+    let exe_path = get_exe_path()?;
+    let mut plans = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let step_config = flow::step_config(config.to_owned(), step.to_owned())?;
+        let (program, args) = flow::get_run_command(step, exe_path.clone())?;
+        let name = step_log::step_name(step);
+        let config_path = match &options.config_dir {
+            Some(dir) => {
+                let path = dir.join(format!("{}-{}.json", i, name));
+                write_step_config(&step_config, &path)?;
+                path.to_string_lossy().to_string()
+            }
+            None => String::from("(temporary file)"),
+        };
+        // Steps in the flow process get their config and events
+        // directly instead of through these variables
+        let in_process = in_process::step_fn(step, resolver).is_some();
+        let mut env = BTreeMap::new();
+        if !in_process {
+            env.insert(String::from("SR_CONFIG"), config_path);
+            if i > 0 {
+                env.insert(String::from("SR_INPUT"), format!("step {} output", i - 1));
+            }
+            if i + 1 < steps.len() {
+                env.insert(String::from("SR_OUTPUT"), format!("step {} input", i + 1));
+            }
+        }
+        for name in step.env.iter().flatten() {
+            let value = match config.secrets.get(name) {
+                Some(secret) if secret.value.is_some() => "(secret)",
+                Some(_) => "(not set)",
+                None if name == "SRVC_TOKEN" && env::var(name).is_ok() => "(forwarded)",
+                None => continue,
+            };
+            env.insert(name.to_owned(), value.to_string());
+        }
+        plans.push(StepPlan {
+            args,
+            current_labels: step_config.current_labels.clone().unwrap_or_default(),
+            env,
+            in_process,
+            index: i,
+            input: if i > 0 { Some(i - 1) } else { None },
+            interactive: step_log::is_interactive(step),
+            kind: step_kind(i, config.sources.len(), steps.len()).to_string(),
+            name,
+            output: if i + 1 < steps.len() {
+                Some(i + 1)
+            } else {
+                None
+            },
+            port: step.extra.get("port").and_then(|v| v.as_u64()),
+            program: program.to_string_lossy().to_string(),
+            step: step.to_owned(),
+        });
+    }
+    Ok(FlowPlan {
+        flow: flow_name.to_owned(),
+        sink: config.db.clone(),
+        steps: plans,
+    })
+}
+
+fn write_plan(writer: &mut impl Write, plan: &FlowPlan) -> io::Result<()> {
+    writeln!(writer, "Flow: {}", plan.flow)?;
+    writeln!(writer, "Sink: {}", plan.sink)?;
+    for step in &plan.steps {
+        writeln!(writer)?;
+        writeln!(writer, "{} {} ({})", step.index, step.name, step.kind)?;
+        if step.in_process {
+            writeln!(writer, "  Runs in the flow process")?;
+        } else {
+            let mut command = vec![step.program.as_str()];
+            command.extend(step.args.iter().map(|s| s.as_str()));
+            writeln!(writer, "  Command: {}", shell_words::join(command))?;
+        }
+        if !step.current_labels.is_empty() {
+            let ids: Vec<&str> = step.current_labels.iter().map(|l| l.id.as_str()).collect();
+            writeln!(writer, "  Labels: {}", ids.join(", "))?;
+        }
+        if let Some(port) = step.port {
+            writeln!(writer, "  Port: {}", port)?;
+        }
+        if step.interactive {
+            writeln!(writer, "  Interactive: yes")?;
+        }
+        for (k, v) in &step.env {
+            writeln!(writer, "  {}: {}", k, v)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the plan of a flow, in JSON or human-readable form
+pub fn run(
+    flow_name: &str,
+    flow: &Flow,
+    config: &Config,
+    options: &DryRunOptions,
+    resolver: Option<Resolver>,
+) -> Result<()> {
+    let plan = make_plan(flow_name, flow, config, options, resolver)?;
+    let mut stdout = io::stdout();
+    if options.json {
+        serde_json::to_writer_pretty(&mut stdout, &plan)
+            .with_context(|| "Failed to serialize plan")?;
+        writeln!(stdout).with_context(|| "Failed to write to stdout")?;
+    } else {
+        write_plan(&mut stdout, &plan).with_context(|| "Failed to write to stdout")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Steps run in their own process groups, so that a step and the
+//! processes that it starts can be stopped together.
+
+use std::io;
+use std::process::{Child, Command};
+
+#[cfg(unix)]
+mod groups {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::Once;
+
+    const SIGNALS: [libc::c_int; 3] = [libc::SIGHUP, libc::SIGINT, libc::SIGTERM];
+
+    // The process groups of running steps. The signal handler reads
+    // them, so they are kept in atomics rather than behind a lock.
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicI32 = AtomicI32::new(0);
+    static GROUPS: [AtomicI32; 256] = [EMPTY; 256];
+
+    /// Pass a signal that stops the flow on to the steps, which no
+    /// longer receive signals sent to the terminal's process group
+    extern "C" fn forward(signal: libc::c_int) {
+        for slot in &GROUPS {
+            let pgid = slot.load(Ordering::SeqCst);
+            if pgid > 0 {
+                unsafe { libc::kill(-pgid, signal) };
+            }
+        }
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    fn install_handlers() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            for signal in SIGNALS {
+                let handler = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                let previous = unsafe { libc::signal(signal, handler) };
+                // Leave signals that were ignored, as under nohup,
+                // ignored
+                if previous == libc::SIG_IGN {
+                    unsafe { libc::signal(signal, libc::SIG_IGN) };
+                }
+            }
+        });
+    }
+
+    pub fn add(pgid: i32) {
+        install_handlers();
+        for slot in &GROUPS {
+            if slot
+                .compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    pub fn remove(pgid: i32) {
+        for slot in &GROUPS {
+            let _ = slot.compare_exchange(pgid, 0, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Start a command in a new process group
+#[cfg(unix)]
+pub fn configure(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+pub fn configure(_cmd: &mut Command) {}
+
+/// Remember the process group of a step started with configure, so
+/// that signals that stop the flow are passed on to it
+#[cfg(unix)]
+pub fn register(child: &Child) {
+    groups::add(child.id() as i32);
+}
+
+#[cfg(not(unix))]
+pub fn register(_child: &Child) {}
+
+/// Forget the process group of a step that has exited
+#[cfg(unix)]
+pub fn unregister(child: &Child) {
+    groups::remove(child.id() as i32);
+}
+
+#[cfg(not(unix))]
+pub fn unregister(_child: &Child) {}
+
+/// Kill a step started with configure and every process in its group
+#[cfg(unix)]
+pub fn kill(child: &mut Child) -> io::Result<()> {
+    let pgid = child.id() as i32;
+    groups::remove(pgid);
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        // The group is already gone
+        e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
@@ -0,0 +1,407 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+
+use crate::Sandbox;
+
+/// Directories that most programs need to read in order to run
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SYSTEM_DIRS: &[&str] = &[
+    "/bin",
+    "/etc",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/nix/store",
+    "/opt",
+    "/proc",
+    "/run/current-system",
+    "/sbin",
+    "/usr",
+];
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod linux {
+    use std::ffi::CString;
+    use std::fs;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::Command;
+
+    use anyhow::{Context, Error, Result};
+
+    use super::SYSTEM_DIRS;
+    use crate::Sandbox;
+
+    const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const LANDLOCK_RULE_NET_PORT: libc::c_int = 2;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+    const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+    // The rights that apply to files rather than directories
+    const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE
+        | ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_READ_FILE
+        | ACCESS_FS_TRUNCATE
+        | ACCESS_FS_IOCTL_DEV;
+    const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+    const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+    const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+        handled_access_net: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    #[repr(C)]
+    struct NetPortAttr {
+        allowed_access: u64,
+        port: u64,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    // Syscalls with this bit set use the x32 ABI, which would
+    // otherwise get around the filter
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// Syscalls that sandboxed steps have no use for, and that could
+    /// be used to get out of the sandbox or to read other processes
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_add_key,
+        libc::SYS_bpf,
+        libc::SYS_chroot,
+        libc::SYS_delete_module,
+        libc::SYS_finit_module,
+        libc::SYS_init_module,
+        libc::SYS_io_uring_setup,
+        libc::SYS_kexec_load,
+        libc::SYS_keyctl,
+        libc::SYS_mount,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_perf_event_open,
+        libc::SYS_pivot_root,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_ptrace,
+        libc::SYS_reboot,
+        libc::SYS_request_key,
+        libc::SYS_setns,
+        libc::SYS_swapoff,
+        libc::SYS_swapon,
+        libc::SYS_umount2,
+        libc::SYS_unshare,
+        libc::SYS_userfaultfd,
+    ];
+
+    fn landlock_abi() -> Result<i64> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(Error::msg(format!(
+                "Step sandboxes require Landlock, which is not available ({})",
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(abi)
+    }
+
+    fn handled_access_fs(abi: i64) -> u64 {
+        match abi {
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            3 | 4 => (1 << 15) - 1,
+            _ => (1 << 16) - 1,
+        }
+    }
+
+    fn create_ruleset(abi: i64, network: bool) -> Result<OwnedFd> {
+        let attr = RulesetAttr {
+            handled_access_fs: handled_access_fs(abi),
+            handled_access_net: if network {
+                0
+            } else {
+                ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP
+            },
+        };
+        // Older kernels do not know about handled_access_net
+        let size = if abi < 4 {
+            std::mem::size_of::<u64>()
+        } else {
+            std::mem::size_of::<RulesetAttr>()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                size,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| "Failed to create Landlock ruleset");
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+    }
+
+    fn add_path_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("Invalid path: {:?}", path))?;
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to open sandbox path: {:?}", path));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let access = if path.is_dir() {
+            access
+        } else {
+            access & ACCESS_FS_FILE
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to add sandbox rule for {:?}", path));
+        }
+        Ok(())
+    }
+
+    fn add_port_rule(ruleset: &OwnedFd, port: u16) -> Result<()> {
+        let attr = NetPortAttr {
+            allowed_access: ACCESS_NET_CONNECT_TCP,
+            port: port as u64,
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_NET_PORT,
+                &attr as *const NetPortAttr,
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to add sandbox rule for port {}", port));
+        }
+        Ok(())
+    }
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Build a seccomp filter that denies DENIED_SYSCALLS, and that
+    /// only allows Unix and TCP sockets when the network is not
+    /// allowed. TCP connections are limited by Landlock.
+    fn seccomp_filter(network: bool) -> Vec<libc::sock_filter> {
+        use libc::{
+            BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+            SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO,
+        };
+
+        let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        // Offsets into struct seccomp_data
+        let nr = 0;
+        let arch = 4;
+        let arg0 = 16;
+        let arg1 = 24;
+
+        let mut filter = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, arch),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD | BPF_W | BPF_ABS, nr),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            filter.push(jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1));
+            filter.push(stmt(BPF_RET | BPF_K, deny));
+        }
+        for syscall in DENIED_SYSCALLS {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *syscall as u32, 0, 1));
+            filter.push(stmt(BPF_RET | BPF_K, deny));
+        }
+        if !network {
+            filter.extend([
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_socket as u32, 1, 0),
+                stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+                stmt(BPF_LD | BPF_W | BPF_ABS, arg0),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::AF_UNIX as u32, 0, 1),
+                stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::AF_INET as u32, 1, 0),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::AF_INET6 as u32, 0, 4),
+                // Mask out SOCK_NONBLOCK and SOCK_CLOEXEC
+                stmt(BPF_LD | BPF_W | BPF_ABS, arg1),
+                stmt(BPF_ALU | BPF_AND | BPF_K, 0xf),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SOCK_STREAM as u32, 0, 1),
+                stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+                stmt(BPF_RET | BPF_K, deny),
+            ]);
+        } else {
+            filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        }
+        filter
+    }
+
+    pub fn apply(cmd: &mut Command, sandbox: &Sandbox, dir: &Path, ports: &[u16]) -> Result<()> {
+        let abi = landlock_abi()?;
+        if !sandbox.network && abi < 4 {
+            return Err(Error::msg(format!(
+                "Landlock version {} cannot limit network access. Set \"network: true\" in the step's sandbox to run it anyway.",
+                abi
+            )));
+        }
+        let handled = handled_access_fs(abi);
+        let ruleset = create_ruleset(abi, sandbox.network)?;
+
+        for s in SYSTEM_DIRS {
+            let path = Path::new(s);
+            if path.exists() {
+                add_path_rule(&ruleset, path, ACCESS_FS_READ)?;
+            }
+        }
+        // For /dev/null, /dev/urandom, and the terminal
+        add_path_rule(
+            &ruleset,
+            Path::new("/dev"),
+            (ACCESS_FS_READ_FILE
+                | ACCESS_FS_READ_DIR
+                | ACCESS_FS_WRITE_FILE
+                | ACCESS_FS_TRUNCATE
+                | ACCESS_FS_IOCTL_DEV)
+                & handled,
+        )?;
+        // Embedded steps run the sr executable
+        let exe = crate::flow::get_exe_path()?;
+        add_path_rule(&ruleset, &exe, ACCESS_FS_READ)?;
+        add_path_rule(&ruleset, dir, handled)?;
+        for s in &sandbox.read {
+            let path = fs::canonicalize(s)
+                .with_context(|| format!("Sandbox path does not exist: {}", s))?;
+            add_path_rule(&ruleset, &path, ACCESS_FS_READ)?;
+        }
+        for s in &sandbox.write {
+            let path = fs::canonicalize(s)
+                .with_context(|| format!("Sandbox path does not exist: {}", s))?;
+            add_path_rule(&ruleset, &path, handled)?;
+        }
+        if !sandbox.network {
+            for port in ports {
+                add_port_rule(&ruleset, *port)?;
+            }
+        }
+
+        let mut filter = seccomp_filter(sandbox.network);
+        // Safety: the closure only makes syscalls. The ruleset and
+        // filter are created before the process is forked.
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let prog = libc::sock_fprog {
+                    len: filter.len() as u16,
+                    filter: filter.as_mut_ptr(),
+                };
+                if libc::syscall(
+                    libc::SYS_seccomp,
+                    libc::SECCOMP_SET_MODE_FILTER,
+                    0,
+                    &prog as *const libc::sock_fprog,
+                ) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Limit the files and network that a step can use, before it is
+/// spawned. The step can read system directories and the paths in
+/// sandbox.read, and can write to dir and the paths in
+/// sandbox.write. Unless the network is allowed, the step can only
+/// connect to the given ports.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn apply(cmd: &mut Command, sandbox: &Sandbox, dir: &Path, ports: &[u16]) -> Result<()> {
+    linux::apply(cmd, sandbox, dir, ports)
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn apply(_cmd: &mut Command, _sandbox: &Sandbox, _dir: &Path, _ports: &[u16]) -> Result<()> {
+    Err(anyhow::Error::msg(
+        "Step sandboxes are only supported on Linux on x86_64 and aarch64",
+    ))
+}
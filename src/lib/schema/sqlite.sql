@@ -26,9 +26,27 @@ CREATE INDEX IF NOT EXISTS idx_srvc_event_label_answer_reviewer
   ON srvc_event (data->>'$.reviewer')
   WHERE type = 'label-answer';
 
+CREATE INDEX IF NOT EXISTS idx_srvc_event_label_answer_supersedes
+  ON srvc_event (data->>'$.supersedes')
+  WHERE type = 'label-answer';
+
+CREATE INDEX IF NOT EXISTS idx_srvc_event_label_answer_retraction_event
+  ON srvc_event (data->>'$.event')
+  WHERE type = 'label-answer-retraction';
+
+CREATE INDEX IF NOT EXISTS idx_srvc_event_redaction_event
+  ON srvc_event (data->>'$.event')
+  WHERE type = 'redaction';
+
+-- Tombstones of redacted events have no data to check. Older sinks
+-- have versions of these triggers that also check tombstones.
+DROP TRIGGER IF EXISTS srvc_event_label_answer_event_constraint;
+DROP TRIGGER IF EXISTS srvc_event_label_answer_label_constraint;
+DROP TRIGGER IF EXISTS srvc_event_label_answer_retraction_constraint;
+
 CREATE TRIGGER IF NOT EXISTS srvc_event_label_answer_event_constraint
 AFTER INSERT ON srvc_event
-WHEN NEW.type = 'label-answer'
+WHEN NEW.type = 'label-answer' AND NEW.extra->>'$.redacted' IS NULL
 BEGIN
   SELECT RAISE(ABORT, 'Missing event for label-answer')
   WHERE NOT EXISTS (
@@ -39,7 +57,7 @@ END;
 
 CREATE TRIGGER IF NOT EXISTS srvc_event_label_answer_label_constraint
 AFTER INSERT ON srvc_event
-WHEN NEW.type = 'label-answer'
+WHEN NEW.type = 'label-answer' AND NEW.extra->>'$.redacted' IS NULL
 BEGIN
   SELECT RAISE(ABORT, 'Missing label for label-answer')
   WHERE NOT EXISTS (
@@ -48,4 +66,30 @@ BEGIN
   );
 END;
 
+CREATE TRIGGER IF NOT EXISTS srvc_event_label_answer_supersedes_constraint
+AFTER INSERT ON srvc_event
+WHEN NEW.type = 'label-answer' AND NEW.data->>'$.supersedes' IS NOT NULL
+BEGIN
+  SELECT RAISE(ABORT, 'Superseded label-answer must exist and have the same event, label, and reviewer')
+  WHERE NOT EXISTS (
+    SELECT 1 FROM srvc_event
+    WHERE hash = NEW.data->>'$.supersedes' AND type = 'label-answer'
+      AND data->>'$.event' = NEW.data->>'$.event'
+      AND data->>'$.label' = NEW.data->>'$.label'
+      AND data->>'$.reviewer' = NEW.data->>'$.reviewer'
+  );
+END;
+
+CREATE TRIGGER IF NOT EXISTS srvc_event_label_answer_retraction_constraint
+AFTER INSERT ON srvc_event
+WHEN NEW.type = 'label-answer-retraction' AND NEW.extra->>'$.redacted' IS NULL
+BEGIN
+  SELECT RAISE(ABORT, 'Missing label-answer from the same reviewer for label-answer-retraction')
+  WHERE NOT EXISTS (
+    SELECT 1 FROM srvc_event
+    WHERE hash = NEW.data->>'$.event' AND type = 'label-answer'
+      AND data->>'$.reviewer' = NEW.data->>'$.reviewer'
+  );
+END;
+
 COMMIT;
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_with::skip_serializing_none;
+
+/// The file that secrets are read from when they are not set in the
+/// environment
+pub const DOTENV_FILE: &str = ".env";
+
+const REDACTED: &str = "<redacted>";
+
+lazy_static! {
+    // Every secret value that has been read, so that it can be
+    // removed from output
+    static ref VALUES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+/// The value of a secret. It is never serialized or printed.
+#[derive(Clone, Eq, PartialEq)]
+pub struct SecretValue(String);
+
+impl SecretValue {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+impl Serialize for SecretValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Where the value of a secret comes from. A secret with no source is
+/// read from the environment variable with the same name as the
+/// secret, or else from the .env file.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Secret {
+    // A key in the .env file
+    pub dotenv: Option<String>,
+    // An environment variable
+    pub env: Option<String>,
+    // A file that holds only the secret
+    pub file: Option<String>,
+    #[serde(skip_deserializing)]
+    pub value: Option<SecretValue>,
+}
+
+impl Secret {
+    /// Describe where the secret is read from, for error messages
+    pub fn describe_source(&self, name: &str) -> String {
+        let mut sources = Vec::new();
+        if let Some(var) = &self.env {
+            sources.push(format!("the {} environment variable", var));
+        }
+        if let Some(path) = &self.file {
+            sources.push(format!("the file {}", path));
+        }
+        if let Some(key) = &self.dotenv {
+            sources.push(format!("{} in {}", key, DOTENV_FILE));
+        }
+        if sources.is_empty() {
+            sources.push(format!("the {} environment variable", name));
+            sources.push(format!("{} in {}", name, DOTENV_FILE));
+        }
+        sources.join(" or ")
+    }
+
+    /// The environment variables that the secret may be read from.
+    /// These are removed from the environment of steps that do not
+    /// ask for the secret.
+    pub fn env_vars<'a>(&'a self, name: &'a str) -> Vec<&'a str> {
+        let mut vars = vec![name];
+        if let Some(var) = &self.env {
+            vars.push(var);
+        }
+        vars
+    }
+}
+
+/// Parse the contents of a .env file. Blank lines and lines starting
+/// with # are ignored, and values may be quoted.
+pub fn parse_dotenv(s: &str) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    for line in s.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        if let Some((k, v)) = line.split_once('=') {
+            let v = v.trim();
+            let v = if v.len() >= 2
+                && ((v.starts_with('"') && v.ends_with('"'))
+                    || (v.starts_with('\'') && v.ends_with('\'')))
+            {
+                &v[1..v.len() - 1]
+            } else {
+                v
+            };
+            vars.insert(k.trim().to_string(), v.to_string());
+        }
+    }
+    vars
+}
+
+fn read_dotenv(path: &Path) -> Result<HashMap<String, String>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(parse_dotenv(&s)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.to_string_lossy())),
+    }
+}
+
+fn read_file(path: &str) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s.trim_end_matches(&['\r', '\n'][..]).to_string())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read secret file: {}", path)),
+    }
+}
+
+/// Read the value of each secret. Secrets that are not set have no
+/// value, which is only an error if a step asks for them.
+pub fn resolve(secrets: &mut BTreeMap<String, Secret>) -> Result<()> {
+    let mut dotenv = None;
+    for (name, secret) in secrets.iter_mut() {
+        let mut value = secret.env.as_ref().and_then(|var| env::var(var).ok());
+        if value.is_none() {
+            if let Some(path) = &secret.file {
+                value = read_file(path)?;
+            }
+        }
+        let no_source = secret.env.is_none() && secret.file.is_none() && secret.dotenv.is_none();
+        if value.is_none() && no_source {
+            value = env::var(name).ok();
+        }
+        if value.is_none() && (no_source || secret.dotenv.is_some()) {
+            if dotenv.is_none() {
+                dotenv = Some(read_dotenv(Path::new(DOTENV_FILE))?);
+            }
+            let key = secret.dotenv.as_deref().unwrap_or(name);
+            value = dotenv.as_ref().and_then(|vars| vars.get(key).cloned());
+        }
+        secret.value = value.filter(|s| !s.is_empty()).map(|s| {
+            register(&s);
+            SecretValue(s)
+        });
+    }
+    Ok(())
+}
+
+/// Return the value of a secret that a step asks for
+pub fn value<'a>(secrets: &'a BTreeMap<String, Secret>, name: &str) -> Result<Option<&'a str>> {
+    match secrets.get(name) {
+        Some(secret) => match &secret.value {
+            Some(value) => Ok(Some(value.expose())),
+            None => Err(Error::msg(format!(
+                "Secret \"{}\" is not set. Set {}.",
+                name,
+                secret.describe_source(name)
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Remember a secret value so that it is removed from output
+pub fn register(value: &str) {
+    let mut values = VALUES.lock().unwrap();
+    if !value.is_empty() && !values.iter().any(|v| v == value) {
+        values.push(value.to_string());
+    }
+}
+
+/// Replace every known secret value in a string
+pub fn redact(s: &str) -> String {
+    let values = VALUES.lock().unwrap();
+    let mut s = s.to_string();
+    for value in values.iter() {
+        if s.contains(value.as_str()) {
+            s = s.replace(value.as_str(), REDACTED);
+        }
+    }
+    s
+}
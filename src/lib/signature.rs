@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+pub use ed25519_dalek::SigningKey;
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::event::{self, Event, HashAlgorithm};
+use crate::Config;
+
+/// The environment variable that overrides the path of the signing key
+pub const KEY_FILE_VAR: &str = "SR_SIGNING_KEY_FILE";
+
+const KEY_PREFIX: &str = "ed25519:";
+
+/// The property in a label-answer's extra that holds its signature
+pub const SIGNATURE_PROPERTY: &str = "signature";
+
+/// The public keys that a reviewer signs label-answers with
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Reviewer {
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/// The signature of a label-answer, stored in its extra
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct EventSignature {
+    key: String,
+    value: String,
+}
+
+/// The default path of the signing key. This is srvc/signing-key in
+/// the user's config directory.
+fn default_key_file() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    config_dir.map(|dir| dir.join("srvc").join("signing-key"))
+}
+
+/// The path of the signing key, from SR_SIGNING_KEY_FILE if it is set.
+/// None if SR_SIGNING_KEY_FILE is empty.
+pub fn key_file() -> Option<PathBuf> {
+    match env::var_os(KEY_FILE_VAR) {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => default_key_file(),
+    }
+}
+
+pub fn public_key_string(key: &VerifyingKey) -> String {
+    format!(
+        "{}{}",
+        KEY_PREFIX,
+        bs58::encode(key.to_bytes()).into_string()
+    )
+}
+
+pub fn parse_public_key(s: &str) -> Result<VerifyingKey> {
+    let invalid = || Error::msg(format!("Invalid public key: \"{}\"", s));
+    let encoded = s.strip_prefix(KEY_PREFIX).ok_or_else(invalid)?;
+    let bytes: [u8; 32] = bs58::decode(encoded)
+        .into_vec()
+        .ok()
+        .and_then(|v| v.try_into().ok())
+        .ok_or_else(invalid)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())
+}
+
+/// Generate a signing key and write it to path. An existing key is
+/// never overwritten.
+pub fn generate_key(path: &Path) -> Result<SigningKey> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory: {:?}", dir))?;
+    }
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create signing key file: {:?}", path))?;
+    writeln!(file, "{}", bs58::encode(key.to_bytes()).into_string())
+        .with_context(|| format!("Failed to write signing key file: {:?}", path))?;
+    Ok(key)
+}
+
+pub fn read_key(path: &Path) -> Result<SigningKey> {
+    let s = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read signing key file: {:?}", path))?;
+    let bytes: [u8; 32] = bs58::decode(s.trim())
+        .into_vec()
+        .ok()
+        .and_then(|v| v.try_into().ok())
+        .ok_or_else(|| Error::msg(format!("Invalid signing key file: {:?}", path)))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// The key that new label-answers are signed with, or None if signing
+/// is not enabled. Signing is enabled by "sign: true" in the config, or
+/// by setting SR_SIGNING_KEY_FILE, unless the config has "sign: false".
+pub fn signing_key(config: &Config) -> Result<Option<SigningKey>> {
+    let explicit = env::var_os(KEY_FILE_VAR).is_some_and(|path| !path.is_empty());
+    if !config.sign.unwrap_or(explicit) {
+        return Ok(None);
+    }
+    match key_file() {
+        Some(path) if path.exists() => Ok(Some(read_key(&path)?)),
+        Some(path) => Err(Error::msg(format!(
+            "Signing is enabled, but there is no signing key file at {:?}. Create one with \"sr keys generate\".",
+            path
+        ))),
+        None => Err(Error::msg(format!(
+            "Signing is enabled, but there is no signing key file. Set {}.",
+            KEY_FILE_VAR
+        ))),
+    }
+}
+
+/// The hash that an event would have without its signature
+fn unsigned_hash(event: &Event, algorithm: HashAlgorithm) -> Result<String> {
+    let mut unsigned = Event {
+        data: event.data.clone(),
+        extra: event.extra.clone(),
+        hash: None,
+        r#type: event.r#type.clone(),
+        uri: event.uri.clone(),
+    };
+    unsigned.extra.remove(SIGNATURE_PROPERTY);
+    event::hash_event(&unsigned, algorithm)
+}
+
+pub fn is_signed(event: &Event) -> bool {
+    event.extra.contains_key(SIGNATURE_PROPERTY)
+}
+
+/// Sign an event and set its hash. The signature covers the hash
+/// that the event would have without the signature, and is added to
+/// the event's extra, so the event's hash changes.
+pub fn sign_event(event: &mut Event, key: &SigningKey, algorithm: HashAlgorithm) -> Result<()> {
+    let hash = unsigned_hash(event, algorithm)?;
+    let signature = key.sign(hash.as_bytes());
+    let signature = EventSignature {
+        key: public_key_string(&key.verifying_key()),
+        value: bs58::encode(signature.to_bytes()).into_string(),
+    };
+    event
+        .extra
+        .insert(String::from(SIGNATURE_PROPERTY), json!(signature));
+    event.hash = Some(event::hash_event(event, algorithm)?);
+    Ok(())
+}
+
+/// Check the signature of an event with a hash. Returns the public
+/// key that signed the event, or None if it has no signature.
+pub fn verify_event(event: &Event) -> Result<Option<String>> {
+    let value = match event.extra.get(SIGNATURE_PROPERTY) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let hash = event.hash.as_deref().expect("hash");
+    let signature: EventSignature = serde_json::from_value(value.clone())
+        .with_context(|| format!("Invalid signature in event {}", hash))?;
+    let key = parse_public_key(&signature.key)
+        .with_context(|| format!("Invalid signature in event {}", hash))?;
+    let bytes = bs58::decode(&signature.value)
+        .into_vec()
+        .unwrap_or_default();
+    let valid = match Signature::from_slice(&bytes) {
+        Ok(sig) => {
+            let algorithm = HashAlgorithm::of_hash(hash).unwrap_or_default();
+            let unsigned = unsigned_hash(event, algorithm)?;
+            key.verify_strict(unsigned.as_bytes(), &sig).is_ok()
+        }
+        Err(_) => false,
+    };
+    if valid {
+        Ok(Some(signature.key))
+    } else {
+        Err(Error::msg(format!("Invalid signature in event {}", hash)))
+    }
+}
+
+/// Checks the signatures of label-answers against the keys in the
+/// reviewers section of sr.yaml
+#[derive(Debug, Default)]
+pub struct SignatureChecker {
+    keys: HashMap<String, HashSet<String>>,
+    require: bool,
+}
+
+impl SignatureChecker {
+    pub fn new(config: &Config) -> SignatureChecker {
+        let keys = config
+            .reviewers
+            .iter()
+            .map(|(id, reviewer)| (id.to_owned(), reviewer.keys.iter().cloned().collect()))
+            .collect();
+        SignatureChecker {
+            keys,
+            require: config.require_signatures.unwrap_or(false),
+        }
+    }
+
+    /// Check an event with a hash. A signed label-answer must have a
+    /// valid signature, and if its reviewer is listed, the signature
+    /// must use one of the reviewer's keys. If signatures are
+    /// required, every label-answer must be signed by a listed key.
+    pub fn check(&self, event: &Event) -> Result<()> {
+        if event.r#type != "label-answer" {
+            return Ok(());
+        }
+        let hash = event.hash.as_deref().expect("hash");
+        let reviewer = event
+            .data
+            .as_ref()
+            .and_then(|data| data.get("reviewer"))
+            .and_then(|reviewer| reviewer.as_str())
+            .unwrap_or_default();
+        let key = match verify_event(event)? {
+            Some(key) => key,
+            None if self.require => {
+                return Err(Error::msg(format!("label-answer {} is not signed", hash)))
+            }
+            None => return Ok(()),
+        };
+        match self.keys.get(reviewer) {
+            Some(keys) if keys.contains(&key) => Ok(()),
+            Some(_) => Err(Error::msg(format!(
+                "label-answer {} is signed with a key that is not listed for reviewer \"{}\"",
+                hash, reviewer
+            ))),
+            None if self.require => Err(Error::msg(format!(
+                "label-answer {} is from reviewer \"{}\", who is not listed in reviewers",
+                hash, reviewer
+            ))),
+            None => Ok(()),
+        }
+    }
+}
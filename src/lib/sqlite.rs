@@ -4,9 +4,9 @@ use std::path::Path;
 use anyhow::{Context, Error, Result};
 use log::trace;
 use rusqlite::{params, CachedStatement, Connection, OpenFlags, Row};
-use serde_json::Value;
+use serde_json::{json, Value};
 
-use crate::event::Event;
+use crate::event::{self, Event};
 
 fn load_schema(conn: &Connection) -> Result<()> {
     conn.execute_batch(include_str!("schema/sqlite.sql"))
@@ -92,10 +92,8 @@ pub fn close(conn: Connection) -> Result<()> {
 pub fn insert_event(conn: &Connection, event: Event) -> Result<usize> {
     let hash = event.hash.clone().expect("Hash not set");
     trace! {"Inserting event: {}", hash};
-    let data = event
-        .data
-        .map(|v| serde_json::to_string(&v))
-        .transpose()
+    // Events without data, such as tombstones, store JSON null
+    let data = serde_json::to_string(&event.data)
         .with_context(|| format!("Failed to serialize data property of event: {}", hash))?;
     let extra = if event.extra.is_empty() {
         String::from("{}")
@@ -111,7 +109,7 @@ pub fn insert_event(conn: &Connection, event: Event) -> Result<usize> {
     };
     match conn.execute(
         "INSERT INTO srvc_event (hash, data, extra, type, uri) VALUES (?, ?, ?, ?, ?) ON CONFLICT (hash) DO NOTHING",
-        [event.hash, data, Some(extra), Some(event.r#type), event.uri],
+        [event.hash, Some(data), Some(extra), Some(event.r#type), event.uri],
     ) {
         Ok(rows) => {
             trace!("Modified {} rows", rows);
@@ -121,6 +119,48 @@ pub fn insert_event(conn: &Connection, event: Event) -> Result<usize> {
     }
 }
 
+/// The hash of a redaction of the event with the given hash, if there
+/// is one
+pub fn redaction_for(conn: &Connection, hash: &str) -> Result<Option<String>> {
+    let mut stmt = prepare_cached(
+        conn,
+        "SELECT hash FROM srvc_event WHERE type = 'redaction' AND data->>'$.event' = ? ORDER BY hash LIMIT 1",
+    )?;
+    let mut rows = stmt
+        .query([hash])
+        .with_context(|| "Failed to execute query")?;
+    match rows.next().with_context(|| "Failed to retrieve rows")? {
+        Some(row) => Ok(Some(row.get(0).with_context(|| "Failed to get hash")?)),
+        None => Ok(None),
+    }
+}
+
+/// The hashes of the redactions in the sink and of the events that
+/// they redact
+pub fn redactions(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = prepare_cached(
+        conn,
+        "SELECT hash, data->>'$.event' FROM srvc_event WHERE type = 'redaction'",
+    )?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .with_context(|| "Failed to execute query")?;
+    rows.collect::<rusqlite::Result<_>>()
+        .with_context(|| "Failed to retrieve rows")
+}
+
+/// Replace an event with its tombstone. Returns the number of rows
+/// modified, which is 0 if the event is not in the sink.
+pub fn redact_event(conn: &Connection, hash: &str, redaction_hash: &str) -> Result<usize> {
+    trace! {"Redacting event: {}", hash};
+    let extra = json!({ event::REDACTED_PROPERTY: redaction_hash }).to_string();
+    conn.execute(
+        "UPDATE srvc_event SET data = 'null', extra = ?, uri = NULL WHERE hash = ?",
+        [extra.as_str(), hash],
+    )
+    .with_context(|| format!("Error redacting event: {}", hash))
+}
+
 fn value_to_map(value: &Value) -> Option<HashMap<String, Value>> {
     if let Some(map) = value.as_object() {
         let mut new_map = HashMap::new();
@@ -139,8 +179,9 @@ pub fn parse_event_rusqlite(row: &Row) -> rusqlite::Result<Event> {
         .and_then(|v| value_to_map(&v))
         .unwrap_or_else(HashMap::new);
     let extra: BTreeMap<String, Value> = extra_hashmap.into_iter().collect();
+    let data: Option<Value> = row.get(0)?;
     Ok(Event {
-        data: row.get(0)?,
+        data: data.filter(|v| !v.is_null()),
         extra,
         hash: row.get(2)?,
         r#type: row.get(3)?,
@@ -14,23 +14,62 @@ use serde_with::skip_serializing_none;
 use url::Url;
 
 use crate as lib_sr;
-use crate::{event, json_schema};
+use crate::event::HashAlgorithm;
+use crate::secrets::{self, Secret};
+use crate::signature::{self, Reviewer};
+use crate::step_manifest::{self, StepManifest};
+use crate::{common, event, json_schema};
 
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub struct Step {
+    pub cache: Option<bool>,
     pub env: Option<Vec<String>>,
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
+    #[serde(alias = "idle-timeout", rename(serialize = "idle-timeout"))]
+    pub idle_timeout: Option<serde_json::Value>,
     pub labels: Option<Vec<String>>,
+    pub limits: Option<Limits>,
+    // Inline, or a URL or path to a manifest file
+    pub manifest: Option<serde_json::Value>,
+    pub retry: Option<Retry>,
     pub run: Option<String>,
     #[serde(alias = "run-embedded", rename(serialize = "run-embedded"))]
     pub run_embedded: Option<String>,
+    // true, or the paths and network access to allow
+    pub sandbox: Option<serde_json::Value>,
+    pub timeout: Option<serde_json::Value>,
     pub uses: Option<String>,
     #[serde(alias = "url")]
     uri: Option<String>,
 }
 
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Limits {
+    #[serde(alias = "cpu-seconds", rename(serialize = "cpu-seconds"))]
+    pub cpu_seconds: Option<u64>,
+    pub memory: Option<serde_json::Value>,
+    #[serde(alias = "open-files", rename(serialize = "open-files"))]
+    pub open_files: Option<u64>,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Retry {
+    pub backoff: Option<serde_json::Value>,
+    pub max: Option<u32>,
+}
+
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Sandbox {
+    pub network: Option<bool>,
+    pub read: Option<Vec<String>>,
+    pub write: Option<Vec<String>>,
+}
+
 #[skip_serializing_none]
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub struct Flow {
@@ -84,8 +123,15 @@ pub struct Config {
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
     pub flows: Option<BTreeMap<String, Flow>>,
+    #[serde(alias = "hash_algorithm", rename = "hash-algorithm")]
+    pub hash_algorithm: Option<HashAlgorithm>,
     pub labels: Option<BTreeMap<String, Label>>,
+    #[serde(alias = "require_signatures", rename = "require-signatures")]
+    pub require_signatures: Option<bool>,
     pub reviewer: Option<String>,
+    pub reviewers: Option<BTreeMap<String, Reviewer>>,
+    pub secrets: Option<BTreeMap<String, Option<Secret>>>,
+    pub sign: Option<bool>,
     #[serde(
         alias = "sink-all-events",
         alias = "sink_all_events",
@@ -103,8 +149,13 @@ impl Config {
             db: other.db.or(self.db),
             extra: self.extra,
             flows: other.flows.or(self.flows),
+            hash_algorithm: other.hash_algorithm.or(self.hash_algorithm),
             labels: other.labels.or(self.labels),
+            require_signatures: other.require_signatures.or(self.require_signatures),
             reviewer: other.reviewer.or(self.reviewer),
+            reviewers: other.reviewers.or(self.reviewers),
+            secrets: other.secrets.or(self.secrets),
+            sign: other.sign.or(self.sign),
             sink_control_events: other.sink_control_events.or(self.sink_control_events),
             sources: other.sources.or(self.sources),
         }
@@ -162,6 +213,81 @@ pub fn get_object<T: DeserializeOwned>(client: &Client, url: &str) -> Result<T>
     }
 }
 
+/// Validate a duration given as a string like "2s" or as a number
+/// of seconds, and return it as a string.
+pub fn parse_duration_value(k: &str, v: &serde_json::Value) -> Result<String> {
+    let s = match v {
+        serde_json::Value::Number(n) => format!("{}s", n),
+        serde_json::Value::String(s) => s.to_owned(),
+        _ => return Err(Error::msg(format!("Invalid {}: {}", k, v))),
+    };
+    common::parse_duration(&s).with_context(|| format!("Invalid {}: {}", k, v))?;
+    Ok(s)
+}
+
+/// Parse a size given as a number of bytes or as a string like "512M"
+pub fn parse_size_value(k: &str, v: &serde_json::Value) -> Result<u64> {
+    let err = || Error::msg(format!("Invalid {}: {}", k, v));
+    match v {
+        serde_json::Value::Number(n) => n.as_u64().ok_or_else(err),
+        serde_json::Value::String(s) => {
+            let s = s.trim();
+            let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let (n, unit) = s.split_at(split);
+            let n = n.parse::<u64>().map_err(|_| err())?;
+            let multiplier: u64 = match unit.trim().to_uppercase().as_str() {
+                "" | "B" => 1,
+                "K" | "KB" => 1 << 10,
+                "M" | "MB" => 1 << 20,
+                "G" | "GB" => 1 << 30,
+                _ => return Err(err()),
+            };
+            n.checked_mul(multiplier).ok_or_else(err)
+        }
+        _ => Err(err()),
+    }
+}
+
+pub fn parse_limits(limits: Limits) -> Result<lib_sr::Limits> {
+    Ok(lib_sr::Limits {
+        cpu_seconds: limits.cpu_seconds,
+        memory: limits
+            .memory
+            .map(|v| parse_size_value("memory limit", &v))
+            .transpose()?,
+        open_files: limits.open_files,
+    })
+}
+
+pub fn parse_retry(retry: Retry) -> Result<lib_sr::Retry> {
+    Ok(lib_sr::Retry {
+        backoff: retry
+            .backoff
+            .map(|v| parse_duration_value("retry backoff", &v))
+            .transpose()?,
+        max: retry.max.unwrap_or(3),
+    })
+}
+
+/// Parse a sandbox given as a boolean or as the paths and network
+/// access to allow
+pub fn parse_sandbox_value(v: &serde_json::Value) -> Result<Option<lib_sr::Sandbox>> {
+    match v {
+        serde_json::Value::Bool(true) => Ok(Some(lib_sr::Sandbox::default())),
+        serde_json::Value::Bool(false) => Ok(None),
+        serde_json::Value::Object(_) => {
+            let sandbox: Sandbox = serde_json::from_value(v.to_owned())
+                .with_context(|| format!("Invalid sandbox: {}", v))?;
+            Ok(Some(lib_sr::Sandbox {
+                network: sandbox.network.unwrap_or(false),
+                read: sandbox.read.unwrap_or_default(),
+                write: sandbox.write.unwrap_or_default(),
+            }))
+        }
+        _ => Err(Error::msg(format!("Invalid sandbox: {}", v))),
+    }
+}
+
 pub fn parse_step_data(step: Step) -> Result<lib_sr::Step> {
     let run_embedded = match step.uses {
         Some(s) => {
@@ -171,23 +297,92 @@ pub fn parse_step_data(step: Step) -> Result<lib_sr::Step> {
         }
         None => step.run_embedded,
     };
+    let cache = step.cache.filter(|cache| *cache);
+    if cache.is_some() && step.retry.is_some() {
+        return Err(Error::msg("A step cannot set both \"cache\" and \"retry\""));
+    }
     Ok(lib_sr::Step {
+        cache,
         env: step.env,
         extra: step.extra,
+        idle_timeout: step
+            .idle_timeout
+            .map(|v| parse_duration_value("idle-timeout", &v))
+            .transpose()?,
         labels: step.labels.unwrap_or(Vec::new()),
+        limits: step.limits.map(parse_limits).transpose()?,
+        manifest: None,
+        retry: step.retry.map(parse_retry).transpose()?,
         run: step.run,
         run_embedded,
+        sandbox: step
+            .sandbox
+            .map(|v| parse_sandbox_value(&v))
+            .transpose()?
+            .flatten(),
+        timeout: step
+            .timeout
+            .map(|v| parse_duration_value("timeout", &v))
+            .transpose()?,
     })
 }
 
+/// Find the manifest of a step. A manifest can be given inline or as
+/// a URL or file path, or it can be in the directory of a local flake.
+/// Relative URLs are resolved against the URI of the step definition.
+pub fn parse_manifest(
+    client: &Client,
+    step: &Step,
+    base_uri: Option<&str>,
+) -> Result<Option<StepManifest>> {
+    match &step.manifest {
+        Some(serde_json::Value::String(s)) => {
+            let url = match base_uri {
+                Some(base) => Url::parse(base).and_then(|base| base.join(s)).ok(),
+                None => Url::parse(s).ok(),
+            };
+            match url {
+                Some(url) => get_object(client, url.as_str())
+                    .with_context(|| format!("Failed to get step manifest: {}", url)),
+                None => step_manifest::read(&PathBuf::from(s)),
+            }
+            .map(Some)
+        }
+        Some(v @ serde_json::Value::Object(_)) => serde_json::from_value(v.to_owned())
+            .with_context(|| "Failed to parse step manifest")
+            .map(Some),
+        Some(v) => Err(Error::msg(format!("Invalid step manifest: {}", v))),
+        None => {
+            let path = step
+                .uses
+                .as_deref()
+                .and_then(step_manifest::local_flake_dir)
+                .map(|dir| dir.join(step_manifest::FLAKE_MANIFEST_FILE));
+            match path {
+                Some(path) if path.exists() => step_manifest::read(&path).map(Some),
+                _ => Ok(None),
+            }
+        }
+    }
+}
+
 pub fn parse_step(client: &Client, step: Step) -> Result<lib_sr::Step> {
-    match &step.uri {
+    let (step, base_uri) = match &step.uri {
         Some(uri) => {
-            let stp: Step = get_object(client, uri)?;
-            parse_step_data(stp)
+            let mut stp: Step = get_object(client, uri)?;
+            // The project decides how much to trust a remote step
+            if step.sandbox.is_some() {
+                stp.sandbox = step.sandbox.clone();
+            }
+            (stp, Some(uri.to_owned()))
         }
-        None => parse_step_data(step),
-    }
+        None => (step, None),
+    };
+    let manifest = parse_manifest(client, &step, base_uri.as_deref())?;
+    let mut step = parse_step_data(step)?;
+    step.manifest = manifest;
+    step_manifest::validate_options(&step)?;
+    Ok(step)
 }
 
 pub fn parse_flow_data(client: &Client, flow: Flow) -> Result<lib_sr::Flow> {
@@ -234,6 +429,7 @@ pub fn parse_label_data(
     id: &str,
     label: &Label,
     json_schema: Option<serde_json::Value>,
+    hash_algorithm: HashAlgorithm,
 ) -> Result<lib_sr::Label> {
     let mut extra = label.extra.clone();
     extra.remove("hash");
@@ -255,7 +451,7 @@ pub fn parse_label_data(
         r#type: String::from("label"),
         uri: None,
     };
-    label.hash = Some(event::event_hash(event)?);
+    label.hash = Some(event::hash_event(&event, hash_algorithm)?);
     Ok(label)
 }
 
@@ -264,13 +460,14 @@ pub fn parse_label(
     id: &str,
     label: &Label,
     json_schema: Option<serde_json::Value>,
+    hash_algorithm: HashAlgorithm,
 ) -> Result<lib_sr::Label> {
     match &label.uri {
         Some(uri) => {
             let lbl: Label = get_object(client, uri)?;
-            parse_label_data(id, &lbl, json_schema)
+            parse_label_data(id, &lbl, json_schema, hash_algorithm)
         }
-        None => parse_label_data(id, label, json_schema),
+        None => parse_label_data(id, label, json_schema, hash_algorithm),
     }
 }
 
@@ -320,13 +517,14 @@ pub fn get_label_schema(
 pub fn parse_labels(
     client: &Client,
     labels: &Option<BTreeMap<String, Label>>,
+    hash_algorithm: HashAlgorithm,
 ) -> Result<BTreeMap<String, lib_sr::Label>> {
     match labels {
         Some(labels) => {
             let mut m = BTreeMap::new();
             for (id, label) in labels {
                 let json_schema = get_label_schema(&client, &label, id)?;
-                let parsed = parse_label(&client, &id, label, json_schema)?;
+                let parsed = parse_label(&client, &id, label, json_schema, hash_algorithm)?;
                 m.insert(id.to_owned(), parsed);
             }
             Ok(m)
@@ -344,11 +542,18 @@ pub fn parse_source(client: &Client, source: Source) -> Result<lib_sr::Source> {
                 None => source.uri.expect("uri"),
             };
             lib_sr::Step {
+                cache: None,
                 extra: BTreeMap::new(),
                 env: None,
+                idle_timeout: None,
                 labels: Vec::new(),
+                limits: None,
+                manifest: None,
+                retry: None,
                 run: None,
                 run_embedded: Some(format!("generator {}", s)),
+                sandbox: None,
+                timeout: None,
             }
         }
     };
@@ -398,6 +603,21 @@ pub fn parse_config(config: Config) -> Result<lib_sr::Config> {
         Some(reviewer) => validate_reviewer(&reviewer)?,
         None => (),
     };
+    let reviewers = config.reviewers.unwrap_or_default();
+    for (id, reviewer) in &reviewers {
+        for key in &reviewer.keys {
+            signature::parse_public_key(key)
+                .with_context(|| format!("Invalid key for reviewer \"{}\"", id))?;
+        }
+    }
+
+    let mut secrets: BTreeMap<String, Secret> = config
+        .secrets
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, secret)| (name, secret.unwrap_or_default()))
+        .collect();
+    secrets::resolve(&mut secrets)?;
 
     let mut extra = config.extra;
     extra.remove("current_labels");
@@ -410,8 +630,17 @@ pub fn parse_config(config: Config) -> Result<lib_sr::Config> {
         db: config.db.expect("config.db"),
         extra,
         flows: parse_flows(&client, config.flows)?,
-        labels: parse_labels(&client, &config.labels)?,
+        hash_algorithm: config.hash_algorithm,
+        labels: parse_labels(
+            &client,
+            &config.labels,
+            config.hash_algorithm.unwrap_or_default(),
+        )?,
+        require_signatures: config.require_signatures,
         reviewer: config.reviewer,
+        reviewers,
+        secrets,
+        sign: config.sign,
         sink_control_events: config.sink_control_events.unwrap_or(false),
         sources: parse_sources(&client, config.sources.unwrap_or(Vec::new()))?,
         srvc: lib_sr::Srvc {
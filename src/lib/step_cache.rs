@@ -0,0 +1,495 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, LineWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use multihash::MultihashDigest;
+use serde_json::json;
+use tempfile::TempPath;
+
+use crate::event::{self, Event};
+use crate::step_source::{self, SourceCache};
+use crate::{common, Label, Step};
+
+/// Where cached step output is stored, relative to the project
+/// directory
+pub const CACHE_DIR: &str = ".srvc/cache";
+
+fn hash_value(value: &serde_json::Value) -> Result<String> {
+    let bytes = serde_ipld_dagcbor::to_vec(value).with_context(|| "Failed to serialize value")?;
+    let hash = multihash::Code::Sha2_256.digest(&bytes);
+    Ok(bs58::encode(hash.to_bytes()).into_string())
+}
+
+/// The hashes of the events that identify the input of a step.
+/// Control events are left out because they vary between runs.
+fn input_hashes(events: &[Event]) -> Vec<&str> {
+    events
+        .iter()
+        .filter(|event| event.r#type != "control")
+        .map(|event| event.hash.as_deref().expect("hash"))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub bytes: u64,
+    pub entries: u64,
+}
+
+/// A content-addressed store of step output. Each entry holds the
+/// events that a step emitted for one group of input events.
+#[derive(Debug)]
+pub struct StepCache {
+    dir: PathBuf,
+}
+
+impl StepCache {
+    pub fn new(dir: PathBuf) -> StepCache {
+        StepCache { dir }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        // Every key starts with the same multihash prefix, so use the
+        // end of the key to spread entries across directories
+        self.dir
+            .join(&key[key.len().saturating_sub(2)..])
+            .join(format!("{}.jsonl", key))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<Event>>> {
+        let path = self.entry_path(key);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let events = event::events(BufReader::new(file))
+            .collect::<Result<Vec<Event>>>()
+            .with_context(|| format!("Failed to read cache entry: {:?}", path))?;
+        Ok(Some(events))
+    }
+
+    /// Create an empty temporary file in the cache directory that can
+    /// later be persisted as an entry
+    fn temp_entry(&self) -> Result<TempPath> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache directory: {:?}", self.dir))?;
+        let file = tempfile::NamedTempFile::new_in(&self.dir)
+            .with_context(|| "Failed to create cache entry")?;
+        Ok(file.into_temp_path())
+    }
+
+    /// Store a file written by temp_entry as the entry for key
+    fn persist(&self, key: &str, temp: TempPath) -> Result<()> {
+        let path = self.entry_path(key);
+        let dir = path.parent().expect("parent");
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache directory: {:?}", dir))?;
+        temp.persist(&path)
+            .with_context(|| format!("Failed to write cache entry: {:?}", path))?;
+        Ok(())
+    }
+
+    pub fn put(&self, key: &str, events: &[Event]) -> Result<()> {
+        let path = self.entry_path(key);
+        let dir = path.parent().expect("parent");
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache directory: {:?}", dir))?;
+        // Write to a temporary file first so that a partial entry is
+        // never read
+        let mut file =
+            tempfile::NamedTempFile::new_in(dir).with_context(|| "Failed to create cache entry")?;
+        {
+            let mut writer = BufWriter::new(&mut file);
+            for event in events {
+                serde_json::to_writer(&mut writer, event)
+                    .with_context(|| "Event serialization failed")?;
+                writer
+                    .write_all(b"\n")
+                    .with_context(|| "Failed to write cache entry")?;
+            }
+            writer
+                .flush()
+                .with_context(|| "Failed to write cache entry")?;
+        }
+        file.persist(&path)
+            .with_context(|| format!("Failed to write cache entry: {:?}", path))?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let mut stats = CacheStats::default();
+        let dirs = match fs::read_dir(&self.dir) {
+            Ok(dirs) => dirs,
+            Err(_) => return Ok(stats),
+        };
+        for dir in dirs {
+            let dir = dir.with_context(|| "Failed to read cache directory")?;
+            if !dir.path().is_dir() {
+                continue;
+            }
+            for entry in
+                fs::read_dir(dir.path()).with_context(|| "Failed to read cache directory")?
+            {
+                let metadata = entry
+                    .with_context(|| "Failed to read cache directory")?
+                    .metadata()
+                    .with_context(|| "Failed to read cache entry metadata")?;
+                stats.bytes += metadata.len();
+                stats.entries += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .with_context(|| format!("Failed to remove cache directory: {:?}", self.dir))?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the number and size of cache entries
+pub fn run_stats() -> Result<()> {
+    let stats = StepCache::new(PathBuf::from(CACHE_DIR)).stats()?;
+    writeln!(io::stdout(), "Entries: {}", stats.entries)
+        .with_context(|| "Failed to write to stdout")?;
+    writeln!(io::stdout(), "Size: {}", common::format_bytes(stats.bytes))
+        .with_context(|| "Failed to write to stdout")?;
+    Ok(())
+}
+
+/// Remove cached step output and pinned step sources
+pub fn run_clear() -> Result<()> {
+    StepCache::new(PathBuf::from(CACHE_DIR)).clear()?;
+    SourceCache::new(PathBuf::from(step_source::SOURCE_CACHE_DIR)).clear()
+}
+
+#[derive(Debug, Default)]
+struct RunState {
+    finished: bool,
+    // The key of the events before the first document, and whether
+    // it was a cache hit
+    header: Option<(String, bool)>,
+    // The hashes of the cached output for the header, if it was a
+    // cache hit
+    header_output: HashSet<String>,
+    // Keys of the document groups that were sent to the step by the
+    // hashes of their documents
+    misses: HashMap<String, String>,
+    start_requested: bool,
+}
+
+/// The output of a cached step, written to a temporary file for each
+/// input group as it arrives
+#[derive(Debug, Default)]
+struct Recorder {
+    // The key of the group that the step is emitting output for
+    current: Option<String>,
+    entries: HashMap<String, TempPath>,
+    // Set when some output could not be attributed to a group or
+    // could not be written, so nothing is stored
+    incomplete: bool,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Recorder {
+    fn switch(&mut self, cache: &StepCache, key: &str) -> Result<()> {
+        if self.current.as_deref() == Some(key) {
+            return Ok(());
+        }
+        self.flush()?;
+        if !self.entries.contains_key(key) {
+            self.entries.insert(key.to_owned(), cache.temp_entry()?);
+        }
+        let path = self.entries.get(key).expect("entry");
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open cache entry: {:?}", path))?;
+        self.current = Some(key.to_owned());
+        self.writer = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn write(&mut self, event: &Event) -> Result<()> {
+        let writer = self.writer.as_mut().expect("writer");
+        serde_json::to_writer(&mut *writer, event).with_context(|| "Event serialization failed")?;
+        writer
+            .write_all(b"\n")
+            .with_context(|| "Failed to write cache entry")
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.current = None;
+        if let Some(mut writer) = self.writer.take() {
+            writer
+                .flush()
+                .with_context(|| "Failed to write cache entry")?;
+        }
+        Ok(())
+    }
+}
+
+/// The cache state for one run of a cached step.
+///
+/// Input events are split into groups. The first group holds the
+/// events before the first document, and each following group holds
+/// a document and the events after it. Only groups that are not in
+/// the cache are sent to the step, and the step is only started if
+/// there is at least one of them.
+///
+/// The step's output belongs to the group of the last input document
+/// that the step emitted, or to the first group before it emits one.
+#[derive(Debug)]
+pub struct CacheRun {
+    cache: StepCache,
+    recorder: Mutex<Recorder>,
+    state: Mutex<RunState>,
+    step_hash: String,
+}
+
+impl CacheRun {
+    pub fn new(cache: StepCache, step: &Step, labels: &[Label]) -> Result<CacheRun> {
+        let step_hash = hash_value(&json!({
+            "labels": labels,
+            "step": step,
+        }))?;
+        Ok(CacheRun {
+            cache,
+            recorder: Mutex::new(Recorder::default()),
+            state: Mutex::new(RunState::default()),
+            step_hash,
+        })
+    }
+
+    /// Record an event emitted by the step
+    pub fn record(&self, event: &Event) {
+        if event.r#type == "control" {
+            return;
+        }
+        let state = self.state.lock().unwrap();
+        let mut recorder = self.recorder.lock().unwrap();
+        if recorder.incomplete {
+            return;
+        }
+        let hash = event.hash.as_deref().expect("hash");
+        let key = match (state.misses.get(hash), &recorder.current, &state.header) {
+            (Some(key), _, _) => key.to_owned(),
+            (None, Some(key), _) => key.to_owned(),
+            (None, None, Some((key, false))) => key.to_owned(),
+            // The step repeated output that was already emitted from
+            // the cache
+            (None, None, _) if state.header_output.contains(hash) => return,
+            (None, None, _) => {
+                info!("Not caching step output that has no input group: {}", hash);
+                recorder.incomplete = true;
+                return;
+            }
+        };
+        if let Err(e) = recorder
+            .switch(&self.cache, &key)
+            .and_then(|_| recorder.write(event))
+        {
+            warn!("Failed to record step output in the cache: {:#}", e);
+            recorder.incomplete = true;
+        }
+    }
+
+    pub fn start_requested(&self) -> bool {
+        self.state.lock().unwrap().start_requested
+    }
+
+    /// Whether every input group was found in the cache, so that the
+    /// step never needs to run
+    pub fn finished_without_step(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.finished && !state.start_requested
+    }
+
+    fn key(&self, header: &[Event], group: Option<&[Event]>) -> Result<String> {
+        let mut value = json!({
+            "header": input_hashes(header),
+            "step": self.step_hash,
+        });
+        if let Some(group) = group {
+            value["input"] = json!(input_hashes(group));
+        }
+        hash_value(&value)
+    }
+
+    /// Store the output of a step that finished successfully
+    pub fn store(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let mut recorder = self.recorder.lock().unwrap();
+        recorder.flush()?;
+        if recorder.incomplete {
+            return Ok(());
+        }
+        let header_key = match &state.header {
+            Some((key, false)) => Some(key),
+            _ => None,
+        };
+        for key in state.misses.values().chain(header_key) {
+            match recorder.entries.remove(key) {
+                Some(temp) => self.cache.persist(key, temp)?,
+                None => self.cache.put(key, &[])?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Read events from the upstream step server, emit the cached
+    /// output of groups that are in the cache, and send the rest to
+    /// the step through `listener`.
+    pub fn run_proxy(
+        &self,
+        upstream: Option<SocketAddr>,
+        listener: Option<TcpListener>,
+        emit: impl FnMut(Event) -> Result<()>,
+        close: impl FnOnce(),
+    ) -> Result<()> {
+        let mut proxy = Proxy {
+            emit,
+            header: Vec::new(),
+            listener,
+            run: self,
+            step_input: None,
+        };
+        let mut group: Option<Vec<Event>> = None;
+        if let Some(addr) = upstream {
+            let stream = TcpStream::connect(addr)
+                .with_context(|| format!("Failed to connect to step server at {}", addr))?;
+            for result in event::events(BufReader::new(stream)) {
+                let mut event = result.with_context(|| "Cannot parse line as JSON")?;
+                event::ensure_hash(&mut event)?;
+                if event.r#type == "document" {
+                    match group.take() {
+                        Some(group) => proxy.handle_group(group)?,
+                        None => proxy.handle_header()?,
+                    }
+                    group = Some(vec![event]);
+                } else {
+                    match &mut group {
+                        Some(group) => group.push(event),
+                        None => proxy.header.push(event),
+                    }
+                }
+            }
+        }
+        match group {
+            Some(group) => proxy.handle_group(group)?,
+            None => proxy.handle_header()?,
+        }
+        // Close the step's input
+        proxy.step_input = None;
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        if !state.start_requested {
+            close();
+        }
+        Ok(())
+    }
+}
+
+struct Proxy<'a, E> {
+    emit: E,
+    header: Vec<Event>,
+    listener: Option<TcpListener>,
+    run: &'a CacheRun,
+    step_input: Option<LineWriter<TcpStream>>,
+}
+
+impl<'a, E: FnMut(Event) -> Result<()>> Proxy<'a, E> {
+    fn emit_cached(&mut self, events: Vec<Event>) -> Result<()> {
+        for event in events {
+            (self.emit)(event)?;
+        }
+        Ok(())
+    }
+
+    /// Start the step, if it hasn't been started, and send it the
+    /// events before the first document
+    fn start_step(&mut self) -> Result<()> {
+        {
+            let mut state = self.run.state.lock().unwrap();
+            if state.start_requested {
+                return Ok(());
+            }
+            state.start_requested = true;
+        }
+        if let Some(listener) = &self.listener {
+            let (stream, _) = listener
+                .accept()
+                .with_context(|| "Failed to accept connection from step")?;
+            self.step_input = Some(LineWriter::new(stream));
+        }
+        let header = self.header.clone();
+        self.forward(&header)
+    }
+
+    fn forward(&mut self, events: &[Event]) -> Result<()> {
+        if let Some(writer) = &mut self.step_input {
+            for event in events {
+                serde_json::to_writer(&mut *writer, event)
+                    .with_context(|| "Event serialization failed")?;
+                writer
+                    .write_all(b"\n")
+                    .with_context(|| "Failed to write to step")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_header(&mut self) -> Result<()> {
+        let key = self.run.key(&self.header, None)?;
+        match self.run.cache.get(&key)? {
+            Some(events) => {
+                info!("Cache hit for input header: {}", key);
+                let mut state = self.run.state.lock().unwrap();
+                state.header = Some((key, true));
+                state.header_output = events
+                    .iter()
+                    .filter_map(|event| event.hash.clone())
+                    .collect();
+                drop(state);
+                self.emit_cached(events)
+            }
+            None => {
+                self.run.state.lock().unwrap().header = Some((key, false));
+                self.start_step()
+            }
+        }
+    }
+
+    fn handle_group(&mut self, group: Vec<Event>) -> Result<()> {
+        if self.run.state.lock().unwrap().header.is_none() {
+            self.handle_header()?;
+        }
+        let key = self.run.key(&self.header, Some(&group))?;
+        match self.run.cache.get(&key)? {
+            Some(events) => {
+                info!("Cache hit for document group: {}", key);
+                // Control events are not step output, so pass them on
+                let control = group
+                    .into_iter()
+                    .filter(|event| event.r#type == "control")
+                    .collect();
+                self.emit_cached(control)?;
+                self.emit_cached(events)
+            }
+            None => {
+                self.start_step()?;
+                let doc_hash = group[0].hash.clone().expect("hash");
+                self.run.state.lock().unwrap().misses.insert(doc_hash, key);
+                self.forward(&group)
+            }
+        }
+    }
+}
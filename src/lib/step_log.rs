@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::{secrets, Step};
+
+/// How long to wait for a step's output to be read after it exits.
+/// Processes started by the step may keep its pipes open.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many lines of output --quiet holds back for each step. Older
+/// lines are dropped, but are still written to the log file.
+const QUIET_LINES: usize = 1000;
+
+/// Embedded steps that need the terminal
+const INTERACTIVE_STEPS: &[&str] = &["label"];
+
+/// A short name for a step, used in log prefixes and file names
+pub fn step_name(step: &Step) -> String {
+    let name = match (&step.run_embedded, &step.run) {
+        (Some(embedded), _) => embedded.split_whitespace().next(),
+        (None, Some(run)) => run
+            .split_whitespace()
+            .next()
+            .and_then(|program| Path::new(program).file_name())
+            .and_then(|s| s.to_str()),
+        (None, None) => None,
+    };
+    name.unwrap_or("step").to_string()
+}
+
+/// Whether the step needs direct access to the terminal
+pub fn is_interactive(step: &Step) -> bool {
+    match &step.run_embedded {
+        Some(embedded) => embedded
+            .split_whitespace()
+            .next()
+            .map(|name| INTERACTIVE_STEPS.contains(&name))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Write a prefixed line to stderr, first clearing any progress line
+fn write_line(prefix: &str, line: &str) -> io::Result<()> {
+    let mut stderr = io::stderr().lock();
+    if stderr.is_terminal() {
+        write!(stderr, "\r\x1b[2K")?;
+    }
+    writeln!(stderr, "{} {}", prefix, line)
+}
+
+/// The last lines of output held back by --quiet
+#[derive(Debug, Default)]
+struct QuietBuffer {
+    dropped: usize,
+    lines: VecDeque<String>,
+}
+
+impl QuietBuffer {
+    fn push(&mut self, line: String) {
+        if self.lines.len() == QUIET_LINES {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        self.lines.push_back(line);
+    }
+}
+
+/// Captures the output of a step process, prefixing each line with
+/// the step index and name.
+#[derive(Debug)]
+pub struct StepLog {
+    buffer: Arc<Mutex<QuietBuffer>>,
+    capture_stdout: bool,
+    file: Option<Arc<Mutex<File>>>,
+    prefix: String,
+    quiet: bool,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl StepLog {
+    pub fn new(
+        index: usize,
+        step: &Step,
+        capture_stdout: bool,
+        log_dir: Option<&PathBuf>,
+        quiet: bool,
+    ) -> Result<StepLog> {
+        let name = step_name(step);
+        let file = match log_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create log directory: {:?}", dir))?;
+                let path = dir.join(format!("{}-{}.log", index, name));
+                let file = OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .open(&path)
+                    .with_context(|| format!("Failed to open log file: {:?}", path))?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+        Ok(StepLog {
+            buffer: Arc::new(Mutex::new(QuietBuffer::default())),
+            capture_stdout,
+            file,
+            prefix: format!("[{} {}]", index, name),
+            quiet,
+            threads: Vec::new(),
+        })
+    }
+
+    /// Set up pipes for the output of a command before it is spawned
+    pub fn configure(&self, cmd: &mut Command) {
+        if self.capture_stdout {
+            cmd.stdout(Stdio::piped());
+        }
+        cmd.stderr(Stdio::piped());
+    }
+
+    /// Start reading the output of a spawned process
+    pub fn capture(&mut self, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            self.spawn_reader(stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.spawn_reader(stderr);
+        }
+    }
+
+    fn spawn_reader<R: Read + Send + 'static>(&mut self, reader: R) {
+        let buffer = self.buffer.clone();
+        let file = self.file.clone();
+        let prefix = self.prefix.clone();
+        let quiet = self.quiet;
+        self.threads.push(thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut bytes = Vec::new();
+            loop {
+                bytes.clear();
+                match reader.read_until(b'\n', &mut bytes) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let line = String::from_utf8_lossy(&bytes);
+                let line = secrets::redact(line.trim_end_matches(&['\r', '\n'][..]));
+                let line = line.as_str();
+                if let Some(file) = &file {
+                    let _ = writeln!(file.lock().unwrap(), "{}", line);
+                }
+                if quiet {
+                    buffer.lock().unwrap().push(line.to_string());
+                } else {
+                    let _ = write_line(&prefix, line);
+                }
+            }
+        }));
+    }
+
+    /// Wait for the output of an exited process to be read
+    pub fn join(&mut self) {
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        while Instant::now() < deadline && self.threads.iter().any(|t| !t.is_finished()) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        self.threads.retain(|t| !t.is_finished());
+    }
+
+    /// Write any output held back by --quiet to stderr
+    pub fn dump(&mut self) -> Result<()> {
+        self.join();
+        let buffer = std::mem::take(&mut *self.buffer.lock().unwrap());
+        if buffer.dropped > 0 {
+            let message = format!("({} earlier lines omitted)", buffer.dropped);
+            write_line(&self.prefix, &message).with_context(|| "Failed to write to stderr")?;
+        }
+        for line in buffer.lines {
+            write_line(&self.prefix, &line).with_context(|| "Failed to write to stderr")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_with::skip_serializing_none;
+
+use crate::{json_schema, step_log, validation, Step};
+
+/// The name of the manifest file that is read from the directory of a
+/// local flake
+pub const FLAKE_MANIFEST_FILE: &str = "srvc-step.yaml";
+
+/// How a step takes part in the event protocol
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepKind {
+    // Passes its input through and then emits new documents
+    Generator,
+    // Reads events and emits them along with any events it adds
+    Map,
+    // Reads events and emits nothing
+    Sink,
+}
+
+impl fmt::Display for StepKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            StepKind::Generator => "generator",
+            StepKind::Map => "map",
+            StepKind::Sink => "sink",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Describes what a step does, which options it accepts, and which
+/// event types it reads and writes.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StepManifest {
+    // The event types that the step reads. None means any type.
+    pub consumes: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+    pub kind: StepKind,
+    // A JSON schema for the options that can be set on the step
+    #[serde(alias = "options_schema", rename = "options-schema")]
+    pub options_schema: Option<serde_json::Value>,
+    // The event types that the step adds to the stream. None means
+    // any type.
+    pub produces: Option<Vec<String>>,
+    // The command that runs the step, relative to the directory of the
+    // manifest. Used for steps referenced by `uses` that are not run
+    // with Nix.
+    pub run: Option<String>,
+}
+
+fn port_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "port": {
+                "type": "integer",
+                "minimum": 0,
+                "maximum": 65535
+            }
+        }
+    })
+}
+
+fn dedupe_schema() -> serde_json::Value {
+    let pointers = json!({"type": "array", "items": {"type": "string"}});
+    json!({
+        "type": "object",
+        "properties": {
+            "fields": {
+                "type": "object",
+                "properties": {
+                    "doi": pointers,
+                    "pmid": pointers,
+                    "title": pointers,
+                    "year": pointers
+                }
+            },
+            "fuzzy-title": {
+                "type": "number",
+                "minimum": 0,
+                "maximum": 1
+            },
+            "keys": {
+                "type": "array",
+                "items": {"enum": ["doi", "pmid", "title-year"]}
+            },
+            "mode": {"enum": ["drop", "link"]}
+        }
+    })
+}
+
+fn generator_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "carry-answers": {
+                "type": "object",
+                "properties": {
+                    "fields": {"type": "array", "items": {"type": "string"}},
+                    "mode": {"enum": ["copy", "link"]}
+                },
+                "required": ["fields"]
+            },
+            "revisions": {"enum": ["all", "latest"]}
+        }
+    })
+}
+
+fn types(v: &[&str]) -> Option<Vec<String>> {
+    Some(v.iter().map(|s| s.to_string()).collect())
+}
+
+/// The manifest of an embedded step, by the name used in
+/// run-embedded
+pub fn embedded(name: &str) -> Option<StepManifest> {
+    let (kind, produces, options_schema) = match name {
+        "dedupe" => (
+            StepKind::Map,
+            types(&["duplicate-of"]),
+            Some(dedupe_schema()),
+        ),
+        "generator" | "generator-file" => (StepKind::Generator, None, Some(generator_schema())),
+        "html" | "label-web" => (
+            StepKind::Map,
+            types(&["control", "label-answer"]),
+            Some(port_schema()),
+        ),
+        "http" => (StepKind::Map, None, None),
+        "label" => (StepKind::Map, types(&["label", "label-answer"]), None),
+        "sink" => (StepKind::Sink, types(&[]), None),
+        "skip-reviewed" | "remove-reviewed" => (StepKind::Map, types(&[]), None),
+        _ => return None,
+    };
+    Some(StepManifest {
+        consumes: None,
+        extra: BTreeMap::new(),
+        kind,
+        options_schema,
+        produces,
+        run: None,
+    })
+}
+
+/// The manifest of a step, if one is declared or the step is
+/// embedded
+pub fn get(step: &Step) -> Option<StepManifest> {
+    match &step.manifest {
+        Some(manifest) => Some(manifest.to_owned()),
+        None => step
+            .run_embedded
+            .as_deref()
+            .and_then(|s| s.split_whitespace().next())
+            .and_then(embedded),
+    }
+}
+
+/// Read a manifest from a YAML or JSON file
+pub fn read(path: &Path) -> Result<StepManifest> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open step manifest: {}", path.to_string_lossy()))?;
+    serde_yaml::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse step manifest: {}", path.to_string_lossy()))
+}
+
+/// The directory of a flake reference that points to a local path,
+/// such as "path:./steps/enrich" or "./steps/enrich"
+pub fn local_flake_dir(uses: &str) -> Option<PathBuf> {
+    let s = uses.strip_prefix("path:").unwrap_or(uses);
+    let s = s.split(['?', '#']).next().unwrap_or(s);
+    if uses.starts_with("path:") || s.starts_with('.') || s.starts_with('/') {
+        Some(PathBuf::from(s))
+    } else {
+        None
+    }
+}
+
+/// Check the options of a step against the JSON schema in its
+/// manifest
+pub fn validate_options(step: &Step) -> Result<()> {
+    let schema = match get(step).and_then(|manifest| manifest.options_schema) {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+    let name = step_log::step_name(step);
+    let compiled = json_schema::compile(&schema)
+        .with_context(|| format!("Invalid options-schema in manifest for step \"{}\"", name))?;
+    let options = serde_json::to_value(&step.extra).with_context(|| "Failed to serialize step")?;
+    if let Err(mut errs) = compiled.validate(&options) {
+        if let Some(e) = errs.next() {
+            return Err(Error::msg(format!(
+                "Invalid options for step \"{}\": {}",
+                name,
+                validation::validation_error_message(e)
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check that no generator comes after a map step. A generator passes
+/// its input through before emitting its own documents, so documents
+/// from a generator after a map step would skip that step.
+pub fn check_order(steps: &[Step]) -> Result<()> {
+    let mut first_map: Option<usize> = None;
+    for (i, step) in steps.iter().enumerate() {
+        match get(step).map(|manifest| manifest.kind) {
+            Some(StepKind::Generator) => {
+                if let Some(j) = first_map {
+                    return Err(Error::msg(format!(
+                        "Step {} ({}) is a generator, but it comes after map step {} ({})",
+                        i,
+                        step_log::step_name(step),
+                        j,
+                        step_log::step_name(&steps[j])
+                    )));
+                }
+            }
+            Some(StepKind::Map) => {
+                first_map = first_map.or(Some(i));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
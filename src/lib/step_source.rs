@@ -0,0 +1,292 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use anyhow::{Context, Error, Result};
+use multihash::MultihashDigest;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::step_manifest;
+
+/// Where the steps referenced by `uses` are pinned and checked out,
+/// relative to the project directory
+pub const SOURCE_CACHE_DIR: &str = ".srvc/sources";
+
+const PIN_FILE: &str = "pin.json";
+
+/// How to run a step referenced by `uses`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StepSource {
+    // A directory with a manifest that has a run command
+    Command { dir: PathBuf, run: String },
+    // A Nix flake reference. Remote flakes are locked to a revision.
+    Flake(String),
+}
+
+/// The resolved form of a `uses` reference, stored so that later flows
+/// don't resolve it again
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Pin {
+    // The directory of the git checkout, relative to the cache entry
+    checkout: Option<PathBuf>,
+    // A locked flake reference or git revision
+    locked: String,
+    uses: String,
+}
+
+/// A git URL in the form that Nix flakes use, such as
+/// git+https://example.com/steps.git?ref=main&dir=classify
+#[derive(Debug)]
+struct GitRef {
+    dir: Option<String>,
+    r#ref: Option<String>,
+    rev: Option<String>,
+    url: String,
+}
+
+fn parse_git_ref(uses: &str) -> Result<Option<GitRef>> {
+    let s = match uses.strip_prefix("git+") {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let mut url = Url::parse(s).with_context(|| format!("Invalid git URL: {}", uses))?;
+    let mut git_ref = GitRef {
+        dir: None,
+        r#ref: None,
+        rev: None,
+        url: String::new(),
+    };
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "dir" => git_ref.dir = Some(v.into_owned()),
+            "ref" => git_ref.r#ref = Some(v.into_owned()),
+            "rev" => git_ref.rev = Some(v.into_owned()),
+            _ => {}
+        }
+    }
+    url.set_query(None);
+    url.set_fragment(None);
+    git_ref.url = url.to_string();
+    Ok(Some(git_ref))
+}
+
+fn cache_key(uses: &str) -> String {
+    let hash = multihash::Code::Sha2_256.digest(uses.as_bytes());
+    bs58::encode(hash.to_bytes()).into_string()
+}
+
+fn run_output(cmd: &mut Command, description: &str) -> Result<Output> {
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to start {}", description))?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "{} failed: {}",
+            description,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output)
+}
+
+/// The run command in the manifest of a step directory, if it has one
+fn manifest_run(dir: &Path) -> Result<Option<String>> {
+    let path = dir.join(step_manifest::FLAKE_MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(step_manifest::read(&path)?.run)
+}
+
+/// Pins the steps referenced by `uses`. Each entry holds the resolved
+/// reference and, for git URLs, a checkout of the pinned revision.
+#[derive(Debug)]
+pub struct SourceCache {
+    dir: PathBuf,
+}
+
+impl SourceCache {
+    pub fn new(dir: PathBuf) -> SourceCache {
+        SourceCache { dir }
+    }
+
+    fn read_pin(&self, entry: &Path) -> Result<Option<Pin>> {
+        let path = entry.join(PIN_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let pin = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to read pinned step source: {:?}", path))?;
+        Ok(Some(pin))
+    }
+
+    fn write_pin(&self, entry: &Path, pin: &Pin) -> Result<()> {
+        // Write to a temporary file first so that a partial pin is
+        // never read
+        let mut file = tempfile::NamedTempFile::new_in(entry)
+            .with_context(|| "Failed to create pinned step source")?;
+        {
+            let mut writer = BufWriter::new(&mut file);
+            serde_json::to_writer_pretty(&mut writer, pin)
+                .with_context(|| "Failed to serialize pinned step source")?;
+            writer
+                .flush()
+                .with_context(|| "Failed to write pinned step source")?;
+        }
+        let path = entry.join(PIN_FILE);
+        file.persist(&path)
+            .with_context(|| format!("Failed to write pinned step source: {:?}", path))?;
+        Ok(())
+    }
+
+    fn entry_dir(&self, uses: &str) -> Result<PathBuf> {
+        let entry = self.dir.join(cache_key(uses));
+        fs::create_dir_all(&entry)
+            .with_context(|| format!("Failed to create step source directory: {:?}", entry))?;
+        Ok(entry)
+    }
+
+    /// Clone a git repository and check out the revision that it
+    /// refers to
+    fn checkout_git(&self, uses: &str, git_ref: &GitRef, entry: &Path) -> Result<Pin> {
+        let tmp = tempfile::tempdir_in(entry)
+            .with_context(|| "Failed to create temporary directory for git checkout")?;
+        let mut clone = Command::new("git");
+        clone.args(["clone", "--quiet"]);
+        if let Some(r) = &git_ref.r#ref {
+            clone.args(["--branch", r]);
+        }
+        clone.arg(&git_ref.url).arg(tmp.path());
+        run_output(&mut clone, &format!("git clone of {}", git_ref.url))?;
+        if let Some(rev) = &git_ref.rev {
+            run_output(
+                Command::new("git")
+                    .args(["checkout", "--quiet", rev])
+                    .current_dir(tmp.path()),
+                &format!("git checkout of {}", rev),
+            )?;
+        }
+        let output = run_output(
+            Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .current_dir(tmp.path()),
+            "git rev-parse",
+        )?;
+        let rev = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let checkout = PathBuf::from(&rev);
+        let path = entry.join(&checkout);
+        if !path.exists() {
+            let tmp = tmp.into_path();
+            if let Err(e) = fs::rename(&tmp, &path) {
+                // Another flow may have checked out the same revision
+                let _ = fs::remove_dir_all(&tmp);
+                if !path.exists() {
+                    return Err(e)
+                        .with_context(|| format!("Failed to move checkout to {:?}", path));
+                }
+            }
+        }
+        Ok(Pin {
+            checkout: Some(checkout),
+            locked: rev,
+            uses: uses.to_owned(),
+        })
+    }
+
+    fn resolve_git(&self, uses: &str, git_ref: GitRef) -> Result<StepSource> {
+        let entry = self.entry_dir(uses)?;
+        let pin = match self.read_pin(&entry)? {
+            Some(pin) if pin.checkout.as_ref().map(|c| entry.join(c).exists()) == Some(true) => pin,
+            _ => {
+                let pin = self.checkout_git(uses, &git_ref, &entry)?;
+                self.write_pin(&entry, &pin)?;
+                pin
+            }
+        };
+        let checkout = entry.join(pin.checkout.as_ref().expect("checkout"));
+        let dir = match &git_ref.dir {
+            Some(dir) => checkout.join(dir),
+            None => checkout,
+        };
+        match manifest_run(&dir)? {
+            Some(run) => Ok(StepSource::Command { dir, run }),
+            None => {
+                let mut url = Url::parse(uses.strip_prefix("git+").unwrap_or(uses))
+                    .with_context(|| format!("Invalid git URL: {}", uses))?;
+                url.query_pairs_mut().append_pair("rev", &pin.locked);
+                Ok(StepSource::Flake(format!("git+{}", url)))
+            }
+        }
+    }
+
+    /// Lock a flake reference with `nix flake metadata`
+    fn resolve_flake(&self, uses: &str) -> Result<StepSource> {
+        let entry = self.entry_dir(uses)?;
+        if let Some(pin) = self.read_pin(&entry)? {
+            return Ok(StepSource::Flake(pin.locked));
+        }
+        let (flake, output) = match uses.split_once('#') {
+            Some((flake, output)) => (flake, Some(output)),
+            None => (uses, None),
+        };
+        let metadata = run_output(
+            Command::new("nix").args([
+                "--extra-experimental-features",
+                "nix-command",
+                "--extra-experimental-features",
+                "flakes",
+                "flake",
+                "metadata",
+                "--json",
+                flake,
+            ]),
+            &format!("nix flake metadata for {}", flake),
+        )?;
+        let metadata: serde_json::Value = serde_json::from_slice(&metadata.stdout)
+            .with_context(|| format!("Failed to parse nix flake metadata for {}", flake))?;
+        let locked_url = metadata["url"]
+            .as_str()
+            .or_else(|| metadata["lockedUrl"].as_str())
+            .ok_or_else(|| Error::msg(format!("No locked URL for flake {}", flake)))?;
+        let locked = match output {
+            Some(output) => format!("{}#{}", locked_url, output),
+            None => locked_url.to_owned(),
+        };
+        let pin = Pin {
+            checkout: None,
+            locked: locked.clone(),
+            uses: uses.to_owned(),
+        };
+        self.write_pin(&entry, &pin)?;
+        Ok(StepSource::Flake(locked))
+    }
+
+    /// Decide how to run the step that `uses` refers to. A local
+    /// directory or git URL with a manifest that has a run command is
+    /// run directly. Anything else is run with Nix. Local directories
+    /// are never pinned, so that changes to them take effect at once.
+    pub fn resolve(&self, uses: &str) -> Result<StepSource> {
+        if let Some(dir) = step_manifest::local_flake_dir(uses) {
+            return Ok(match manifest_run(&dir)? {
+                Some(run) => StepSource::Command { dir, run },
+                None => StepSource::Flake(uses.to_owned()),
+            });
+        }
+        match parse_git_ref(uses)? {
+            Some(git_ref) => self.resolve_git(uses, git_ref),
+            None => self.resolve_flake(uses),
+        }
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir).with_context(|| {
+                format!("Failed to remove step source directory: {:?}", self.dir)
+            })?;
+        }
+        Ok(())
+    }
+}
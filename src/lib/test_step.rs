@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Error, Result};
+use reqwest::blocking::Client;
+
+use crate::event::{self, Event, HashAlgorithm};
+use crate::flow::FlowOptions;
+use crate::{common, flow, sr_yaml, Config, Opts};
+
+/// Options for `sr test-step`
+#[derive(Clone, Debug, Default)]
+pub struct TestStepOptions {
+    pub expected: PathBuf,
+    pub input: Option<PathBuf>,
+    pub step: PathBuf,
+    pub timeout: Option<String>,
+    pub timestamp_override: Option<u64>,
+    pub unordered: bool,
+}
+
+/// Use the project config for labels and the reviewer if there is
+/// one. Steps can also be tested outside of a project.
+fn get_config(opts: &Opts) -> Result<Config> {
+    let path = PathBuf::from(&opts.config);
+    let yaml_config = if path.exists() {
+        sr_yaml::get_config(path)?
+    } else {
+        serde_yaml::from_str("{}").with_context(|| "Failed to create empty config")?
+    };
+    sr_yaml::parse_config(yaml_config)
+}
+
+fn get_step(path: &PathBuf) -> Result<crate::Step> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open step file: {}", path.to_string_lossy()))?;
+    let step: sr_yaml::Step = serde_yaml::from_reader(BufReader::new(file)).with_context(|| {
+        format!(
+            "Failed to parse step file as YAML: {}",
+            path.to_string_lossy()
+        )
+    })?;
+    sr_yaml::parse_step(&Client::new(), step)
+}
+
+fn read_events(path: &PathBuf, hash_algorithm: HashAlgorithm) -> Result<Vec<Event>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+    let mut events = Vec::new();
+    for result in event::events(BufReader::new(file)) {
+        let mut event =
+            result.with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+        event::ensure_hash_with(&mut event, hash_algorithm)?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn describe(event: &Event) -> String {
+    format!(
+        "{} {}",
+        event.r#type,
+        event.hash.as_deref().unwrap_or_default()
+    )
+}
+
+/// Compare events in order. Returns a description of each difference.
+fn compare_ordered(expected: &[Event], actual: &[Event]) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e.hash != a.hash => diffs.push(format!(
+                "Event {}: expected {}, found {}",
+                i,
+                describe(e),
+                describe(a)
+            )),
+            (Some(e), None) => diffs.push(format!("Event {}: missing {}", i, describe(e))),
+            (None, Some(a)) => diffs.push(format!("Event {}: unexpected {}", i, describe(a))),
+            _ => {}
+        }
+    }
+    diffs
+}
+
+/// Compare events regardless of order. Returns a description of each
+/// difference.
+fn compare_unordered(expected: &[Event], actual: &[Event]) -> Vec<String> {
+    let mut counts: HashMap<&str, i64> = HashMap::new();
+    for event in expected {
+        *counts
+            .entry(event.hash.as_deref().expect("hash"))
+            .or_default() += 1;
+    }
+    for event in actual {
+        *counts
+            .entry(event.hash.as_deref().expect("hash"))
+            .or_default() -= 1;
+    }
+    let mut diffs = Vec::new();
+    for event in expected {
+        let count = counts
+            .get_mut(event.hash.as_deref().expect("hash"))
+            .expect("count");
+        if *count > 0 {
+            *count -= 1;
+            diffs.push(format!("Missing {}", describe(event)));
+        }
+    }
+    for event in actual {
+        let count = counts
+            .get_mut(event.hash.as_deref().expect("hash"))
+            .expect("count");
+        if *count < 0 {
+            *count += 1;
+            diffs.push(format!("Unexpected {}", describe(event)));
+        }
+    }
+    diffs
+}
+
+/// Run a step with events from a file, and check that it emits the
+/// expected events
+pub fn run(opts: &Opts, options: TestStepOptions) -> Result<()> {
+    let config = get_config(opts)?;
+    let mut step = get_step(&options.step)?;
+    if let Some(timeout) = options.timeout {
+        common::parse_duration(&timeout)?;
+        step.timeout = Some(timeout);
+    }
+    let expected = read_events(&options.expected, config.hash_algorithm.unwrap_or_default())?;
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let step_output = output.clone();
+    flow::run_isolated(
+        &config,
+        0,
+        &step,
+        Some(&flow::sink_step()),
+        options.input,
+        &FlowOptions {
+            timestamp_override: options.timestamp_override,
+            ..FlowOptions::default()
+        },
+        move |event| {
+            step_output.lock().unwrap().push(event);
+            Ok(())
+        },
+    )?;
+    let actual = mem::take(&mut *output.lock().unwrap());
+
+    let diffs = if options.unordered {
+        compare_unordered(&expected, &actual)
+    } else {
+        compare_ordered(&expected, &actual)
+    };
+    if diffs.is_empty() {
+        writeln!(
+            io::stdout(),
+            "Step output matched {} expected events",
+            expected.len()
+        )
+        .with_context(|| "Failed to write to stdout")?;
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "Step output did not match {}:\n{}",
+            options.expected.to_string_lossy(),
+            diffs.join("\n")
+        )))
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Error, Result};
+use jsonschema::JSONSchema;
+use log::debug;
+
+use crate::event::{self, Event, TypedEvent};
+use crate::json_schema;
+use crate::signature::SignatureChecker;
+
+pub fn validation_error_message(e: jsonschema::ValidationError) -> String {
+    // Work around lifetime complications caused by jsonschema's
+    // ValidationError referencing the schema data
+    let instance_path = e.instance_path.to_string();
+    let path = if instance_path.is_empty() {
+        String::from("root")
+    } else {
+        instance_path
+    };
+    format!("JSON schema validation failed at {}: {}", path, e)
+}
+
+/// Checks label-answers against the labels that came before them in
+/// a stream of events, and optionally checks their signatures.
+/// Tombstones are checked against the redactions that came before
+/// them.
+#[derive(Default)]
+pub struct Validator {
+    // The json-schema of each label by label hash
+    labels: HashMap<String, Option<serde_json::Value>>,
+    // Sinks only check that label-answers name a known label and match
+    // its json_schema, and don't require events to parse as their type
+    lenient: bool,
+    // The hashes of redactions and of the events that they redact
+    redactions: HashSet<(String, String)>,
+    // Compiled json-schemas by label hash. None if the label has no
+    // json-schema.
+    schemas: HashMap<String, Option<JSONSchema>>,
+    signatures: Option<SignatureChecker>,
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    /// A lenient validator that checks events as they are written to
+    /// a sink
+    pub fn for_sink(signatures: SignatureChecker) -> Validator {
+        Validator {
+            lenient: true,
+            signatures: Some(signatures),
+            ..Validator::default()
+        }
+    }
+
+    /// Remember a redaction that came before the events to be
+    /// checked, such as one that is already in a sink
+    pub fn add_redaction(&mut self, redaction_hash: &str, event_hash: &str) {
+        self.redactions
+            .insert((redaction_hash.to_owned(), event_hash.to_owned()));
+    }
+
+    /// Check an event with a hash. Labels and redactions are
+    /// remembered so that later label-answers and tombstones can be
+    /// checked against them.
+    pub fn check(&mut self, event: &Event) -> Result<()> {
+        let hash = event.hash.as_ref().expect("hash");
+        // Tombstones have no content left to check, so they are only
+        // accepted in place of an event that a redaction removed
+        if event::is_tombstone(event) {
+            let redaction_hash = event.extra[event::REDACTED_PROPERTY]
+                .as_str()
+                .expect("redaction hash");
+            if !self
+                .redactions
+                .contains(&(redaction_hash.to_owned(), hash.to_owned()))
+            {
+                return Err(Error::msg(format!(
+                    "Redacted event {} has no redaction {} that removes it",
+                    hash, redaction_hash
+                )));
+            }
+            return Ok(());
+        }
+        match event.r#type.as_str() {
+            "duplicate-of"
+            | "label"
+            | "label-answer"
+            | "label-answer-retraction"
+            | "redaction"
+            | "revision-of" => {}
+            _ => return Ok(()),
+        }
+        if let Some(signatures) = &self.signatures {
+            signatures.check(event)?;
+        }
+        if self.lenient {
+            return self.check_lenient(event);
+        }
+        match TypedEvent::try_from(event.to_owned())? {
+            TypedEvent::Label(label) => {
+                // Older labels use json_schema
+                let schema = label
+                    .data
+                    .json_schema
+                    .or_else(|| label.data.extra.get("json_schema").cloned());
+                self.labels.insert(hash.to_owned(), schema);
+            }
+            TypedEvent::LabelAnswer(answer) => {
+                self.check_answer(event, &answer.data.label, Some(&answer.data.answer))?;
+            }
+            TypedEvent::Redaction(redaction) => {
+                self.add_redaction(hash, &redaction.data.event);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_lenient(&mut self, event: &Event) -> Result<()> {
+        let hash = event.hash.as_ref().expect("hash");
+        let data = event.data.as_ref();
+        match event.r#type.as_str() {
+            "label" => {
+                let schema = data.and_then(|data| data.get("json_schema")).cloned();
+                self.labels.insert(hash.to_owned(), schema);
+            }
+            "label-answer" => {
+                let label = data
+                    .and_then(|data| data.get("label"))
+                    .and_then(|label| label.as_str())
+                    .ok_or_else(|| {
+                        Error::msg(format!("label-answer {} has no label hash", hash))
+                    })?;
+                self.check_answer(event, label, data.and_then(|data| data.get("answer")))?;
+            }
+            "redaction" => {
+                // A redaction that can't be read leaves its tombstones
+                // unaccepted
+                if let Ok(TypedEvent::Redaction(redaction)) = TypedEvent::try_from(event.to_owned())
+                {
+                    self.add_redaction(hash, &redaction.data.event);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_answer(
+        &mut self,
+        event: &Event,
+        label_hash: &str,
+        answer: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        let schema = self
+            .schema(label_hash)
+            .inspect_err(|_| debug!("Validator event: {:?}", event))?;
+        if let (Some(schema), Some(answer)) = (schema, answer) {
+            if let Err(mut errs) = schema.validate(answer) {
+                if let Some(e) = errs.next() {
+                    return Err(Error::msg(format!(
+                        "label-answer {} failed JSON schema validation: {}",
+                        event.hash.as_ref().expect("hash"),
+                        validation_error_message(e)
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn schema(&mut self, label_hash: &str) -> Result<Option<&JSONSchema>> {
+        if !self.schemas.contains_key(label_hash) {
+            let label = self.labels.get(label_hash).ok_or_else(|| {
+                debug!("Label not found with hash: {}", label_hash);
+                debug!("Validator labels: {:?}", self.labels);
+                Error::msg(format!("Label not found with hash: {}", label_hash))
+            })?;
+            let schema = match label {
+                Some(val) => Some(json_schema::compile(val)?),
+                None => None,
+            };
+            self.schemas.insert(label_hash.to_owned(), schema);
+        }
+        Ok(self.schemas.get(label_hash).expect("schema").as_ref())
+    }
+}
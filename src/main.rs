@@ -10,11 +10,15 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use url::{form_urlencoded, Url};
 
-use lib_sr::{common, flow, sr_yaml, Opts};
+use lib_sr::event::HashAlgorithm;
+use lib_sr::{
+    blob, common, flow, in_process, plan, secrets, sr_yaml, step_cache, test_step, Opts, Step,
+};
 
 mod edit_config;
 mod embedded;
 mod hash;
+mod keys;
 mod pull;
 
 const REV: Option<&'static str> = option_env!("SELF_REV");
@@ -36,6 +40,18 @@ struct Cli {
 #[derive(Subcommand)]
 #[clap(version)]
 enum Commands {
+    /// Manage the files that events reference, such as document PDFs
+    Blob {
+        #[clap(subcommand)]
+        command: BlobCommands,
+    },
+
+    /// Manage the cache of step output
+    Cache {
+        #[clap(subcommand)]
+        command: CacheCommands,
+    },
+
     /// Open the documentation website
     Docs {
         /// Search query
@@ -68,10 +84,46 @@ enum Commands {
         #[clap(long)]
         def: Option<String>,
 
+        /// Print the steps that would run, without running them
+        #[clap(long)]
+        dry_run: bool,
+
+        /// With --dry-run, write the config file of each step to this directory
+        #[clap(long, requires = "dry-run")]
+        dump_config: Option<PathBuf>,
+
+        /// Run every step in its own process, including the embedded steps that otherwise run in the flow process
+        #[clap(long)]
+        isolate_steps: bool,
+
+        /// With --dry-run, print the plan in JSON format
+        #[clap(long, requires = "dry-run")]
+        json: bool,
+
+        /// Write the full output of each step to a file in this directory
+        #[clap(long)]
+        log_dir: Option<PathBuf>,
+
+        /// Write metrics for the events passed between steps to this file in JSON format
+        #[clap(long)]
+        metrics_file: Option<PathBuf>,
+
         /// The name of the flow
         #[clap(forbid_empty_values = true)]
         name: String,
 
+        /// Only show step output when a step fails. Up to the last 1000 lines of each step are shown.
+        #[clap(long, short)]
+        quiet: bool,
+
+        /// Write the events that each step emits to a file in this directory
+        #[clap(long)]
+        record: Option<PathBuf>,
+
+        /// Skip documents that went through the flow in an earlier run
+        #[clap(long)]
+        resume: bool,
+
         /// Override the default reviewer URI
         #[clap(long)]
         reviewer: Option<String>,
@@ -80,13 +132,27 @@ enum Commands {
         #[clap(long)]
         sink_control_events: bool,
 
+        /// Validate label-answers as they pass between steps
+        #[clap(long)]
+        strict: bool,
+
         /// Instruct steps to use free ports, ignoring any port set in sr.yaml
         #[clap(long)]
         use_free_ports: bool,
     },
 
     /// Add hashes to a stream of events
-    Hash {},
+    Hash {
+        /// The hash algorithm for events without a hash: sha2-256 (default) or blake3
+        #[clap(long)]
+        algorithm: Option<HashAlgorithm>,
+    },
+
+    /// Manage the key that signs label-answers
+    Keys {
+        #[clap(subcommand)]
+        command: KeysCommands,
+    },
 
     /// Print the full, canonicalized config in JSON format
     PrintConfig {
@@ -108,6 +174,24 @@ enum Commands {
         /// Write control events to the db
         #[clap(long)]
         sink_control_events: bool,
+
+        /// Skip lines that are not valid events instead of failing
+        #[clap(long)]
+        skip_invalid: bool,
+    },
+
+    /// Run one step of a flow with recorded input and print its output
+    ReplayStep {
+        /// The name of the flow. Defaults to the flow that the input was recorded from.
+        #[clap(long)]
+        flow: Option<String>,
+
+        /// The index of the step in the flow, as shown by flow --dry-run
+        index: usize,
+
+        /// A file of events to send to the step, such as one written by flow --record
+        #[clap(long)]
+        input: Option<PathBuf>,
     },
 
     /// Run an embedded step
@@ -117,18 +201,108 @@ enum Commands {
         name: EmbeddedSteps,
     },
 
+    /// Test a step by running it with input events and comparing its output
+    TestStep {
+        /// A file of the events that the step should emit
+        #[clap(long)]
+        expected: PathBuf,
+
+        /// A file of events to send to the step
+        #[clap(long)]
+        input: Option<PathBuf>,
+
+        /// A YAML file that defines the step, in the same form as a step in sr.yaml
+        #[clap(long)]
+        step: PathBuf,
+
+        /// Stop the step if it runs for longer than this, e.g. 30s
+        #[clap(default_value = "60s", long)]
+        timeout: String,
+
+        /// Set SR_TIMESTAMP_OVERRIDE for the step
+        #[clap(long)]
+        timestamp_override: Option<u64>,
+
+        /// Compare events regardless of the order they are emitted in
+        #[clap(long)]
+        unordered: bool,
+    },
+
     /// Print the srvc version
     Version {},
 }
 
+#[derive(Subcommand, Debug)]
+enum BlobCommands {
+    /// Add files to the blob store and print their hashes
+    Add {
+        /// The hash algorithm: sha2-256 (default) or blake3
+        #[clap(long)]
+        algorithm: Option<HashAlgorithm>,
+
+        /// The files to add
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Print a blob, or write it to a file
+    Get {
+        /// The hash of the blob
+        #[clap(forbid_empty_values = true)]
+        hash: String,
+
+        /// Write the blob to this file instead of stdout
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Check that blobs match their hashes. Checks every blob if no hashes are given.
+    Verify {
+        /// The hashes of the blobs to check
+        hashes: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+    /// Remove all cached step output and pinned step sources
+    Clear {},
+
+    /// Print the number and size of cache entries
+    Stats {},
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysCommands {
+    /// Generate a signing key and print its public key
+    Generate {
+        /// The key file. Defaults to SR_SIGNING_KEY_FILE or srvc/signing-key in the user config directory.
+        #[clap(long)]
+        file: Option<PathBuf>,
+    },
+
+    /// Print the public key of the signing key
+    Public {
+        /// The key file. Defaults to SR_SIGNING_KEY_FILE or srvc/signing-key in the user config directory.
+        #[clap(long)]
+        file: Option<PathBuf>,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum EmbeddedSteps {
+    /// Drop or link documents that have the same identity as an earlier document
+    Dedupe {},
     #[clap(alias = "generator-file")]
     /// Source review events from a file or URL
     Generator {
         /// Path to a file or URL containing review events
         #[clap(forbid_empty_values = true)]
         file_or_url: String,
+
+        /// Skip lines that are not valid events instead of failing
+        #[clap(long)]
+        skip_invalid: bool,
     },
     /// Serve an HTML file as a map step
     Html {
@@ -188,7 +362,11 @@ fn print_config(opts: &mut Opts, pretty: bool) -> Result<()> {
 
 fn run_embedded_step(name: EmbeddedSteps) -> Result<()> {
     match name {
-        EmbeddedSteps::Generator { file_or_url } => embedded::generator::run(&file_or_url),
+        EmbeddedSteps::Dedupe {} => embedded::dedupe::run(),
+        EmbeddedSteps::Generator {
+            file_or_url,
+            skip_invalid,
+        } => embedded::generator::run(&file_or_url, skip_invalid),
         EmbeddedSteps::Html { file_or_url } => embedded::html::run(&file_or_url),
         EmbeddedSteps::Http { url } => embedded::http::run(&url),
         EmbeddedSteps::Label {} => embedded::label::run(),
@@ -199,6 +377,38 @@ fn run_embedded_step(name: EmbeddedSteps) -> Result<()> {
     }
 }
 
+/// Return the function that runs an embedded step in the flow process.
+/// Interactive steps and steps that run other programs get a process
+/// of their own.
+fn in_process_step(step: &Step) -> Option<in_process::StepFn> {
+    step.run_embedded.as_ref()?;
+    let (_, args) = flow::get_run_command(step, PathBuf::new()).ok()?;
+    // Parse the arguments as run-embedded-step would
+    let cli = Cli::try_parse_from(std::iter::once(String::from("sr")).chain(args)).ok()?;
+    let name = match cli.command {
+        Commands::RunEmbeddedStep { name } => name,
+        _ => return None,
+    };
+    match name {
+        EmbeddedSteps::Dedupe {} => Some(Box::new(embedded::dedupe::run_in_process)),
+        EmbeddedSteps::Generator {
+            file_or_url,
+            skip_invalid,
+        } => Some(Box::new(move |context| {
+            embedded::generator::run_in_process(&file_or_url, skip_invalid, context)
+        })),
+        EmbeddedSteps::Http { url } => Some(Box::new(move |context| {
+            embedded::http::run_in_process(&url, context)
+        })),
+        EmbeddedSteps::Sink {} => Some(Box::new(embedded::sink::run_in_process)),
+        EmbeddedSteps::SkipReviewed {} => Some(Box::new(embedded::skip_reviewed::run_in_process)),
+        EmbeddedSteps::Html { .. }
+        | EmbeddedSteps::Label {}
+        | EmbeddedSteps::LabelWeb {}
+        | EmbeddedSteps::RunUsing { .. } => None,
+    }
+}
+
 fn version() -> Result<()> {
     writeln!(io::stdout(), "srvc {}", VERSION).with_context(|| "Failed to write to stdout")?;
     match REV {
@@ -217,14 +427,35 @@ fn opts(cli: &Cli) -> Opts {
 
 fn run_command(cli: Cli, opts: &mut Opts) -> Result<()> {
     match cli.command {
+        Commands::Blob { command } => match command {
+            BlobCommands::Add { algorithm, files } => {
+                blob::run_add(files, algorithm.unwrap_or_default())
+            }
+            BlobCommands::Get { hash, out } => blob::run_get(&hash, out),
+            BlobCommands::Verify { hashes } => blob::run_verify(hashes),
+        },
+        Commands::Cache { command } => match command {
+            CacheCommands::Clear {} => step_cache::run_clear(),
+            CacheCommands::Stats {} => step_cache::run_stats(),
+        },
         Commands::Docs { query } => open_docs(query),
         Commands::EditConfig { editor, host, port } => edit_config::run(opts, editor, host, port),
         Commands::Flow {
             db,
             def,
+            dry_run,
+            dump_config,
+            isolate_steps,
+            json,
+            log_dir,
+            metrics_file,
             name,
+            quiet,
+            record,
+            resume,
             reviewer,
             sink_control_events,
+            strict,
             use_free_ports,
         } => flow::run(
             opts,
@@ -233,16 +464,62 @@ fn run_command(cli: Cli, opts: &mut Opts) -> Result<()> {
             name,
             reviewer,
             sink_control_events,
-            use_free_ports,
+            flow::FlowOptions {
+                dry_run: if dry_run {
+                    Some(plan::DryRunOptions {
+                        config_dir: dump_config,
+                        json,
+                    })
+                } else {
+                    None
+                },
+                in_process: if isolate_steps {
+                    None
+                } else {
+                    Some(in_process_step)
+                },
+                log_dir,
+                metrics_file,
+                quiet,
+                record_dir: record,
+                resume,
+                strict,
+                timestamp_override: None,
+                use_free_ports,
+            },
         ),
-        Commands::Hash {} => hash::run(),
+        Commands::Hash { algorithm } => hash::run(algorithm.unwrap_or_default()),
+        Commands::Keys { command } => match command {
+            KeysCommands::Generate { file } => keys::run_generate(file),
+            KeysCommands::Public { file } => keys::run_public(file),
+        },
         Commands::PrintConfig { pretty } => print_config(opts, pretty),
         Commands::Pull {
             db,
             file_or_url,
             sink_control_events,
-        } => pull::run(opts, db, &file_or_url, sink_control_events),
+            skip_invalid,
+        } => pull::run(opts, db, &file_or_url, sink_control_events, skip_invalid),
+        Commands::ReplayStep { flow, index, input } => flow::replay_step(opts, flow, index, input),
         Commands::RunEmbeddedStep { name } => run_embedded_step(name),
+        Commands::TestStep {
+            expected,
+            input,
+            step,
+            timeout,
+            timestamp_override,
+            unordered,
+        } => test_step::run(
+            opts,
+            test_step::TestStepOptions {
+                expected,
+                input,
+                step,
+                timeout: Some(timeout),
+                timestamp_override,
+                unordered,
+            },
+        ),
         Commands::Version {} => version(),
     }
 }
@@ -264,7 +541,7 @@ fn run() -> Result<()> {
 
 fn main() {
     if let Err(err) = run() {
-        eprintln!("Error: {:?}", err);
+        eprintln!("Error: {}", secrets::redact(&format!("{:?}", err)));
         std::process::exit(1);
     }
 }
@@ -1,18 +1,22 @@
 use std::collections::BTreeMap;
+use std::env;
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::thread;
 
 use anyhow::{Context, Error, Result};
+use lib_sr::blob::{self, BlobSource};
 use lib_sr::{event::Event, sr_yaml, Opts};
+use reqwest::blocking::Client;
 
-use crate::embedded::{generator, sink};
+use crate::embedded::{self, generator, sink};
 
 pub fn run(
     opts: &mut Opts,
     db: Option<String>,
     file_or_url: &str,
     sink_control_events: bool,
+    skip_invalid: bool,
 ) -> Result<()> {
     let yaml_config = sr_yaml::get_config(PathBuf::from(&opts.config))?;
     let mut config = sr_yaml::parse_config(yaml_config)?;
@@ -25,7 +29,8 @@ pub fn run(
 
     let cfg = config.clone();
     let thread = thread::spawn(move || {
-        match sink::run_with_events(&cfg, rx.iter().map(|event| Ok(event))) {
+        let token = env::var("SRVC_TOKEN").ok();
+        match sink::run_with_events(&cfg, rx.iter().map(|event| Ok(event)), token.as_deref()) {
             Ok(_) => Ok(()),
             Err(e) => {
                 eprintln!("err! {}", e);
@@ -34,11 +39,23 @@ pub fn run(
         }
     });
 
+    // Copy the blobs that events reference into the project, unless
+    // the events are only being printed
+    let blob_source = match config.db.as_str() {
+        "-" => None,
+        _ => BlobSource::new(file_or_url),
+    };
+    let blob_store = embedded::blob_store(&config);
+    let client = Client::new();
+
     let mut f = |event: Event| {
+        if let Some(source) = &blob_source {
+            blob::transfer(&client, source, &blob_store, &event)?;
+        }
         tx.send(event)
             .with_context(|| "Failed to send event to channel")
     };
-    generator::run_f(file_or_url, &config, &mut f)?;
+    generator::run_f(file_or_url, &config, skip_invalid, &mut f)?;
     drop(tx);
     match thread.join() {
         Ok(Ok(_)) => Ok(()),
@@ -0,0 +1,3 @@
+#!/bin/sh
+test -f "$SR_STEP_DIR/srvc-step.yaml" || exit 2
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
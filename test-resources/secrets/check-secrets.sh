@@ -0,0 +1,8 @@
+test "$API_KEY" = "env-secret" || { echo "API_KEY is not set" >&2; exit 1; }
+test "$DOTENV_KEY" = "dotenv-secret" || { echo "DOTENV_KEY is not set" >&2; exit 1; }
+test "$FILE_KEY" = "file-secret" || { echo "FILE_KEY is not set" >&2; exit 1; }
+if grep -q -e env-secret -e dotenv-secret -e file-secret "$SR_CONFIG"; then
+  echo "Secret found in config" >&2
+  exit 1
+fi
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
@@ -0,0 +1,2 @@
+echo "Using key $API_KEY" >&2
+exit 1
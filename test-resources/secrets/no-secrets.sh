@@ -0,0 +1,2 @@
+test -z "$API_KEY$SR_TEST_API_KEY$DOTENV_KEY$FILE_KEY" || { echo "Secret passed to step" >&2; exit 1; }
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
@@ -0,0 +1,4 @@
+# Pass the input through and then emit the events in extra.jsonl,
+# which don't refer to any input document
+echo run >> runs.txt
+exec "$SR_TEST_BIN" run-embedded-step generator extra.jsonl
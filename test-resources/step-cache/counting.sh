@@ -0,0 +1,2 @@
+echo run >> runs.txt
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
@@ -0,0 +1,2 @@
+echo "noisy step starting" >&2
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
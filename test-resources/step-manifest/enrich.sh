@@ -0,0 +1 @@
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
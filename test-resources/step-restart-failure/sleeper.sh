@@ -0,0 +1,4 @@
+#!/bin/sh
+# Run until the flow ends the step
+echo $$ > sleeper.pid
+exec sleep 30
@@ -0,0 +1,14 @@
+if cat sr.yaml > /dev/null 2>&1; then
+  echo "Read a file outside of the sandbox" >&2
+  exit 1
+fi
+if (echo x > written.txt) 2>/dev/null; then
+  echo "Wrote a file outside of the sandbox" >&2
+  exit 1
+fi
+if command -v unshare > /dev/null && unshare -U true 2>/dev/null; then
+  echo "Created a user namespace in the sandbox" >&2
+  exit 1
+fi
+echo x > "$TMPDIR/scratch.txt" || exit 1
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
@@ -0,0 +1,6 @@
+# Fail the first time, to test retry control events
+if [ ! -f flaky-ran ]; then
+  touch flaky-ran
+  exit 1
+fi
+exec "$SR_TEST_BIN" run-embedded-step skip-reviewed
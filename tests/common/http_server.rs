@@ -13,9 +13,11 @@ fn err(s: &str) -> std::io::Error {
 pub async fn run(dir: &str, port: u16) -> std::io::Result<()> {
     let serve_from = String::from(dir);
     let server = HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::Compress::default())
-            .service(Files::new("/", &serve_from).prefer_utf8(true))
+        App::new().wrap(middleware::Compress::default()).service(
+            Files::new("/", &serve_from)
+                .prefer_utf8(true)
+                .use_hidden_files(),
+        )
     })
     .bind(("127.0.0.1", port))?;
 
@@ -76,7 +76,14 @@ fn remove_file(path: &PathBuf) -> Result<()> {
 }
 
 pub fn remove_sink(dir: &str) -> Result<()> {
-    for fname in ["sink.jsonl", "sink.db", "sink.db-shm", "sink.db-wal"] {
+    for fname in [
+        "sink.jsonl",
+        "sink.jsonl.checkpoint",
+        "sink.db",
+        "sink.db.checkpoint",
+        "sink.db-shm",
+        "sink.db-wal",
+    ] {
         remove_file(&PathBuf::from(dir).join(fname))?
     }
     Ok(())
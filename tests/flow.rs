@@ -100,6 +100,7 @@ fn test_flow_err(
         .stdout("")
         .stderr(stderr);
     assert_eq!(sink_should_exist, common::sqlite_sink_path(&dir).exists());
+    common::remove_sink(&dir)?;
     Ok(())
 }
 
@@ -222,6 +223,7 @@ fn test_db_override() -> Result<()> {
     assert_eq!(true, db_path.exists());
     assert_eq!(false, sink_path.exists());
     fs::rename(db_path, sink_path).unwrap();
+    fs::remove_file(PathBuf::from(&dir).join("override.jsonl.checkpoint"))?;
     common::check_sink(&dir, true)?;
     Ok(())
 }
@@ -461,11 +463,40 @@ fn test_generator_url_404() -> Result<()> {
         "generator-url-404",
         "generator-url",
         2000,
-        "Error: Unexpected 404 status for http://127.0.0.1:8877/generator-url/404.jsonl\nError: Step failed with exit code 1\n",
+        "Error: Step 0 (generator) failed: Unexpected 404 status for http://127.0.0.1:8877/generator-url/404.jsonl\n",
         true
     )
 }
 
+/// Test that --isolate-steps runs embedded steps in their own
+/// processes, which report errors in their output
+#[cfg(unix)]
+#[test]
+fn test_isolate_steps() -> Result<()> {
+    let dir = test_dir("simple");
+    common::remove_sink(&dir)?;
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["flow", "simple", "--isolate-steps"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+
+    let dir = test_dir("generator-url-404");
+    common::remove_sink(&dir)?;
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["flow", "generator-url", "--isolate-steps"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("[0 generator] Error: Unexpected 404 status for http://127.0.0.1:8877/generator-url/404.jsonl\nError: Step failed with exit code 1\n");
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
 #[test]
 fn test_implicit_db() -> Result<()> {
     test_flow("implicit-db", "simple", 400)
@@ -483,6 +514,44 @@ fn test_label_label_answers() -> Result<()> {
     test_flow("label-label-answers", "simple", 400)
 }
 
+/// Test that --metrics-file counts the events passed between steps
+#[test]
+fn test_metrics_file() -> Result<()> {
+    let dir = test_dir("metrics-file");
+    let metrics_path = PathBuf::from(&dir).join("metrics.json");
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "simple", "--metrics-file", "metrics.json"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    let metrics: serde_json::Value = serde_json::from_str(&fs::read_to_string(&metrics_path)?)?;
+    fs::remove_file(&metrics_path)?;
+    let edges = metrics["edges"].as_array().expect("edges");
+    let names: Vec<(&str, &str)> = edges
+        .iter()
+        .map(|edge| {
+            (
+                edge["from"]["name"].as_str().unwrap(),
+                edge["to"]["name"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![("generator", "remove-reviewed"), ("remove-reviewed", "sink")],
+        names
+    );
+    for edge in edges {
+        assert_eq!(8, edge["events"]);
+        assert_eq!(serde_json::json!({"document": 8}), edge["events-by-type"]);
+        assert!(edge["bytes"].as_u64().unwrap() > 0);
+    }
+    Ok(())
+}
+
 #[test]
 fn test_reviewer_uri_domain() -> Result<()> {
     test_flow_err(
@@ -604,6 +673,45 @@ fn test_sources_with_generator() -> Result<()> {
     test_flow("sources-with-generator", "test", 400)
 }
 
+/// Test that --strict rejects invalid events and names the step
+/// that produced them
+#[test]
+fn test_strict_invalid_answer() -> Result<()> {
+    let dir = test_dir("strict-validation");
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "--strict", "--db", "-", "invalid-answer"])
+        .assert()
+        .code(1)
+        .stderr("Error: Step 0 (generator) emitted an invalid event: label-answer QmQ6pknThb1dbmzepFFtnsDMo73KU2fe3VbLN1D7S9P5Lj failed JSON schema validation: JSON schema validation failed at root: \"yes\" is not of type \"boolean\"\n");
+    Ok(())
+}
+
+#[test]
+fn test_strict_unseen_label() -> Result<()> {
+    let dir = test_dir("strict-validation");
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "--strict", "--db", "-", "unseen-label"])
+        .assert()
+        .code(1)
+        .stderr("Error: Step 0 (generator) emitted an invalid event: Label not found with hash: QmNpMP1iyS4LToN1wmzG11tFkGYnQZuzQkaoV3knk4HCFc\n");
+    Ok(())
+}
+
+/// Test that sinks still check label-answers against labels that use
+/// the older json_schema property
+#[test]
+fn test_sink_legacy_json_schema() -> Result<()> {
+    test_flow_err(
+        "sink-legacy-schema",
+        "invalid-answer",
+        400,
+        "Error: Step 1 (sink) failed: label-answer Qmejb34myNZFdWFBwepfAfARXYByaTaKKJrqtywu1yRRzz failed JSON schema validation: JSON schema validation failed at root: \"yes\" is not of type \"boolean\"\n",
+        true,
+    )
+}
+
 #[test]
 fn test_wrong_name() -> Result<()> {
     test_flow_err(
@@ -615,6 +723,1217 @@ fn test_wrong_name() -> Result<()> {
     )
 }
 
+/// Test that --quiet hides step output and --log-dir records it
+#[cfg(unix)]
+#[test]
+fn test_step_logs() -> Result<()> {
+    let dir = test_dir("step-logs");
+    let log_dir = PathBuf::from(&dir).join("logs");
+    common::remove_sink(&dir)?;
+    let _ = std::fs::remove_dir_all(&log_dir);
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "test", "--quiet", "--log-dir", "logs"])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, false)?;
+    assert_eq!(
+        "noisy step starting\n",
+        std::fs::read_to_string(log_dir.join("1-sh.log"))?
+    );
+    std::fs::remove_dir_all(&log_dir)?;
+    Ok(())
+}
+
+/// Test that --quiet shows the output of a step that fails
+#[cfg(unix)]
+#[test]
+fn test_step_logs_quiet_failure() -> Result<()> {
+    let dir = test_dir("step-logs");
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "fail", "--quiet", "--db", "-"])
+        .assert()
+        .code(1)
+        .stderr("[1 sh] starting\n[1 sh] failed\nError: Step failed with exit code 1\n");
+    Ok(())
+}
+
+/// Test that --quiet only holds back the last lines of a step's output
+#[cfg(unix)]
+#[test]
+fn test_step_logs_quiet_limit() -> Result<()> {
+    let dir = test_dir("step-logs");
+    let lines: String = (6..=1005).map(|i| format!("[1 sh] {}\n", i)).collect();
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "fail-long", "--quiet", "--db", "-"])
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "[1 sh] (5 earlier lines omitted)\n{}Error: Step failed with exit code 1\n",
+            lines
+        ));
+    Ok(())
+}
+
+/// Test that --record writes the events that each step emits, and
+/// that a step can be replayed from the recording
+#[test]
+fn test_flow_record_replay() -> Result<()> {
+    let dir = test_dir("flow-record");
+    let record_dir = PathBuf::from(&dir).join("recording");
+    let _ = fs::remove_dir_all(&record_dir);
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "simple", "--record", "recording"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected.jsonl"))?;
+    for file in ["0-generator.jsonl", "1-skip-reviewed.jsonl"] {
+        assert_eq!(expected, fs::read_to_string(record_dir.join(file))?);
+    }
+
+    // The flow name is read from the recording
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["replay-step", "1", "--input", "recording/0-generator.jsonl"])
+        .assert()
+        .success()
+        .stdout(expected.clone())
+        .stderr("");
+    // The sink prints events instead of writing to the db
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["replay-step", "2", "--input", "docs.jsonl", "--flow", "simple"])
+        .assert()
+        .success()
+        .stdout(expected)
+        .stderr("");
+    assert!(!common::sink_path(&dir).exists());
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["replay-step", "3", "--flow", "simple"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("Error: Flow \"simple\" has no step 3. Its steps are numbered 0 to 2.\n");
+    fs::remove_dir_all(&record_dir)?;
+    Ok(())
+}
+
+/// Test that --dry-run prints the plan of a flow without running it
+#[test]
+fn test_flow_dry_run() -> Result<()> {
+    let dir = test_dir("flow-dry-run");
+    let config_dir = PathBuf::from(&dir).join("configs");
+    let _ = fs::remove_dir_all(&config_dir);
+    let exe_path = PathBuf::from(env!("CARGO_BIN_EXE_sr")).canonicalize()?;
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-plan.txt"))?
+        .replace("{sr}", &exe_path.to_string_lossy());
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "review", "--dry-run", "--dump-config", "configs"])
+        .env("SRVC_TOKEN", "token")
+        .assert()
+        .success()
+        .stdout(expected)
+        .stderr("");
+    assert!(!common::sink_path(&dir).exists());
+    let step_config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(config_dir.join("3-html.json"))?)?;
+    assert_eq!("acute_tox", step_config["current-labels"][0]["id"]);
+    assert_eq!(6132, step_config["current-step"]["port"]);
+    fs::remove_dir_all(&config_dir)?;
+
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "review", "--dry-run", "--json", "--use-free-ports"])
+        .env_remove("SRVC_TOKEN")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let plan: serde_json::Value = serde_json::from_slice(&output)?;
+    assert_eq!("sink.jsonl", plan["sink"]);
+    let steps = plan["steps"].as_array().expect("steps");
+    let kinds: Vec<(&str, &str)> = steps
+        .iter()
+        .map(|step| {
+            (
+                step["name"].as_str().unwrap(),
+                step["kind"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            ("generator", "source"),
+            ("skip-reviewed", "step"),
+            ("sh", "step"),
+            ("html", "step"),
+            ("sink", "sink"),
+        ],
+        kinds
+    );
+    assert_eq!(
+        serde_json::json!(["enrich.sh", "--mode", "full text"]),
+        steps[2]["args"]
+    );
+    assert_eq!(
+        serde_json::json!({
+            "SR_CONFIG": "(temporary file)",
+            "SR_INPUT": "step 1 output",
+            "SR_OUTPUT": "step 3 input",
+        }),
+        steps[2]["env"]
+    );
+    assert_eq!(serde_json::Value::Null, steps[3]["port"]);
+    // Embedded steps run in the flow process unless an option such as
+    // timeout needs a process of their own
+    let in_process: Vec<bool> = steps
+        .iter()
+        .map(|step| step["in-process"].as_bool().unwrap())
+        .collect();
+    assert_eq!(vec![true, false, false, false, true], in_process);
+    assert_eq!(serde_json::json!({}), steps[0]["env"]);
+    assert!(!common::sink_path(&dir).exists());
+
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "review", "--dry-run", "--json", "--isolate-steps"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let plan: serde_json::Value = serde_json::from_slice(&output)?;
+    let steps = plan["steps"].as_array().expect("steps");
+    assert!(steps.iter().all(|step| step["in-process"] == false));
+    assert_eq!(
+        serde_json::json!(["run-embedded-step", "generator", "docs.jsonl"]),
+        steps[0]["args"]
+    );
+    assert_eq!("step 1 input", steps[0]["env"]["SR_OUTPUT"]);
+    Ok(())
+}
+
+/// Test that a flow writes a checkpoint, and that --resume skips the
+/// documents that went through the flow before it was interrupted
+#[test]
+fn test_flow_resume() -> Result<()> {
+    let dir = test_dir("flow-resume");
+    let checkpoint_path = PathBuf::from(&dir).join("sink.jsonl.checkpoint");
+    let metrics_path = PathBuf::from(&dir).join("metrics.json");
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "simple"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    let checkpoint: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&checkpoint_path)?)?;
+    common::check_sink(&dir, true)?;
+    let docs = fs::read_to_string(PathBuf::from(&dir).join("expected.jsonl"))?;
+    let docs: Vec<&str> = docs.lines().collect();
+    let doc_hash = |line: &str| -> Result<String> {
+        Ok(lib_sr::event::parse_event(line)?.hash.expect("hash"))
+    };
+    let last = doc_hash(docs[docs.len() - 1])?;
+    assert_eq!(
+        serde_json::json!({"flows": {"simple": {"steps": [
+            {"index": 0, "last-document": last, "name": "generator"},
+            {"index": 1, "last-document": last, "name": "remove-reviewed"},
+        ]}}}),
+        checkpoint
+    );
+
+    // Simulate a run that was interrupted after the third document
+    let third = doc_hash(docs[2])?;
+    fs::write(common::sink_path(&dir), docs[..3].join("\n") + "\n")?;
+    fs::write(
+        &checkpoint_path,
+        serde_json::json!({"flows": {"simple": {"steps": [
+            {"index": 0, "last-document": doc_hash(docs[4])?, "name": "generator"},
+            {"index": 1, "last-document": third, "name": "remove-reviewed"},
+        ]}}})
+        .to_string(),
+    )?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "simple", "--resume", "--metrics-file", "metrics.json"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    let metrics: serde_json::Value = serde_json::from_str(&fs::read_to_string(&metrics_path)?)?;
+    fs::remove_file(&metrics_path)?;
+    assert_eq!(
+        serde_json::json!({"document": 5}),
+        metrics["edges"][0]["events-by-type"]
+    );
+    Ok(())
+}
+
+/// Test that --resume skips finished documents from every source, not
+/// only the first
+#[test]
+fn test_flow_resume_two_sources() -> Result<()> {
+    let dir = test_dir("flow-resume-sources");
+    let checkpoint_path = PathBuf::from(&dir).join("sink.jsonl.checkpoint");
+    let metrics_path = PathBuf::from(&dir).join("metrics.json");
+    common::remove_sink(&dir)?;
+    let docs = fs::read_to_string(PathBuf::from(&dir).join("expected.jsonl"))?;
+    let docs: Vec<&str> = docs.lines().collect();
+    let doc_hash = |line: &str| -> Result<String> {
+        Ok(lib_sr::event::parse_event(line)?.hash.expect("hash"))
+    };
+
+    // Simulate a run that was interrupted after the second document
+    // from the second source
+    let sixth = doc_hash(docs[5])?;
+    fs::write(common::sink_path(&dir), docs[..6].join("\n") + "\n")?;
+    fs::write(
+        &checkpoint_path,
+        serde_json::json!({"flows": {"two-sources": {"steps": [
+            {"index": 0, "last-document": doc_hash(docs[3])?, "name": "generator"},
+            {"index": 1, "last-document": sixth, "name": "generator"},
+            {"index": 2, "last-document": sixth, "name": "remove-reviewed"},
+        ]}}})
+        .to_string(),
+    )?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&[
+            "flow",
+            "two-sources",
+            "--resume",
+            "--metrics-file",
+            "metrics.json",
+        ])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    let metrics: serde_json::Value = serde_json::from_str(&fs::read_to_string(&metrics_path)?)?;
+    fs::remove_file(&metrics_path)?;
+    assert_eq!(
+        serde_json::json!({"document": 2}),
+        metrics["edges"][1]["events-by-type"]
+    );
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that a cached step is not run again when its input hasn't
+/// changed, and that the cache can be cleared
+#[cfg(unix)]
+#[test]
+fn test_step_cache() -> Result<()> {
+    let dir = test_dir("step-cache");
+    let cache_dir = PathBuf::from(&dir).join(".srvc");
+    let runs_path = PathBuf::from(&dir).join("runs.txt");
+    let _ = fs::remove_dir_all(&cache_dir);
+    let _ = fs::remove_file(&runs_path);
+    for _ in 0..2 {
+        common::remove_sink(&dir)?;
+        common::cmd(4000)
+            .current_dir(&dir)
+            .args(&["flow", "test"])
+            .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+        common::check_sink(&dir, true)?;
+        assert_eq!("run\n", fs::read_to_string(&runs_path)?);
+    }
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["cache", "stats"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert!(String::from_utf8(output)?.starts_with("Entries: 9\n"));
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["cache", "clear"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    assert!(!cache_dir.join("cache").exists());
+    fs::remove_dir_all(&cache_dir)?;
+    fs::remove_file(&runs_path)?;
+    Ok(())
+}
+
+/// Test that output that doesn't refer to an input document is cached
+/// with the group that the step emitted it after, even when the
+/// events before the first document were a cache hit
+#[cfg(unix)]
+#[test]
+fn test_step_cache_output() -> Result<()> {
+    let dir = test_dir("step-cache-output");
+    let dir_path = PathBuf::from(&dir);
+    let runs_path = dir_path.join("runs.txt");
+    let _ = fs::remove_dir_all(dir_path.join(".srvc"));
+    let _ = fs::remove_file(&runs_path);
+    for (flow, extra, runs) in [
+        ("one", "extra-1.jsonl", "run\n"),
+        ("two", "extra-2.jsonl", "run\nrun\n"),
+        ("two", "extra-2.jsonl", "run\nrun\n"),
+    ] {
+        common::remove_sink(&dir)?;
+        fs::copy(dir_path.join(extra), dir_path.join("extra.jsonl"))?;
+        common::cmd(4000)
+            .current_dir(&dir)
+            .args(&["flow", flow])
+            .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+        assert_eq!(runs, fs::read_to_string(&runs_path)?);
+    }
+    // The last run only replays the cache
+    common::check_sink(&dir, true)?;
+    fs::remove_dir_all(dir_path.join(".srvc"))?;
+    fs::remove_file(dir_path.join("extra.jsonl"))?;
+    fs::remove_file(&runs_path)?;
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_step_manifest() -> Result<()> {
+    let dir = test_dir("step-manifest");
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "test"])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "generator-after-map"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(
+            "Error: Step 2 (generator) is a generator, but it comes after map step 1 (skip-reviewed)\n",
+        );
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "flake-generator-after-map", "--dry-run"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(
+            "Error: Step 1 (run-using) is a generator, but it comes after map step 0 (skip-reviewed)\n",
+        );
+    Ok(())
+}
+
+/// Test that secrets are passed only to the steps that ask for them,
+/// and that their values are never written to config files or output
+#[cfg(unix)]
+#[test]
+fn test_secrets() -> Result<()> {
+    let dir = test_dir("secrets");
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "test"])
+        .env("SR_TEST_API_KEY", "env-secret")
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "missing"])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .env_remove("SR_TEST_MISSING_KEY")
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("Error: Secret \"MISSING_KEY\" is not set. Set the SR_TEST_MISSING_KEY environment variable.\n");
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "leak"])
+        .env("SR_TEST_API_KEY", "env-secret")
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("[1 sh] Using key <redacted>\nError: Step failed with exit code 1\n");
+    common::remove_sink(&dir)?;
+    let output = common::cmd(1000)
+        .current_dir(&dir)
+        .args(&["print-config"])
+        .env("SR_TEST_API_KEY", "env-secret")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output)?;
+    assert!(output.contains(r#""API_KEY":{"env":"SR_TEST_API_KEY","value":"<redacted>"}"#));
+    for value in ["env-secret", "dotenv-secret", "file-secret"] {
+        assert!(!output.contains(value));
+    }
+    Ok(())
+}
+
+/// Test that sandboxed steps can only use the files and network that
+/// they are given
+#[cfg(target_os = "linux")]
+#[test]
+fn test_step_sandbox() -> Result<()> {
+    let dir = test_dir("step-sandbox");
+    common::remove_sink(&dir)?;
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "read-outside"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output)?;
+    // Without Landlock, or without Landlock network limits before
+    // Linux 6.7, the flow must fail before it runs the step
+    if stderr.starts_with("Error: Step sandboxes require Landlock, which is not available (")
+        || stderr.starts_with("Error: Landlock version ")
+    {
+        eprintln!("Skipping sandbox checks: {}", stderr.trim_end());
+        assert_eq!(1, stderr.lines().count(), "{}", stderr);
+        assert!(!common::sink_path(&dir).exists());
+        return Ok(());
+    }
+    assert!(stderr.contains("Failed to open file docs.jsonl"));
+    assert!(stderr.contains("Permission denied"));
+    common::remove_sink(&dir)?;
+    // A sandbox set in the project applies to steps loaded from a uri
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "uri"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("Permission denied"));
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "test"])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "no-network"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("tcp connect error: Permission denied"));
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "network"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    let status = std::process::Command::new("git")
+        .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .current_dir(dir)
+        .status()?;
+    assert!(status.success());
+    Ok(())
+}
+
+/// Test steps that `uses` refers to by a local directory or a git URL
+/// with a run command in their manifest
+#[cfg(unix)]
+#[test]
+fn test_run_using() -> Result<()> {
+    let dir = test_dir("run-using");
+    let srvc_dir = PathBuf::from(&dir).join(".srvc");
+    let _ = fs::remove_dir_all(&srvc_dir);
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "local"])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "fail"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("Step \"./steps/fail\" failed with exit code 3"));
+    common::remove_sink(&dir)?;
+
+    // A git URL is pinned to the revision that it first resolved to
+    // until the cache is cleared
+    let repo = tempfile::tempdir()?;
+    let step_dir = repo.path().join("pass");
+    fs::create_dir(&step_dir)?;
+    for file in ["pass.sh", "srvc-step.yaml"] {
+        fs::copy(
+            PathBuf::from(&dir).join("steps/pass").join(file),
+            step_dir.join(file),
+        )?;
+    }
+    git(repo.path(), &["init", "--quiet"])?;
+    git(repo.path(), &["add", "."])?;
+    git(repo.path(), &["commit", "--quiet", "-m", "Add step"])?;
+    let flow_json = format!(
+        r#"{{"steps":[{{"run-embedded":"generator docs.jsonl"}},{{"uses":"git+file://{}?dir=pass"}}]}}"#,
+        repo.path().display()
+    );
+    for i in 0..2 {
+        common::cmd(4000)
+            .current_dir(&dir)
+            .args(&["flow", "git", "--def", &flow_json])
+            .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+        common::check_sink(&dir, true)?;
+        if i == 0 {
+            fs::write(step_dir.join("pass.sh"), "#!/bin/sh\nexit 5\n")?;
+            git(repo.path(), &["commit", "--quiet", "-am", "Fail"])?;
+        }
+    }
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["cache", "clear"])
+        .assert()
+        .success();
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "git", "--def", &flow_json])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("failed with exit code 5"));
+    common::remove_sink(&dir)?;
+    fs::remove_dir_all(&srvc_dir)?;
+    Ok(())
+}
+
+/// Test that embedded steps read label and label-answer data by type,
+/// and pass through labels from older versions that don't parse
+#[test]
+fn test_typed_events() -> Result<()> {
+    let dir = test_dir("typed-events");
+    // Documents with answers from the reviewer are skipped
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected.jsonl"))?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["replay-step", "0", "--flow", "skip", "--input", "docs.jsonl"])
+        .assert()
+        .success()
+        .stdout(expected)
+        .stderr("");
+    let legacy_label = fs::read_to_string(PathBuf::from(&dir).join("legacy-label.jsonl"))?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "--db", "-", "legacy-label"])
+        .assert()
+        .success()
+        .stdout(legacy_label);
+    Ok(())
+}
+
+/// Test that skip-reviewed skips the documents that the reviewer
+/// answered in a local db, and keeps those answered by others
+#[test]
+fn test_skip_reviewed() -> Result<()> {
+    let dir = test_dir("skip-reviewed");
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected.jsonl"))?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["replay-step", "0", "--flow", "skip", "--input", "docs.jsonl"])
+        .assert()
+        .success()
+        .stdout(expected)
+        .stderr("");
+    Ok(())
+}
+
+/// Test that the generator emits label-answers after the answers they
+/// supersede, with the answers in effect last, and that SQLite sinks
+/// reject answers that supersede another reviewer's answer
+#[test]
+fn test_answer_revisions() -> Result<()> {
+    let dir = test_dir("answer-revisions");
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "generator"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "--db", "sink.db", "generator"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-sqlite.jsonl"))?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["pull", "sink.db", "--db", "-"])
+        .assert()
+        .success()
+        .stdout(expected)
+        .stderr("");
+    common::remove_sink(&dir)?;
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "--db", "sink.db", "bad"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("Superseded label-answer must exist and have the same event, label, and reviewer"));
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that tombstones keep the hashes of the events they replace
+/// when they pass between steps in their own processes
+#[test]
+fn test_redaction_isolate_steps() -> Result<()> {
+    let dir = test_dir("redaction");
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-stdout.jsonl"))?;
+    for args in [
+        vec!["flow", "--db", "-", "redacted-source"],
+        vec!["flow", "--isolate-steps", "--db", "-", "redacted-source"],
+    ] {
+        common::cmd(2000)
+            .current_dir(&dir)
+            .args(&args)
+            .assert()
+            .success()
+            .stdout(expected.clone())
+            .stderr("");
+    }
+    Ok(())
+}
+
+/// Test that new events are hashed with the project's hash algorithm,
+/// that events hashed with other algorithms are accepted, and that
+/// unsupported multihash codes are reported
+#[test]
+fn test_hash_algorithm() -> Result<()> {
+    let dir = test_dir("hash-algorithm");
+    // Events from steps in their own processes are checked with the
+    // algorithm that their hashes name
+    for args in [vec!["flow", "import"], vec!["flow", "--isolate-steps", "import"]] {
+        common::remove_sink(&dir)?;
+        common::cmd(400)
+            .current_dir(&dir)
+            .args(&args)
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+        common::check_sink(&dir, true)?;
+    }
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected.jsonl"))?;
+    let label = expected.lines().next().expect("label");
+    let unhashed = label.replace(r#""hash":"gW9fdSqgkrmDv27m65yGSL1uyqJGxw63mjof85ir2YgiZH","#, "");
+    common::cmd(400)
+        .args(&["hash", "--algorithm", "blake3"])
+        .write_stdin(unhashed)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", label));
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "unsupported"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("Unsupported hash algorithm (multihash code 0xd5) in event hash"));
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that the label step signs label-answers with the signing key,
+/// that a key in the user's config directory is not used unless
+/// signing is enabled, that sinks reject unsigned and badly signed
+/// label-answers when signatures are required, and that keys can be
+/// generated
+#[test]
+fn test_signatures() -> Result<()> {
+    let dir = test_dir("signatures");
+    common::remove_sink(&dir)?;
+    let config_home = tempfile::tempdir()?;
+    std::fs::create_dir(config_home.path().join("srvc"))?;
+    std::fs::copy(
+        format!("{}/signing-key", dir),
+        config_home.path().join("srvc").join("signing-key"),
+    )?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "label"])
+        .env("SR_SIGNING_KEY_FILE", "signing-key")
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    common::check_sink(&dir, true)?;
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "label"])
+        .env("APPDATA", config_home.path())
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env_remove("SR_SIGNING_KEY_FILE")
+        .write_stdin("y\n")
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("label-answer QmVSZgw3JYq2MTwAp22f6u749ohjbikxujoi89N4trPU81 is not signed"));
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["--config", "sign.yaml", "flow", "label"])
+        .env("APPDATA", config_home.path())
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env_remove("SR_SIGNING_KEY_FILE")
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    common::check_sink(&dir, true)?;
+    common::remove_sink(&dir)?;
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["--config", "sign.yaml", "flow", "label"])
+        .env("SR_SIGNING_KEY_FILE", "missing-key")
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .write_stdin("y\n")
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("Signing is enabled, but there is no signing key file at \"missing-key\""));
+    common::remove_sink(&dir)?;
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["pull", "tampered.jsonl", "--db", "-"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("Invalid signature in event QmW8dV3XwTsxgYJAadxpJpF1nGToXMNF2uFmW7kyTk54bK"));
+
+    let tmp = tempfile::tempdir()?;
+    let key_file = tmp.path().join("signing-key");
+    let public_key = common::cmd(400)
+        .arg("keys")
+        .arg("generate")
+        .arg("--file")
+        .arg(&key_file)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert!(String::from_utf8(public_key.clone())?.starts_with("ed25519:"));
+    common::cmd(400)
+        .args(&["keys", "public"])
+        .env("SR_SIGNING_KEY_FILE", &key_file)
+        .assert()
+        .success()
+        .stdout(public_key);
+    let output = common::cmd(400)
+        .arg("keys")
+        .arg("generate")
+        .arg("--file")
+        .arg(&key_file)
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("A signing key already exists"));
+    Ok(())
+}
+
+/// Test that the dedupe step links or drops duplicate documents, and
+/// that skip-reviewed skips duplicates of reviewed documents
+#[test]
+fn test_dedupe() -> Result<()> {
+    let dir = test_dir("dedupe");
+    common::remove_sink(&dir)?;
+    // Running the flow again must not add more links
+    for _ in 0..2 {
+        common::cmd(400)
+            .current_dir(&dir)
+            .args(&["flow", "link"])
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+    }
+    common::check_sink(&dir, true)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "drop"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-drop.jsonl"))?;
+    assert_eq!(expected, fs::read_to_string(common::sink_path(&dir))?);
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["pull", "reviewed.jsonl"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    // Only the document that is not a duplicate of a reviewed document
+    // is labeled
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "label"])
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-label.jsonl"))?;
+    assert_eq!(expected, fs::read_to_string(common::sink_path(&dir))?);
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that the generator links versions of a document with the
+/// same uri, carries answers forward, and can emit only the newest
+/// version
+#[test]
+fn test_revisions() -> Result<()> {
+    let dir = test_dir("revisions");
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "copy"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "latest"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-latest.jsonl"))?;
+    assert_eq!(expected, fs::read_to_string(common::sink_path(&dir))?);
+    common::remove_sink(&dir)?;
+    // SQLite sources order versions by when they were added
+    let tmp = tempfile::tempdir()?;
+    let source = tmp.path().join("source.db");
+    common::cmd(400)
+        .current_dir(&dir)
+        .arg("pull")
+        .arg("docs.jsonl")
+        .arg("--db")
+        .arg(&source)
+        .assert()
+        .success();
+    let flow_json = serde_json::json!({
+        "steps": [{
+            "run-embedded": format!("generator {}", source.display()),
+            "revisions": "latest",
+            "carry-answers": {"fields": ["/journal"]},
+        }]
+    })
+    .to_string();
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "latest-sqlite", "--def", &flow_json])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-latest-sqlite.jsonl"))?;
+    assert_eq!(expected, fs::read_to_string(common::sink_path(&dir))?);
+    common::remove_sink(&dir)?;
+    // The revision that carries the reviewer's answer is skipped, and
+    // the revision with a changed abstract is labeled
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "label"])
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    let expected = fs::read_to_string(PathBuf::from(&dir).join("expected-label.jsonl"))?;
+    assert_eq!(expected, fs::read_to_string(common::sink_path(&dir))?);
+    common::remove_sink(&dir)?;
+    let output = common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "carry-only"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("carry-answers requires the revisions option"));
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that signed answers are linked rather than copied, since a
+/// copy in the reviewer's name would not be signed
+#[test]
+fn test_revisions_signatures() -> Result<()> {
+    let dir = test_dir("revisions-signatures");
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "copy"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, true)?;
+    Ok(())
+}
+
+/// Test that the label step records when each document was displayed
+/// and answered, and writes reviewer-session events around its work
+#[test]
+fn test_label_session() -> Result<()> {
+    let dir = test_dir("label-session");
+    common::remove_sink(&dir)?;
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&["flow", "label", "--sink-control-events"])
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    let events: Vec<serde_json::Value> = fs::read_to_string(common::sink_path(&dir))?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<std::result::Result<_, _>>()?;
+    let sessions: Vec<&serde_json::Value> = events
+        .iter()
+        .filter(|event| event.pointer("/data/reviewer-session").is_some())
+        .map(|event| &event["data"])
+        .collect();
+    let expected: Vec<serde_json::Value> = ["start", "end"]
+        .iter()
+        .map(|phase| {
+            serde_json::json!({
+                "reviewer-session": {
+                    "phase": phase,
+                    "reviewer": "mailto:user@example.com",
+                    "srvc-version": env!("CARGO_PKG_VERSION"),
+                    "step": "label"
+                },
+                "timestamp": 1661192610
+            })
+        })
+        .collect();
+    assert_eq!(expected.iter().collect::<Vec<_>>(), sessions);
+    let answer = events
+        .iter()
+        .find(|event| event["type"] == "label-answer")
+        .expect("label-answer");
+    assert_eq!(1661192610000u64, answer["data"]["displayed-ms"]);
+    assert_eq!(1661192610000u64, answer["data"]["submitted-ms"]);
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that a failing step with a retry policy is restarted, and
+/// that the retry is reported as a control event
+#[cfg(unix)]
+#[test]
+fn test_step_retry() -> Result<()> {
+    let dir = test_dir("step-retry");
+    common::remove_sink(&dir)?;
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "test", "--sink-control-events"])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .env("SR_TIMESTAMP_OVERRIDE", "1661192610")
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    common::check_sink(&dir, false)?;
+    Ok(())
+}
+
+/// Test that when a step can't be restarted, the flow fails and still
+/// ends its other steps
+#[cfg(target_os = "linux")]
+#[test]
+fn test_step_restart_failure() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = test_dir("step-restart-failure");
+    common::remove_sink(&dir)?;
+    // The step removes itself, so the retry fails to start it again
+    let step_path = PathBuf::from(&dir).join("vanishing.sh");
+    fs::write(&step_path, "#!/bin/sh\nrm \"$0\"\nexit 1\n")?;
+    fs::set_permissions(&step_path, fs::Permissions::from_mode(0o755))?;
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&["flow", "test"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.starts_with("Error: Failed to restart step sub-process"));
+    let pid_path = PathBuf::from(&dir).join("sleeper.pid");
+    let pid = fs::read_to_string(&pid_path)?;
+    fs::remove_file(&pid_path)?;
+    // A killed step stays a zombie until it is reaped
+    let running = match fs::read_to_string(format!("/proc/{}/status", pid.trim())) {
+        Ok(status) => !status.contains("State:\tZ"),
+        Err(_) => false,
+    };
+    assert!(!running, "Step 2 is still running");
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_step_cpu_seconds() -> Result<()> {
+    test_flow_err(
+        "step-cpu-seconds",
+        "test",
+        8000,
+        "Error: Step 1 exceeded its cpu-seconds limit (1)\n",
+        true,
+    )
+}
+
+/// Test that a step that fails with limits set reports its limits
+#[cfg(target_os = "linux")]
+#[test]
+fn test_step_open_files() -> Result<()> {
+    test_flow_err(
+        "step-open-files",
+        "test",
+        4000,
+        "Error: Step failed with exit code 2 with limits open-files=4\n",
+        true,
+    )
+}
+
+#[cfg(unix)]
+#[test]
+fn test_step_idle_timeout() -> Result<()> {
+    test_flow_err(
+        "step-idle-timeout",
+        "test",
+        4000,
+        "Error: Step 1 exceeded its idle-timeout (500ms)\n",
+        true,
+    )
+}
+
+#[cfg(unix)]
+#[test]
+fn test_step_timeout() -> Result<()> {
+    test_flow_err(
+        "step-timeout",
+        "test",
+        4000,
+        "Error: Step 1 exceeded its timeout (1s)\n",
+        true,
+    )?;
+    // Processes started by the step are killed with it
+    let pid_path = PathBuf::from(test_dir("step-timeout")).join("sleep.pid");
+    let pid = fs::read_to_string(&pid_path)?;
+    fs::remove_file(&pid_path)?;
+    if cfg!(target_os = "linux") {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        // A killed process may be a zombie until it is reaped
+        let state = stat.rsplit(") ").next().and_then(|s| s.chars().next());
+        assert!(matches!(state, None | Some('Z')), "{}", stat);
+    }
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn test_step_uri() -> std::result::Result<(), rexpect::errors::Error> {
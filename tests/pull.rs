@@ -68,3 +68,260 @@ fn test_pull_stdout() -> Result<()> {
         .stderr("");
     Ok(())
 }
+
+/// Test that gzip and zstd files and URLs are decompressed, and that
+/// --skip-invalid skips lines that are not valid events
+#[test]
+fn test_pull_compressed() -> Result<()> {
+    let dir = test_dir("pull-compressed");
+    let expected = fs::read_to_string(Path::new(&dir).join("expected.jsonl"))?;
+    for source in [
+        "docs.jsonl.gz",
+        "docs.jsonl.zst",
+        "http://localhost:8877/pull-compressed/docs.jsonl.gz",
+        "http://localhost:8877/pull-compressed/docs.jsonl.zst",
+    ] {
+        common::cmd(2000)
+            .current_dir(&dir)
+            .args(&["pull", "--db", "-", source])
+            .assert()
+            .success()
+            .stdout(expected.clone())
+            .stderr("");
+    }
+
+    let output = common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "--db", "-", "invalid.jsonl"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("Cannot parse line 2 as JSON"));
+    let expected_skip = fs::read_to_string(Path::new(&dir).join("expected-skip.jsonl"))?;
+    let output = common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "--db", "-", "--skip-invalid", "invalid.jsonl"])
+        .env("RUST_LOG", "warn")
+        .assert()
+        .success()
+        .stdout(expected_skip)
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output)?;
+    assert!(stderr.contains("Skipped invalid event: Cannot parse line 2 as JSON"));
+    assert!(
+        stderr.contains("Skipped invalid event: Invalid event on line 4: Incorrect event hash.")
+    );
+    Ok(())
+}
+
+/// Test that pull copies the blobs that events reference into the
+/// project, and the blob subcommands
+#[test]
+fn test_pull_blobs() -> Result<()> {
+    let dir = test_dir("pull-blobs");
+    let hash = "QmfAVUHrUpQjCW7TtD41GgrDDJd86zTHG4MqeyZcBPoGvi";
+    let blob_dir = Path::new(&dir).join(".srvc/blobs");
+    let blob = fs::read(Path::new(&dir).join("source/.srvc/blobs").join(hash))?;
+    for source in [
+        "source/docs.jsonl",
+        "http://localhost:8877/pull-blobs/source/docs.jsonl",
+    ] {
+        let _ = fs::remove_dir_all(Path::new(&dir).join(".srvc"));
+        common::remove_sink(&dir)?;
+        common::cmd(2000)
+            .current_dir(&dir)
+            .args(&["pull", source])
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+        assert_eq!(blob, fs::read(blob_dir.join(hash))?);
+    }
+
+    // Blobs go in the project that holds the db
+    let other = Path::new(&dir).join("other");
+    let _ = fs::remove_dir_all(&other);
+    let _ = fs::remove_dir_all(Path::new(&dir).join(".srvc"));
+    fs::create_dir(&other)?;
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "--db", "other/sink.jsonl", "source/docs.jsonl"])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    assert_eq!(blob, fs::read(other.join(".srvc/blobs").join(hash))?);
+    assert!(!blob_dir.exists());
+    fs::remove_dir_all(&other)?;
+
+    // Blobs are not copied when events are only printed
+    let _ = fs::remove_dir_all(Path::new(&dir).join(".srvc"));
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "--db", "-", "source/docs.jsonl"])
+        .assert()
+        .success();
+    assert!(!blob_dir.exists());
+
+    let output = common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "source/missing.jsonl"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?
+        .contains("Blob not found: QmZ4tDuvesekSs4qM5ZBKpXiZGun7S2CYtEZRB3DYXkjGx"));
+
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["blob", "add", &format!("source/.srvc/blobs/{}", hash)])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", hash))
+        .stderr("");
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["blob", "get", hash])
+        .assert()
+        .success()
+        .stdout(blob.clone())
+        .stderr("");
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["blob", "verify"])
+        .assert()
+        .success()
+        .stdout("Verified 1 blobs\n")
+        .stderr("");
+
+    // A blob whose contents don't match its hash fails verification
+    let corrupt = "QmZ4tDuvesekSs4qM5ZBKpXiZGun7S2CYtEZRB3DYXkjGx";
+    fs::write(blob_dir.join(corrupt), &blob)?;
+    let output = common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["blob", "verify"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output)?;
+    assert!(stderr.contains(&format!(
+        "Blob {} is corrupt. Its contents have the hash {}.",
+        corrupt, hash
+    )));
+    assert!(stderr.contains("1 of 2 blobs failed verification"));
+
+    fs::remove_dir_all(Path::new(&dir).join(".srvc"))?;
+    common::remove_sink(&dir)?;
+    Ok(())
+}
+
+/// Test that sinks replace redacted events with tombstones that keep
+/// their hashes, and that the generator emits tombstones
+#[test]
+fn test_pull_redaction() -> Result<()> {
+    let dir = test_dir("redaction");
+
+    // The redaction comes after the event in the sink, and the
+    // redacted event can't be added back
+    common::remove_sink(&dir)?;
+    for source in ["docs.jsonl", "redaction.jsonl", "docs.jsonl"] {
+        common::cmd(2000)
+            .current_dir(&dir)
+            .args(&["pull", source])
+            .assert()
+            .success()
+            .stdout("")
+            .stderr("");
+    }
+    common::check_sink(&dir, true)?;
+
+    // The redaction comes after the event in the source, and the
+    // generator emits it before the tombstone
+    let expected = fs::read_to_string(Path::new(&dir).join("expected-stdout.jsonl"))?;
+    common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "--db", "-", "redacted-source.jsonl"])
+        .assert()
+        .success()
+        .stdout(expected.clone())
+        .stderr("");
+
+    let expected_sqlite = fs::read_to_string(Path::new(&dir).join("expected-sqlite.jsonl"))?;
+    for sources in [
+        vec!["docs.jsonl", "redaction.jsonl"],
+        vec!["redacted-source.jsonl"],
+    ] {
+        common::remove_sink(&dir)?;
+        for source in sources {
+            common::cmd(2000)
+                .current_dir(&dir)
+                .args(&["pull", "--db", "sink.db", source])
+                .assert()
+                .success()
+                .stdout("")
+                .stderr("");
+        }
+        common::cmd(2000)
+            .current_dir(&dir)
+            .args(&["pull", "--db", "-", "sink.db"])
+            .assert()
+            .success()
+            .stdout(expected_sqlite.clone())
+            .stderr("");
+    }
+
+    let output = common::cmd(2000)
+        .current_dir(&dir)
+        .args(&["pull", "--db", "-", "invalid-tombstone.jsonl"])
+        .assert()
+        .code(1)
+        .get_output()
+        .stderr
+        .clone();
+    assert!(String::from_utf8(output)?.contains("Invalid hash in redacted event"));
+
+    // A tombstone needs a redaction of its event in the sink or
+    // earlier in the stream. Otherwise anyone could erase an event.
+    for db in ["sink.jsonl", "sink.db"] {
+        common::remove_sink(&dir)?;
+        for (source, success) in [
+            ("forged-tombstone.jsonl", false),
+            ("redaction.jsonl", true),
+            ("forged-tombstone.jsonl", false),
+            ("docs.jsonl", true),
+        ] {
+            let assert = common::cmd(2000)
+                .current_dir(&dir)
+                .args(&["pull", "--db", db, source])
+                .assert();
+            if success {
+                assert.success();
+            } else {
+                let output = assert.code(1).get_output().stderr.clone();
+                assert!(String::from_utf8(output)?.contains(
+                    "Redacted event QmT8Uvt3hUNc5jUhJvg9CwF6uhHHS1bNB2vxKtRYRucK1u has no redaction"
+                ));
+            }
+        }
+        let output = common::cmd(2000)
+            .current_dir(&dir)
+            .args(&["pull", "--db", "-", db])
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        assert!(String::from_utf8(output)?.contains("An ordinary paper"));
+    }
+
+    common::remove_sink(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use common::test_dir;
+
+mod common;
+
+#[test]
+fn test_step_matches() -> Result<()> {
+    let dir = test_dir("test-step");
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&[
+            "test-step",
+            "--step",
+            "step.yaml",
+            "--input",
+            "docs.jsonl",
+            "--expected",
+            "expected.jsonl",
+        ])
+        .assert()
+        .success()
+        .stdout("Step output matched 8 expected events\n")
+        .stderr("");
+    Ok(())
+}
+
+#[test]
+fn test_step_order() -> Result<()> {
+    let dir = test_dir("test-step");
+    let args = [
+        "test-step",
+        "--step",
+        "step.yaml",
+        "--input",
+        "docs.jsonl",
+        "--expected",
+        "reversed.jsonl",
+    ];
+    let output = common::cmd(4000)
+        .current_dir(&dir)
+        .args(&args)
+        .assert()
+        .code(1)
+        .stdout("")
+        .get_output()
+        .stderr
+        .clone();
+    let stderr = String::from_utf8(output)?;
+    assert!(stderr.starts_with(
+        "Error: Step output did not match reversed.jsonl:\nEvent 0: expected document QmUefYWNtso9nkEK4tCC7gYXxnRisAdsMcPjzhzMmeUPdx, found document Qmc2diLjUoiFx4SNEqGhDNEv9JwKvd1GfGpkUwbwLpTsXm\n"
+    ));
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&args)
+        .arg("--unordered")
+        .assert()
+        .success()
+        .stdout("Step output matched 8 expected events\n")
+        .stderr("");
+    Ok(())
+}
+
+#[test]
+fn test_step_timeout() -> Result<()> {
+    let dir = test_dir("test-step");
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&[
+            "test-step",
+            "--step",
+            "sleep.yaml",
+            "--expected",
+            "expected.jsonl",
+            "--timeout",
+            "200ms",
+        ])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("Error: Step 0 exceeded its timeout (200ms)\n");
+    Ok(())
+}
+
+/// Test that control events from a retried step have the overridden
+/// timestamp
+#[cfg(unix)]
+#[test]
+fn test_step_timestamp_override() -> Result<()> {
+    let dir = test_dir("test-step");
+    let marker = PathBuf::from(&dir).join("flaky-ran");
+    let _ = fs::remove_file(&marker);
+    common::cmd(4000)
+        .current_dir(&dir)
+        .args(&[
+            "test-step",
+            "--step",
+            "flaky.yaml",
+            "--input",
+            "docs.jsonl",
+            "--expected",
+            "expected-retry.jsonl",
+            "--timestamp-override",
+            "1000",
+            "--unordered",
+        ])
+        .env("SR_TEST_BIN", env!("CARGO_BIN_EXE_sr"))
+        .assert()
+        .success()
+        .stdout("Step output matched 9 expected events\n")
+        .stderr("");
+    fs::remove_file(&marker)?;
+    Ok(())
+}
+
+#[test]
+fn test_step_manifest_options() -> Result<()> {
+    let dir = test_dir("step-manifest");
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&[
+            "test-step",
+            "--step",
+            "invalid-options.yaml",
+            "--expected",
+            "expected.jsonl",
+        ])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("Error: Invalid options for step \"sh\": JSON schema validation failed at /mode: \"fast\" is not one of [\"abstract\",\"full\"]\n");
+    common::cmd(400)
+        .current_dir(&dir)
+        .args(&[
+            "test-step",
+            "--step",
+            "invalid-port.yaml",
+            "--expected",
+            "expected.jsonl",
+        ])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("Error: Invalid options for step \"label-web\": JSON schema validation failed at /port: \"web\" is not of type \"integer\"\n");
+    Ok(())
+}